@@ -1,12 +1,87 @@
-use lib::chip8::Chip8;
+use lib::analysis;
+use lib::chip8::{self, Chip8, Chip8Error, Variant};
+use lib::crash::{self, CrashReport};
+use lib::detect::{self, RomDatabase};
+use lib::diagnostics::Diagnostic;
+use lib::font::{self, Font};
+use lib::frontend::{Frontend, Hotkey};
+#[cfg(feature = "sdl")]
 use lib::gui::Gui;
-use std::time::SystemTime;
+use lib::lint;
+use lib::machine::Machine;
+use lib::megachip::{self, MegaChip};
+use lib::overlay::Overlay;
+use lib::png;
+use lib::quirks::Quirks;
+use lib::rpl::{self, FileStore};
+#[cfg(feature = "scripting")]
+use lib::script::Script;
+use lib::speed::{SpeedControl, Tick};
+use lib::stack;
+use lib::timing;
+#[cfg(unix)]
+use lib::tty::Tty;
+use lib::vip::{self, Vip};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::mpsc;
+
+const USAGE: &str = "Invalid Arguments \nEnter: [ROM path] Optional{[Resolution Scale] [Delay]} Optional{--frontend sdl|tty} Optional{--ff [Fast-forward multiplier]} Optional{--quirks chip8|schip|xochip} Optional{--variant chip8|chip8x|chip8e} Optional{--font vip|dream6800|eti660|schip|octo|[font file]} Optional{--font-base [hex address]} Optional{--timing fixed|vip} Optional{--stack-depth [calls]|unlimited} Optional{--stack-overflow error|wrap} Optional{--flags-dir [RPL flag directory]} Optional{--resume [crash report]} Optional{--coverage [report path prefix]} Optional{--profile [report path prefix]} Optional{--lint} Optional{--analyze [DOT graph path]} Optional{--detect} Optional{--rom-db [ROM database file]} Optional{--script [Rhai script]} Optional{--machine auto|chip8|megachip|vip --interpreter [VIP CHIP-8 interpreter image] Optional{--monitor [VIP monitor ROM image]}}";
+
+const FRAME: Duration = Duration::from_micros(16_667);
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() == 0 {
-        panic!("Invalid Arguments \nEnter: [ROM path] Optional{[Resolution Scale] [Delay]}");
+    let mut args: Vec<String> = env::args().collect();
+    let frontend_name = take_option(&mut args, "--frontend")
+        .unwrap_or_else(|| String::from(if cfg!(feature = "sdl") { "sdl" } else { "tty" }));
+    let ff_multiplier: u32 = take_option(&mut args, "--ff").map_or(4, |n| n.parse().unwrap());
+    let quirks = take_option(&mut args, "--quirks").map(|name| {
+        Quirks::from_name(&name).unwrap_or_else(|| panic!("Unknown quirks preset: {}", name))
+    });
+    let variant = take_option(&mut args, "--variant").map(|name| {
+        Variant::from_name(&name).unwrap_or_else(|| panic!("Unknown variant: {}", name))
+    });
+    let font = take_option(&mut args, "--font").map_or_else(Font::default, |name| {
+        Font::load(&name).unwrap_or_else(|e| panic!("{} (bundled fonts: {})", e, font::NAMES.join(", ")))
+    });
+    let font_base = take_option(&mut args, "--font-base").map(|address| {
+        usize::from_str_radix(address.trim_start_matches("0x"), 16).unwrap_or_else(|_| panic!("Invalid font address: {}", address))
+    });
+    let vip_timing = match take_option(&mut args, "--timing").as_deref() {
+        None | Some("fixed") => false,
+        Some("vip") => true,
+        Some(name) => panic!("Unknown timing mode: {}", name),
+    };
+    // The quirks preset picks the depth unless this overrides it.
+    let stack_depth = take_option(&mut args, "--stack-depth").map(|depth| {
+        stack::parse_depth(&depth).unwrap_or_else(|| panic!("Invalid stack depth: {}", depth))
+    });
+    let wrap_stack = match take_option(&mut args, "--stack-overflow").as_deref() {
+        None | Some("error") => false,
+        Some("wrap") => true,
+        Some(name) => panic!("Unknown stack overflow handling: {}", name),
+    };
+    let machine_name = take_option(&mut args, "--machine");
+    let interpreter_path = take_option(&mut args, "--interpreter");
+    let monitor_path = take_option(&mut args, "--monitor");
+    let flags_dir = take_option(&mut args, "--flags-dir").map_or_else(rpl::data_dir, PathBuf::from);
+    let resume_path = take_option(&mut args, "--resume");
+    let coverage_prefix = take_option(&mut args, "--coverage");
+    let profile_prefix = take_option(&mut args, "--profile");
+    let lint_only = take_flag(&mut args, "--lint");
+    let dot_path = take_option(&mut args, "--analyze");
+    let detect_only = take_flag(&mut args, "--detect");
+    let script_path = take_option(&mut args, "--script");
+    let database = take_option(&mut args, "--rom-db").map(|path| RomDatabase::load(&path).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    }));
+
+    if args.len() < 2 {
+        panic!("{}", USAGE);
     }
     let rom_path = &args[1];
     let mut scale: u32 = 10;
@@ -17,29 +92,387 @@ fn main() {
         delay = args[3].parse().unwrap();
     }
 
+    // Detection only suggests: it takes --detect to see it, or --machine
+    // auto to run with whatever of it wasn't picked by hand.
+    let auto = machine_name.as_deref() == Some("auto");
+    let detection = if detect_only || auto {
+        fs::read(rom_path).ok().map(|rom| detect::detect(&rom, database.as_ref()))
+    } else {
+        None
+    };
+    if detect_only {
+        match &detection {
+            Some(detection) => print!("{}", detection.report()),
+            None => {
+                eprintln!("{}", read_rom(rom_path).unwrap_err());
+                process::exit(1);
+            },
+        }
+        process::exit(0);
+    }
+    let machine_name = match &detection {
+        Some(detection) => match detection.machine() {
+            Some(machine) => String::from(machine),
+            None => {
+                eprintln!("Detected {}, which no machine here runs; pick one with --machine", detection.platform.name());
+                process::exit(1);
+            },
+        },
+        None => machine_name.filter(|_| !auto).unwrap_or_else(|| String::from("chip8")),
+    };
+    // Only the chip8 machine has save states for a crash report to carry.
+    if resume_path.is_some() && machine_name != "chip8" {
+        eprintln!("--resume needs --machine chip8, {} has no save states", machine_name);
+        process::exit(1);
+    }
+    let variant = variant.or_else(|| detection.as_ref().map(|detection| detection.variant())).unwrap_or(Variant::Chip8);
+    let quirks = quirks.or_else(|| detection.as_ref().map(|detection| detection.quirks()));
+
+    if lint_only {
+        let rom = read_rom(rom_path).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            process::exit(1);
+        });
+        let lints = lint::lint(&rom, variant);
+        lints.iter().for_each(|lint| println!("{}", lint));
+        process::exit(if lints.is_empty() { 0 } else { 2 });
+    }
+
+    if let Some(dot_path) = dot_path {
+        let rom = read_rom(rom_path).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            process::exit(1);
+        });
+        let analysis = analysis::analyze(&rom, variant);
+        print!("{}", analysis.summary());
+        if let Err(e) = fs::write(&dot_path, analysis.to_dot()) {
+            eprintln!("Error writing graph {}: {}", dot_path, e);
+            process::exit(1);
+        }
+        process::exit(0);
+    }
+
+    let (diagnostics_sender, diagnostics) = mpsc::channel::<Diagnostic>();
+    let mut reported = Vec::new();
 
-    let mut gui = Gui::new(scale); 
+    let loaded = match machine_name.as_str() {
+        "chip8" => {
+            let mut c8 = Chip8::with_quirks(Quirks { wrap_stack, ..quirks.unwrap_or_default() });
+            c8.set_variant(variant);
+            c8.set_vip_timing(vip_timing);
+            if let Some(depth) = stack_depth {
+                c8.set_stack_depth(depth);
+            }
+            c8.set_diagnostics(diagnostics_sender.clone());
+            if profile_prefix.is_some() {
+                c8.enable_profiler();
+            }
+            read_rom(rom_path).and_then(|rom| {
+                c8.set_font(&font, font_base.unwrap_or_else(|| variant.font_start()))?;
+                c8.load_bytes(&rom)?;
+                c8.set_flag_store(Box::new(FileStore::for_rom(&flags_dir, &rom)))?;
+                if let Some(path) = &resume_path {
+                    c8.load_state(&read_crash_state(path)?)?;
+                }
+                Ok(Box::new(c8) as Box<dyn Machine>)
+            })
+        },
+        // MegaChip builds on SCHIP and takes its quirks unless told otherwise.
+        "megachip" => {
+            let mut mega = MegaChip::with_quirks(Quirks { wrap_stack, ..quirks.unwrap_or(Quirks::SCHIP) });
+            if let Some(depth) = stack_depth {
+                mega.set_stack_depth(depth);
+            }
+            read_rom(rom_path).and_then(|rom| {
+                mega.set_font(&font, font_base.unwrap_or(chip8::FONT_START))?;
+                mega.load_bytes(&rom)?;
+                mega.set_flag_store(Box::new(FileStore::for_rom(&flags_dir, &rom)))?;
+                Ok(Box::new(mega) as Box<dyn Machine>)
+            })
+        },
+        "vip" => {
+            let interpreter_path = interpreter_path.unwrap_or_else(|| panic!("--machine vip needs --interpreter"));
+            load_vip(&interpreter_path, monitor_path.as_deref(), rom_path)
+        },
+        _ => panic!("Unknown machine: {}", machine_name),
+    };
+    let mut machine = loaded.unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
 
-    let mut c8 = Chip8::new();
-    c8.load_fontset();
-    c8.load_rom(rom_path);
+    let mut script = script_path.map(|path| Script::load(&path, machine.as_mut()).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    }));
+
+    let mut frontend = create_frontend(&frontend_name, scale);
 
     let mut quit: bool = false;
 
-    let start = SystemTime::now();
-    let mut last_time = start.elapsed().unwrap();
+    // The VIP runs its real frame of 1802 machine cycles and MegaChip its
+    // customary instructions per frame. VIP timing charges every CHIP-8
+    // instruction its cycles on that machine against the frame budget,
+    // otherwise each one takes `delay` milliseconds.
+    let mut speed = if machine_name == "vip" {
+        SpeedControl::with_frame_budget(vip::CYCLES_PER_FRAME, ff_multiplier)
+    } else if machine_name == "megachip" {
+        SpeedControl::with_frame_budget(megachip::INSTRUCTIONS_PER_FRAME, ff_multiplier)
+    } else if vip_timing {
+        SpeedControl::with_frame_budget(timing::VIP_FRAME_BUDGET, ff_multiplier)
+    } else {
+        SpeedControl::new(delay, ff_multiplier)
+    };
+    let mut overlay = Overlay::new();
+    if let Some(detection) = &detection {
+        overlay.message(&format!("Detected {}", detection.platform.name()));
+    }
+    let mut last_frame = Instant::now();
+    let mut show_heatmap = false;
+
+    // A resumed crash opens paused with the registers up, ready to step.
+    if resume_path.is_some() {
+        speed.handle(Hotkey::TogglePause);
+        overlay.toggle_registers();
+    }
 
     while !quit {
-        quit = gui.process_input(&mut c8.keypad);
+        quit = frontend.process_input(machine.keypad());
+        if let Some(script) = &mut script {
+            script.hold_keys(machine.keypad());
+        }
+        for hotkey in frontend.poll_hotkeys() {
+            match hotkey {
+                Hotkey::ToggleStats => overlay.toggle_stats(),
+                Hotkey::ToggleRegisters => overlay.toggle_registers(),
+                Hotkey::ToggleHeatmap => {
+                    show_heatmap = !show_heatmap && machine.coverage().is_some();
+                    if !show_heatmap {
+                        frontend.show_heatmap(None);
+                    }
+                },
+                Hotkey::ReloadScript => match &mut script {
+                    Some(script) => match script.reload(machine.as_mut()) {
+                        Ok(()) => overlay.message("Script reloaded"),
+                        Err(e) => overlay.message(&e.to_string()),
+                    },
+                    None => overlay.message("No script loaded"),
+                },
+                Hotkey::FrameAdvance => speed.handle(hotkey),
+                _ => {
+                    speed.handle(hotkey);
+                    overlay.message(&format!("Speed: {}", speed.label()));
+                },
+            }
+        }
+
+        let result = speed.run(|tick| match tick {
+            Tick::Instruction => match &mut script {
+                Some(script) => {
+                    script.before_step(machine.as_mut());
+                    let cost = machine.step();
+                    script.after_step(machine.as_mut());
+                    cost
+                },
+                None => machine.step(),
+            },
+            Tick::Vblank => {
+                machine.vblank();
+                if let Some(script) = &mut script {
+                    script.end_frame(machine.as_mut());
+                }
+                Ok(0)
+            },
+        });
+        if let Err(e) = result {
+            // Let the frontend restore the terminal before reporting.
+            drop(frontend);
+            print_diagnostics(&reported, &diagnostics);
+            report_crash(e, rom_path, machine.as_ref());
+            export_reports(coverage_prefix.as_deref(), profile_prefix.as_deref(), machine.as_ref());
+            process::exit(1);
+        }
+
+        if last_frame.elapsed() >= FRAME {
+            last_frame = Instant::now();
+
+            for diagnostic in diagnostics.try_iter() {
+                overlay.message(&diagnostic.to_string());
+                reported.push(diagnostic);
+            }
+            let mut frame = machine.frame();
+            if let Some(script) = &mut script {
+                script.take_messages().iter().for_each(|message| overlay.message(message));
+                script.draw(&mut frame);
+                overlay.set_texts(script.texts());
+            }
+            overlay.update(&speed, machine.as_ref());
+            frontend.set_sound(machine.sound_active() && !speed.paused());
+            frontend.queue_audio(&machine.audio_samples());
+            frontend.render_frame(&frame, &overlay);
+            if show_heatmap {
+                frontend.show_heatmap(machine.coverage().map(|coverage| coverage.heatmap()).as_ref());
+            }
+        }
+    }
+
+    drop(frontend);
+    print_diagnostics(&reported, &diagnostics);
+    export_reports(coverage_prefix.as_deref(), profile_prefix.as_deref(), machine.as_ref());
+}
+
+// The overlay only shows diagnostics for a moment; this lists them all
+// once the screen is no longer in use.
+fn print_diagnostics(reported: &[Diagnostic], pending: &mpsc::Receiver<Diagnostic>) {
+    for diagnostic in reported.iter().cloned().chain(pending.try_iter()) {
+        eprintln!("Warning: {}", diagnostic);
+    }
+}
+
+fn export_reports(coverage_prefix: Option<&str>, profile_prefix: Option<&str>, machine: &dyn Machine) {
+    if let Some(prefix) = coverage_prefix {
+        export_coverage(prefix, machine);
+    }
+    if let Some(prefix) = profile_prefix {
+        export_profile(prefix, machine);
+    }
+}
+
+// Writes <prefix>.json, <prefix>.csv and <prefix>.png.
+fn export_coverage(prefix: &str, machine: &dyn Machine) {
+    let coverage = match machine.coverage() {
+        Some(coverage) => coverage,
+        None => {
+            eprintln!("This machine doesn't track memory coverage");
+            return;
+        },
+    };
+    let files = [
+        ("json", coverage.to_json().into_bytes()),
+        ("csv", coverage.to_csv().into_bytes()),
+        ("png", png::encode(&coverage.heatmap().scaled(8))),
+    ];
+    write_files(prefix, &files);
+}
 
-        let current_time = start.elapsed().unwrap();
-        let delay_time = current_time.as_millis() - last_time.as_millis();
+// Writes <prefix>.txt with the flat profile and <prefix>.folded for flame
+// graph tools.
+fn export_profile(prefix: &str, machine: &dyn Machine) {
+    let profiler = match machine.profiler() {
+        Some(profiler) => profiler,
+        None => {
+            eprintln!("This machine can't be profiled");
+            return;
+        },
+    };
+    write_files(prefix, &[("txt", profiler.flat().into_bytes()), ("folded", profiler.folded().into_bytes())]);
+}
 
-        if delay_time > delay {
-            last_time = current_time;
-            c8.interpret();
-            gui.render_frame(&c8.gfx);
+fn write_files(prefix: &str, files: &[(&str, Vec<u8>)]) {
+    for (extension, contents) in files {
+        let path = format!("{}.{}", prefix, extension);
+        if let Err(e) = fs::write(&path, contents) {
+            eprintln!("Error writing report {}: {}", path, e);
         }
     }
 }
 
+fn read_rom(path: &str) -> Result<Vec<u8>, Chip8Error> {
+    fs::read(path).map_err(|e| Chip8Error::Io(format!("Error opening file {}: {}", path, e)))
+}
+
+fn read_crash_state(path: &str) -> Result<Vec<u8>, Chip8Error> {
+    let report = fs::read_to_string(path).map_err(|e| Chip8Error::Io(format!("Error opening crash report {}: {}", path, e)))?;
+    crash::extract_state(&report).ok_or_else(|| Chip8Error::Io(format!("No save state in {}", path)))
+}
+
+// Prints a summary and writes the full report next to where we were run.
+fn report_crash(error: Chip8Error, rom_path: &str, machine: &dyn Machine) {
+    let rom = fs::read(rom_path).unwrap_or_default();
+    let rom_name = Path::new(rom_path).file_name().map_or_else(|| String::from(rom_path), |name| name.to_string_lossy().into_owned());
+    let report = CrashReport::new(error, &rom_name, &rom, machine);
+    eprint!("{}", report.summary());
+
+    let stem = Path::new(&rom_name).file_stem().map_or_else(|| String::from("rom"), |stem| stem.to_string_lossy().into_owned());
+    let seconds = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs());
+    let path = format!("{}-crash-{}.txt", stem, seconds);
+    match fs::write(&path, report.render()) {
+        Ok(()) => eprintln!("Crash report written to {}", path),
+        Err(e) => eprintln!("Error writing crash report {}: {}", path, e),
+    }
+}
+
+fn load_vip(interpreter_path: &str, monitor_path: Option<&str>, rom_path: &str) -> Result<Box<dyn Machine>, Chip8Error> {
+    let interpreter = read_rom(interpreter_path)?;
+    let monitor = monitor_path.map(read_rom).transpose()?;
+
+    let mut vip = Vip::new(&interpreter, monitor.as_deref())?;
+    vip.load_rom(rom_path)?;
+    Ok(Box::new(vip))
+}
+
+// Removes `name` and the value following it from `args`.
+fn take_option(args: &mut Vec<String>, name: &str) -> Option<String> {
+    let i = args.iter().position(|arg| arg == name)?;
+    if i + 1 >= args.len() {
+        panic!("{}", USAGE);
+    }
+    args.remove(i);
+    Some(args.remove(i))
+}
+
+// Removes the switch `name` from `args`, returning whether it was there.
+fn take_flag(args: &mut Vec<String>, name: &str) -> bool {
+    match args.iter().position(|arg| arg == name) {
+        Some(i) => {
+            args.remove(i);
+            true
+        },
+        None => false,
+    }
+}
+
+#[allow(unused_variables)]
+fn create_frontend(name: &str, scale: u32) -> Box<dyn Frontend> {
+    match name {
+        #[cfg(feature = "sdl")]
+        "sdl" => Box::new(Gui::new(scale)),
+        #[cfg(unix)]
+        "tty" => Box::new(Tty::new()),
+        _ => panic!("Unsupported frontend: {}", name),
+    }
+}
+
+// Stands in for the script engine in builds without it, which refuse
+// --script, so the loop reads the same either way.
+#[cfg(not(feature = "scripting"))]
+struct Script;
+
+#[cfg(not(feature = "scripting"))]
+impl Script {
+    fn load(_path: &str, _machine: &mut dyn Machine) -> Result<Self, Chip8Error> {
+        Err(Chip8Error::Io(String::from("Built without scripting support")))
+    }
+
+    fn reload(&mut self, _machine: &mut dyn Machine) -> Result<(), Chip8Error> {
+        Ok(())
+    }
+
+    fn before_step(&mut self, _machine: &mut dyn Machine) {}
+
+    fn after_step(&mut self, _machine: &mut dyn Machine) {}
+
+    fn end_frame(&mut self, _machine: &mut dyn Machine) {}
+
+    fn hold_keys(&mut self, _keypad: &mut [u8; 16]) {}
+
+    fn draw(&self, _frame: &mut lib::display::Frame) {}
+
+    fn texts(&self) -> Vec<lib::overlay::Text> {
+        Vec::new()
+    }
+
+    fn take_messages(&mut self) -> Vec<String> {
+        Vec::new()
+    }
+}
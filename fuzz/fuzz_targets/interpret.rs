@@ -0,0 +1,59 @@
+#![no_main]
+use lib::chip8::{Chip8, Variant};
+use lib::machine::Machine;
+use lib::megachip::MegaChip;
+use lib::quirks::Quirks;
+use libfuzzer_sys::fuzz_target;
+
+const CYCLES: u32 = 10_000;
+const CYCLES_PER_FRAME: u32 = 16;
+
+// Input layout: one byte picking the quirks preset (the remainder by the
+// number of presets) and the machine (the quotient: CHIP-8, CHIP-8X, CHIP-8E
+// or MegaChip), two bytes of keypad state (one bit per key) and the ROM
+// itself. The keypad is rotated every 64 cycles so Fx0A and the key skips see
+// both states. A vertical blank follows every CYCLES_PER_FRAME cycles.
+fuzz_target!(|data: &[u8]| {
+    if data.len() < 3 {
+        return;
+    }
+
+    let presets = Quirks::PRESETS.len();
+    let (_, quirks) = Quirks::PRESETS[data[0] as usize % presets];
+    let mut keys = u16::from_le_bytes([data[1], data[2]]);
+
+    let mut machine: Box<dyn Machine> = match data[0] as usize / presets % 4 {
+        3 => {
+            let mut mega = MegaChip::with_quirks(quirks);
+            if mega.load_bytes(&data[3..]).is_err() {
+                return;
+            }
+            Box::new(mega)
+        },
+        variant => {
+            let mut c8 = Chip8::with_quirks(quirks);
+            c8.set_variant([Variant::Chip8, Variant::Chip8X, Variant::Chip8E][variant]);
+            if c8.load_bytes(&data[3..]).is_err() {
+                return;
+            }
+            Box::new(c8)
+        },
+    };
+
+    for cycle in 0..CYCLES {
+        if cycle % 64 == 0 {
+            for (key, state) in machine.keypad().iter_mut().enumerate() {
+                *state = (keys >> key & 1) as u8;
+            }
+            keys = keys.rotate_left(1);
+        }
+
+        if machine.step().is_err() {
+            break;
+        }
+
+        if cycle % CYCLES_PER_FRAME == CYCLES_PER_FRAME - 1 {
+            machine.vblank();
+        }
+    }
+});
@@ -0,0 +1,35 @@
+#![no_main]
+use lib::chip8::{Chip8, Variant};
+use lib::megachip::{self, MegaChip};
+use libfuzzer_sys::fuzz_target;
+
+// The first byte picks CHIP-8, CHIP-8X, CHIP-8E or MegaChip, the rest is the
+// ROM.
+fuzz_target!(|data: &[u8]| {
+    let (&machine, rom) = match data.split_first() {
+        Some(split) => split,
+        None => return,
+    };
+
+    if machine % 4 == 3 {
+        let mut mega = MegaChip::new();
+        match mega.load_bytes(rom) {
+            Ok(()) => assert_eq!(&mega.memory()[0x200..0x200 + rom.len()], rom),
+            Err(_) => assert!(rom.len() > megachip::MEMORY_SIZE - 0x200),
+        }
+        return;
+    }
+
+    let variant = [Variant::Chip8, Variant::Chip8X, Variant::Chip8E][machine as usize % 4];
+    let start = variant.program_start();
+    let mut c8 = Chip8::new();
+    c8.set_variant(variant);
+    match c8.load_bytes(rom) {
+        // HIRES programs get their first jump redirected.
+        Ok(()) => {
+            let patched = if c8.hires() { 2 } else { 0 };
+            assert_eq!(&c8.memory()[start + patched..start + rom.len()], &rom[patched..]);
+        },
+        Err(_) => assert!(rom.len() > 0x1000 - start),
+    }
+});
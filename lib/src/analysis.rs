@@ -0,0 +1,496 @@
+use std::collections::{BTreeMap, BTreeSet};
+use crate::chip8::Variant;
+use crate::instruction::{self, Flow, Instruction};
+
+// Static analysis of a ROM: the code reachable from the start address split
+// into basic blocks and subroutines, the sprite data it draws, the bytes
+// nothing seems to use, and which platform's instructions it relies on.
+// Like any static view of CHIP-8 it can be fooled: Bnnn and Fx1B jump to
+// places only known at run time, and self-modifying code changes the rules.
+
+// The CHIP-8 dialects a ROM can be written for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Platform {
+    Chip8,
+    Schip,
+    XoChip,
+    Chip8X,
+    Chip8E,
+    MegaChip,
+}
+
+impl Platform {
+    pub const ALL: [Platform; 6] =
+        [Platform::Chip8, Platform::Schip, Platform::XoChip, Platform::Chip8X, Platform::Chip8E, Platform::MegaChip];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Platform::Chip8 => "chip8",
+            Platform::Schip => "schip",
+            Platform::XoChip => "xochip",
+            Platform::Chip8X => "chip8x",
+            Platform::Chip8E => "chip8e",
+            Platform::MegaChip => "megachip",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Platform::ALL.iter().copied().find(|platform| platform.name() == name)
+    }
+
+    // The platforms that give `opcode` a meaning plain CHIP-8 doesn't. Some
+    // extensions reused the same opcodes, and Bnnn is left out because
+    // CHIP-8X and CHIP-8E's versions of it look like any other Bnnn.
+    pub fn extensions_using(opcode: u16) -> Vec<Platform> {
+        let n = opcode & 0x000F;
+        let nn = opcode & 0x00FF;
+        match opcode & 0xF000 {
+            0x0000 => match opcode {
+                0x00C0..=0x00CF | 0x00FB..=0x00FF => vec![Platform::Schip],
+                0x00D0..=0x00DF => vec![Platform::XoChip],
+                0x0010 | 0x0011 | 0x00B0..=0x00BF => vec![Platform::MegaChip],
+                0x02A0 => vec![Platform::Chip8X],
+                0x00ED | 0x00F2 | 0x0151 | 0x0188 => vec![Platform::Chip8E],
+                _ => vec![],
+            },
+            0x5000 => match n {
+                1 => vec![Platform::Chip8X, Platform::Chip8E],
+                2 | 3 => vec![Platform::XoChip, Platform::Chip8E],
+                _ => vec![],
+            },
+            0xD000 if n == 0 => vec![Platform::Schip],
+            0xE000 if nn == 0xF2 || nn == 0xF5 => vec![Platform::Chip8X],
+            0xF000 => match nn {
+                0x30 | 0x75 | 0x85 => vec![Platform::Schip],
+                0x00 | 0x01 | 0x02 | 0x3A => vec![Platform::XoChip],
+                0xF8 | 0xFB => vec![Platform::Chip8X],
+                0x03 | 0x1B | 0x4F | 0xE3 | 0xE7 => vec![Platform::Chip8E],
+                _ => vec![],
+            },
+            _ => vec![],
+        }
+    }
+}
+
+// Where control goes at the end of a block.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Edge {
+    // Falling through, including back from a call.
+    Next(u16),
+    // The taken side of a skip.
+    Skip(u16),
+    Jump(u16),
+    Call(u16),
+    // A computed jump; could be anywhere.
+    Computed,
+}
+
+impl Edge {
+    pub fn target(self) -> Option<u16> {
+        match self {
+            Edge::Next(target) | Edge::Skip(target) | Edge::Jump(target) | Edge::Call(target) => Some(target),
+            Edge::Computed => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Block {
+    pub start: u16,
+    // Past the last instruction.
+    pub end: u16,
+    pub edges: Vec<Edge>,
+}
+
+// Data drawn as a sprite: `len` bytes at `address`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SpriteTable {
+    pub address: u16,
+    pub len: u16,
+}
+
+#[derive(Debug, Clone)]
+struct Decoded {
+    opcode: u16,
+    len: u16,
+    instruction: Option<Instruction>,
+    flow: Flow,
+}
+
+#[derive(Debug, Clone)]
+pub struct Analysis {
+    pub origin: u16,
+    rom: Vec<u8>,
+    variant: Variant,
+    instructions: BTreeMap<u16, Decoded>,
+    pub blocks: BTreeMap<u16, Block>,
+    // Each subroutine's entry and the blocks it runs through; the program
+    // start counts as one.
+    pub subroutines: BTreeMap<u16, BTreeSet<u16>>,
+    pub sprites: Vec<SpriteTable>,
+    // Ranges of bytes, end exclusive, that are neither code nor sprites.
+    pub unreachable: Vec<(u16, u16)>,
+    // Addresses of the instructions only each platform has.
+    pub platform_ops: BTreeMap<Platform, BTreeSet<u16>>,
+}
+
+pub fn analyze(rom: &[u8], variant: Variant) -> Analysis {
+    let origin = variant.program_start() as u16;
+    let instructions = trace(rom, origin, variant);
+
+    let mut leaders: BTreeSet<u16> = BTreeSet::new();
+    leaders.insert(origin);
+    for (&pc, decoded) in &instructions {
+        for edge in edges(pc, decoded) {
+            if let (Edge::Next(_), Flow::Next) = (edge, decoded.flow) {
+                continue;
+            }
+            leaders.extend(edge.target());
+        }
+    }
+
+    let mut blocks = BTreeMap::new();
+    for &leader in leaders.iter().filter(|leader| instructions.contains_key(leader)) {
+        let mut pc = leader;
+        loop {
+            let decoded = &instructions[&pc];
+            let next = pc.wrapping_add(decoded.len);
+            let ends = decoded.flow != Flow::Next || !instructions.contains_key(&next) || leaders.contains(&next);
+            if ends {
+                let edges = edges(pc, decoded).into_iter()
+                    .filter(|edge| edge.target().is_none_or(|target| instructions.contains_key(&target)))
+                    .collect();
+                blocks.insert(leader, Block { start: leader, end: next, edges });
+                break;
+            }
+            pc = next;
+        }
+    }
+
+    let mut analysis = Analysis {
+        origin,
+        rom: rom.to_vec(),
+        variant,
+        instructions,
+        blocks,
+        subroutines: BTreeMap::new(),
+        sprites: Vec::new(),
+        unreachable: Vec::new(),
+        platform_ops: BTreeMap::new(),
+    };
+    analysis.find_subroutines();
+    analysis.find_sprites();
+    analysis.find_unreachable();
+    for (&pc, decoded) in &analysis.instructions {
+        for platform in Platform::extensions_using(decoded.opcode) {
+            analysis.platform_ops.entry(platform).or_default().insert(pc);
+        }
+    }
+    analysis
+}
+
+// Follows every path from `origin`. Opcodes the variant doesn't know, or
+// only knows as a machine code SYS call, are tried as SCHIP ones, which
+// clash with nothing, before giving up on the path; XO-CHIP's four byte F000 nnnn is stepped over whole.
+fn trace(rom: &[u8], origin: u16, variant: Variant) -> BTreeMap<u16, Decoded> {
+    let word = |pc: u16| -> Option<u16> {
+        let offset = (pc as usize).checked_sub(origin as usize)?;
+        Some(u16::from_be_bytes([*rom.get(offset)?, *rom.get(offset + 1)?]))
+    };
+
+    let mut instructions = BTreeMap::new();
+    let mut work = vec![origin];
+    while let Some(pc) = work.pop() {
+        if instructions.contains_key(&pc) {
+            continue;
+        }
+        let opcode = match word(pc) {
+            Some(opcode) => opcode,
+            None => continue,
+        };
+        let instruction = match variant.decode(opcode) {
+            Some(Instruction::Sys(_)) | None => Instruction::decode_schip(opcode),
+            decoded => decoded,
+        };
+        let decoded = match instruction {
+            Some(instruction) => Decoded { opcode, len: 2, instruction: Some(instruction), flow: instruction.flow(pc) },
+            None if opcode == 0xF000 => Decoded { opcode, len: 4, instruction: None, flow: Flow::Next },
+            None => continue,
+        };
+        work.extend(edges(pc, &decoded).iter().filter_map(|edge| edge.target()));
+        instructions.insert(pc, decoded);
+    }
+    instructions
+}
+
+fn edges(pc: u16, decoded: &Decoded) -> Vec<Edge> {
+    let next = pc.wrapping_add(decoded.len);
+    match decoded.flow {
+        Flow::Next => vec![Edge::Next(next)],
+        Flow::Skip => vec![Edge::Next(next), Edge::Skip(next.wrapping_add(2))],
+        Flow::Jump(target) => vec![Edge::Jump(target)],
+        Flow::Call(target) => vec![Edge::Call(target), Edge::Next(next)],
+        Flow::Return | Flow::Stop => vec![],
+        Flow::Computed => vec![Edge::Computed],
+    }
+}
+
+impl Analysis {
+    fn block_instructions(&self, block: &Block) -> Vec<(u16, &Decoded)> {
+        let mut pc = block.start;
+        let mut out = Vec::new();
+        while pc != block.end {
+            let decoded = &self.instructions[&pc];
+            out.push((pc, decoded));
+            pc = pc.wrapping_add(decoded.len);
+        }
+        out
+    }
+
+    fn find_subroutines(&mut self) {
+        let mut entries: BTreeSet<u16> = self.blocks.values()
+            .flat_map(|block| block.edges.iter())
+            .filter_map(|edge| if let Edge::Call(target) = edge { Some(*target) } else { None })
+            .collect();
+        entries.insert(self.origin);
+        entries.retain(|entry| self.blocks.contains_key(entry));
+
+        for entry in entries {
+            let mut members = BTreeSet::new();
+            let mut work = vec![entry];
+            while let Some(start) = work.pop() {
+                if !members.insert(start) {
+                    continue;
+                }
+                for edge in &self.blocks[&start].edges {
+                    match edge {
+                        Edge::Next(target) | Edge::Skip(target) | Edge::Jump(target) => work.push(*target),
+                        Edge::Call(_) | Edge::Computed => {},
+                    }
+                }
+            }
+            self.subroutines.insert(entry, members);
+        }
+    }
+
+    // A Dxyn with I set by an earlier Annn in the same block points at
+    // its sprite. Dxy0 is a 16x16 SCHIP sprite.
+    fn find_sprites(&mut self) {
+        let mut tables: BTreeMap<u16, u16> = BTreeMap::new();
+        for block in self.blocks.values() {
+            let mut i = None;
+            for (pc, decoded) in self.block_instructions(block) {
+                match decoded.instruction {
+                    Some(Instruction::Drw(_, _, n)) => {
+                        if let Some(address) = i {
+                            let len = if n == 0 { 32 } else { n as u16 };
+                            let known = tables.entry(address).or_insert(0);
+                            *known = (*known).max(len);
+                        }
+                    },
+                    Some(instruction) => i = instruction.i_after(i),
+                    None => i = self.long_i(pc),
+                }
+            }
+        }
+        self.sprites = tables.into_iter().map(|(address, len)| SpriteTable { address, len }).collect();
+    }
+
+    // The address F000 nnnn loads.
+    fn long_i(&self, pc: u16) -> Option<u16> {
+        let offset = (pc as usize + 2).checked_sub(self.origin as usize)?;
+        Some(u16::from_be_bytes([*self.rom.get(offset)?, *self.rom.get(offset + 1)?]))
+    }
+
+    fn find_unreachable(&mut self) {
+        let end = self.origin as usize + self.rom.len();
+        let mut used = vec![false; self.rom.len()];
+        let mut mark = |start: usize, len: usize| {
+            for address in start..(start + len).min(end) {
+                if let Some(offset) = address.checked_sub(self.origin as usize) {
+                    used[offset] = true;
+                }
+            }
+        };
+        for (&pc, decoded) in &self.instructions {
+            mark(pc as usize, decoded.len as usize);
+        }
+        for sprite in &self.sprites {
+            mark(sprite.address as usize, sprite.len as usize);
+        }
+
+        let mut ranges = Vec::new();
+        let mut offset = 0;
+        while offset < used.len() {
+            if used[offset] {
+                offset += 1;
+                continue;
+            }
+            let start = offset;
+            while offset < used.len() && !used[offset] {
+                offset += 1;
+            }
+            ranges.push(((self.origin as usize + start) as u16, (self.origin as usize + offset) as u16));
+        }
+        self.unreachable = ranges;
+    }
+
+    // The platform whose own instructions the ROM uses most. Opcodes two
+    // extensions share only decide between otherwise equal candidates.
+    pub fn guess_platform(&self) -> Platform {
+        let score = |platform: Platform| {
+            let ops = match self.platform_ops.get(&platform) {
+                Some(ops) => ops,
+                None => return (0, 0),
+            };
+            let own = ops.iter()
+                .filter(|pc| Platform::extensions_using(self.instructions[pc].opcode).len() == 1)
+                .count();
+            (own, ops.len())
+        };
+        Platform::ALL.iter().copied()
+            .filter(|&platform| score(platform) > (0, 0))
+            .max_by_key(|&platform| (score(platform), std::cmp::Reverse(platform)))
+            .unwrap_or(Platform::Chip8)
+    }
+
+    fn disassemble(&self, pc: u16, decoded: &Decoded) -> String {
+        match decoded.instruction {
+            Some(instruction) => format!("{:03X}: {:04X}  {}", pc, decoded.opcode, instruction),
+            None => instruction::disassemble(&decoded.opcode.to_be_bytes(), pc, self.variant).remove(0),
+        }
+    }
+
+    // Graphviz: a box per block, listing its instructions, grouped into a
+    // cluster per subroutine. Calls are blue, skips dashed and computed
+    // jumps run to a "?" node.
+    pub fn to_dot(&self) -> String {
+        let mut out = String::from("digraph rom {\n  node [shape=box fontname=monospace];\n");
+        let mut placed = BTreeSet::new();
+        for (entry, members) in &self.subroutines {
+            let name = if *entry == self.origin { String::from("main") } else { format!("sub_{:03X}", entry) };
+            out += &format!("  subgraph cluster_{:03X} {{\n    label=\"{}\";\n", entry, name);
+            for start in members.iter().filter(|start| placed.insert(**start)) {
+                out += &format!("    b{:03X};\n", start);
+            }
+            out += "  }\n";
+        }
+
+        let mut computed = false;
+        for block in self.blocks.values() {
+            let lines: Vec<String> = self.block_instructions(block).into_iter()
+                .map(|(pc, decoded)| format!("{}\\l", self.disassemble(pc, decoded).replace('"', "\\\"")))
+                .collect();
+            out += &format!("  b{:03X} [label=\"{}\"];\n", block.start, lines.concat());
+            for edge in &block.edges {
+                out += &match edge {
+                    Edge::Next(target) | Edge::Jump(target) => format!("  b{:03X} -> b{:03X};\n", block.start, target),
+                    Edge::Skip(target) => format!("  b{:03X} -> b{:03X} [style=dashed];\n", block.start, target),
+                    Edge::Call(target) => format!("  b{:03X} -> b{:03X} [color=blue];\n", block.start, target),
+                    Edge::Computed => {
+                        computed = true;
+                        format!("  b{:03X} -> computed [style=dotted];\n", block.start)
+                    },
+                };
+            }
+        }
+        if computed {
+            out += "  computed [shape=diamond label=\"?\"];\n";
+        }
+        out += "}\n";
+        out
+    }
+
+    pub fn summary(&self) -> String {
+        let code_bytes: usize = self.instructions.values().map(|decoded| decoded.len as usize).sum();
+        let subroutines = self.subroutines.keys().filter(|entry| **entry != self.origin).count();
+        let mut out = format!(
+            "{} bytes at {:03X}: {} instructions ({} bytes) in {} blocks, {} subroutines\n",
+            self.rom.len(), self.origin, self.instructions.len(), code_bytes, self.blocks.len(), subroutines);
+
+        let computed: Vec<String> = self.blocks.values()
+            .filter(|block| block.edges.contains(&Edge::Computed))
+            .map(|block| format!("{:03X}", block.end.wrapping_sub(2)))
+            .collect();
+        if !computed.is_empty() {
+            out += &format!("Computed jumps at {}; code they reach isn't counted\n", computed.join(", "));
+        }
+
+        out += "\nSubroutines\n";
+        for (entry, members) in self.subroutines.iter().filter(|(entry, _)| **entry != self.origin) {
+            out += &format!("  {:03X}  {} blocks\n", entry, members.len());
+        }
+        out += "\nSprite tables\n";
+        for sprite in &self.sprites {
+            out += &format!("  {:03X}-{:03X}  {} bytes\n", sprite.address, sprite.address + sprite.len - 1, sprite.len);
+        }
+        out += "\nUnreachable bytes\n";
+        for (start, end) in &self.unreachable {
+            out += &format!("  {:03X}-{:03X}  {} bytes\n", start, end - 1, end - start);
+        }
+        out += "\nPlatform instructions\n";
+        for (platform, ops) in &self.platform_ops {
+            let addresses: Vec<String> = ops.iter().map(|pc| format!("{:03X}", pc)).collect();
+            out += &format!("  {:<8} {}\n", platform.name(), addresses.join(" "));
+        }
+        out += &format!("\nProbably written for: {}\n", self.guess_platform().name());
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROM: [u8; 22] = [
+        0xA2, 0x12, // 200: LD I, 212
+        0x22, 0x0A, // 202: CALL 20A
+        0x30, 0x01, // 204: SE V0, 01
+        0x12, 0x02, // 206: JP 202
+        0x00, 0xFD, // 208: EXIT
+        0xD0, 0x15, // 20A: DRW V0, V1, 5
+        0xB2, 0x00, // 20C: JP V0, 200
+        0x00, 0xEE, // 20E: never reached
+        0x12, 0x34, // 210: never reached
+        0xF0, 0x90, 0xF0, 0x90, // 212: I is set in another block than the draw
+    ];
+
+    #[test]
+    fn blocks_and_subroutines() {
+        let analysis = analyze(&ROM, Variant::Chip8);
+        let starts: Vec<u16> = analysis.blocks.keys().copied().collect();
+        assert_eq!(starts, [0x200, 0x202, 0x204, 0x206, 0x208, 0x20A]);
+        assert_eq!(analysis.blocks[&0x202].edges, [Edge::Call(0x20A), Edge::Next(0x204)]);
+        assert_eq!(analysis.blocks[&0x204].edges, [Edge::Next(0x206), Edge::Skip(0x208)]);
+        assert_eq!(analysis.blocks[&0x20A], Block { start: 0x20A, end: 0x20E, edges: vec![Edge::Computed] });
+
+        let main: Vec<u16> = analysis.subroutines[&0x200].iter().copied().collect();
+        assert_eq!(main, [0x200, 0x202, 0x204, 0x206, 0x208]);
+        assert!(analysis.subroutines[&0x20A].contains(&0x20A));
+
+        assert_eq!(analysis.unreachable, [(0x20E, 0x216)]);
+        assert_eq!(analysis.sprites, []);
+        assert_eq!(analysis.guess_platform(), Platform::Schip);
+
+        let dot = analysis.to_dot();
+        assert!(dot.contains("  b202 -> b20A [color=blue];\n"));
+        assert!(dot.contains("  b204 -> b208 [style=dashed];\n"));
+        assert!(dot.contains("  b20A -> computed [style=dotted];\n"));
+        assert!(dot.contains("b20A [label=\"20A: D015  DRW V0, V1, 5\\l20C: B200  JP V0, 200\\l\"]"));
+    }
+
+    #[test]
+    fn sprite_tables_and_platforms() {
+        let rom = [0xA2, 0x08, 0xD0, 0x13, 0x00, 0xFF, 0x12, 0x06, 0xFF, 0x81, 0xFF];
+        let analysis = analyze(&rom, Variant::Chip8);
+        assert_eq!(analysis.sprites, [SpriteTable { address: 0x208, len: 3 }]);
+        assert_eq!(analysis.unreachable, []);
+        assert!(analysis.summary().contains("Sprite tables\n  208-20A  3 bytes\n"));
+
+        assert_eq!(Platform::extensions_using(0x5121), [Platform::Chip8X, Platform::Chip8E]);
+        assert_eq!(Platform::extensions_using(0xF275), [Platform::Schip]);
+        let rom = [0x51, 0x21, 0x52, 0x32, 0x12, 0x04];
+        assert_eq!(analyze(&rom, Variant::Chip8E).guess_platform(), Platform::Chip8E);
+        assert_eq!(analyze(&[0x12, 0x00], Variant::Chip8).guess_platform(), Platform::Chip8);
+    }
+}
@@ -0,0 +1,68 @@
+// Digitised sound: machines hand the frontends signed 16 bit mono samples at
+// a fixed output rate, whatever rate their own samples were recorded at.
+
+pub const SAMPLE_RATE: u32 = 44_100;
+
+// Output samples per 60 Hz frame.
+pub const SAMPLES_PER_FRAME: usize = SAMPLE_RATE as usize / 60;
+
+// Plays back 8 bit unsigned PCM at `rate` Hz, resampled to `SAMPLE_RATE` by
+// picking the nearest earlier source sample.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SamplePlayer {
+    data: Vec<u8>,
+    rate: u32,
+    looping: bool,
+    // Position in source samples, in 1/SAMPLE_RATE steps.
+    position: u64,
+}
+
+impl SamplePlayer {
+    pub fn new(data: Vec<u8>, rate: u32, looping: bool) -> Self {
+        SamplePlayer { data, rate, looping, position: 0 }
+    }
+
+    pub fn finished(&self) -> bool {
+        self.data.is_empty() || self.rate == 0 || (!self.looping && self.index() >= self.data.len())
+    }
+
+    fn index(&self) -> usize {
+        (self.position * self.rate as u64 / SAMPLE_RATE as u64) as usize
+    }
+
+    // Appends up to `count` output samples to `out`, stopping early when a
+    // one-shot sample runs out.
+    pub fn render(&mut self, count: usize, out: &mut Vec<i16>) {
+        for _ in 0..count {
+            if self.finished() {
+                return;
+            }
+            let index = self.index() % self.data.len();
+            out.push((self.data[index] as i16 - 128) << 8);
+            self.position += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resamples_to_output_rate() {
+        let mut player = SamplePlayer::new(vec![0x80, 0xFF, 0x00], SAMPLE_RATE / 2, false);
+        let mut out = Vec::new();
+        player.render(10, &mut out);
+        assert_eq!(out, [0, 0, 127 << 8, 127 << 8, -128 << 8, -128 << 8]);
+        assert!(player.finished());
+    }
+
+    #[test]
+    fn loops_until_stopped() {
+        let mut player = SamplePlayer::new(vec![0x80, 0x90], SAMPLE_RATE, true);
+        let mut out = Vec::new();
+        player.render(5, &mut out);
+        assert_eq!(out, [0, 16 << 8, 0, 16 << 8, 0]);
+        assert!(!player.finished());
+    }
+}
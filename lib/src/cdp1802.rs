@@ -0,0 +1,449 @@
+// RCA CDP1802 CPU core. Timing is counted in machine cycles (8 clocks): two
+// per instruction, three for long branches and skips, one per DMA or
+// interrupt cycle. DMA and interrupts are requested by the surrounding machine
+// between instructions, which is where the real chip services them.
+
+// Everything outside the CPU: memory, the I/O ports used by OUT/INP and the
+// four EF flag inputs.
+pub trait Bus {
+    fn read(&mut self, address: u16) -> u8;
+    fn write(&mut self, address: u16, value: u8);
+    // OUT 1-7.
+    fn output(&mut self, port: u8, value: u8);
+    // INP 1-7.
+    fn input(&mut self, port: u8) -> u8;
+    // True while EF1-EF4 (`flag` 1 to 4) is asserted.
+    fn ef(&self, flag: u8) -> bool;
+}
+
+#[derive(Debug, Clone)]
+pub struct Cdp1802 {
+    pub r: [u16; 16],
+    pub p: u8,
+    pub x: u8,
+    pub d: u8,
+    pub df: bool,
+    pub t: u8,
+    pub ie: bool,
+    pub q: bool,
+    idle: bool,
+}
+
+impl Default for Cdp1802 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Cdp1802 {
+    // State after reset: P, X and R0 cleared, interrupts enabled, Q off.
+    pub fn new() -> Self {
+        Cdp1802 { r: [0; 16], p: 0, x: 0, d: 0, df: false, t: 0, ie: true, q: false, idle: false }
+    }
+
+    pub fn idle(&self) -> bool {
+        self.idle
+    }
+
+    fn fetch(&mut self, bus: &mut impl Bus) -> u8 {
+        let p = self.p as usize;
+        let byte = bus.read(self.r[p]);
+        self.r[p] = self.r[p].wrapping_add(1);
+        byte
+    }
+
+    fn rx(&self) -> u16 {
+        self.r[self.x as usize]
+    }
+
+    // D = a + b + carry, DF = carry out.
+    fn add(&mut self, a: u8, b: u8, carry: bool) {
+        let sum = a as u16 + b as u16 + carry as u16;
+        self.d = sum as u8;
+        self.df = sum > 0xFF;
+    }
+
+    // D = a - b - borrow, DF = no borrow.
+    fn sub(&mut self, a: u8, b: u8, borrow: bool) {
+        let difference = a as i16 - b as i16 - borrow as i16;
+        self.d = difference as u8;
+        self.df = difference >= 0;
+    }
+
+    // Runs one instruction and returns how many machine cycles it took. While
+    // idle the CPU only burns cycles until a DMA or interrupt wakes it.
+    pub fn step(&mut self, bus: &mut impl Bus) -> u32 {
+        if self.idle {
+            return 2;
+        }
+
+        let opcode = self.fetch(bus);
+        let n = opcode & 0x0F;
+        let rn = n as usize;
+
+        match opcode >> 4 {
+            0x0 => {
+                if n == 0 {
+                    self.idle = true;
+                } else {
+                    self.d = bus.read(self.r[rn]);
+                }
+            },
+            0x1 => self.r[rn] = self.r[rn].wrapping_add(1),
+            0x2 => self.r[rn] = self.r[rn].wrapping_sub(1),
+            0x3 => {
+                let taken = self.condition(n & 0x7, bus) != (n & 0x8 != 0);
+                let p = self.p as usize;
+                if taken {
+                    let target = bus.read(self.r[p]);
+                    self.r[p] = (self.r[p] & 0xFF00) | target as u16;
+                } else {
+                    self.r[p] = self.r[p].wrapping_add(1);
+                }
+            },
+            0x4 => {
+                self.d = bus.read(self.r[rn]);
+                self.r[rn] = self.r[rn].wrapping_add(1);
+            },
+            0x5 => bus.write(self.r[rn], self.d),
+            0x6 => match n {
+                0x0 => self.inc_x(),
+                0x1..=0x7 => {
+                    let value = bus.read(self.rx());
+                    bus.output(n, value);
+                    self.inc_x();
+                },
+                // 68 does nothing on the 1802.
+                0x8 => {},
+                _ => {
+                    let value = bus.input(n - 8);
+                    bus.write(self.rx(), value);
+                    self.d = value;
+                },
+            },
+            0x7 => self.group_7(n, bus),
+            0x8 => self.d = self.r[rn] as u8,
+            0x9 => self.d = (self.r[rn] >> 8) as u8,
+            0xA => self.r[rn] = (self.r[rn] & 0xFF00) | self.d as u16,
+            0xB => self.r[rn] = (self.r[rn] & 0x00FF) | (self.d as u16) << 8,
+            0xC => {
+                self.long_branch(n, bus);
+                return 3;
+            },
+            0xD => self.p = n,
+            0xE => self.x = n,
+            _ => self.group_f(n, bus),
+        }
+
+        2
+    }
+
+    fn inc_x(&mut self) {
+        let x = self.x as usize;
+        self.r[x] = self.r[x].wrapping_add(1);
+    }
+
+    // Conditions of the short branches 30-37; 38-3F are their negations.
+    fn condition(&self, n: u8, bus: &impl Bus) -> bool {
+        match n {
+            0 => true,
+            1 => self.q,
+            2 => self.d == 0,
+            3 => self.df,
+            _ => bus.ef(n - 3),
+        }
+    }
+
+    fn group_7(&mut self, n: u8, bus: &mut impl Bus) {
+        match n {
+            // RET and DIS
+            0x0 | 0x1 => {
+                let value = bus.read(self.rx());
+                self.inc_x();
+                self.x = value >> 4;
+                self.p = value & 0x0F;
+                self.ie = n == 0x0;
+            },
+            // LDXA
+            0x2 => {
+                self.d = bus.read(self.rx());
+                self.inc_x();
+            },
+            // STXD
+            0x3 => {
+                bus.write(self.rx(), self.d);
+                let x = self.x as usize;
+                self.r[x] = self.r[x].wrapping_sub(1);
+            },
+            // ADC, SDB, SMB and their immediate forms
+            0x4 | 0xC => {
+                let m = self.operand(n, bus);
+                self.add(m, self.d, self.df);
+            },
+            0x5 | 0xD => {
+                let m = self.operand(n, bus);
+                self.sub(m, self.d, !self.df);
+            },
+            0x7 | 0xF => {
+                let m = self.operand(n, bus);
+                self.sub(self.d, m, !self.df);
+            },
+            // SHRC
+            0x6 => {
+                let carry = self.d & 0x01 != 0;
+                self.d = self.d >> 1 | (self.df as u8) << 7;
+                self.df = carry;
+            },
+            // SHLC
+            0xE => {
+                let carry = self.d & 0x80 != 0;
+                self.d = self.d << 1 | self.df as u8;
+                self.df = carry;
+            },
+            // SAV
+            0x8 => bus.write(self.rx(), self.t),
+            // MARK
+            0x9 => {
+                self.t = self.x << 4 | self.p;
+                bus.write(self.r[2], self.t);
+                self.x = self.p;
+                self.r[2] = self.r[2].wrapping_sub(1);
+            },
+            // REQ and SEQ
+            0xA => self.q = false,
+            _ => self.q = true,
+        }
+    }
+
+    // M(R(X)) for the memory forms (low bit 3 clear), the next program byte
+    // for the immediate ones.
+    fn operand(&mut self, n: u8, bus: &mut impl Bus) -> u8 {
+        if n & 0x8 != 0 {
+            self.fetch(bus)
+        } else {
+            bus.read(self.rx())
+        }
+    }
+
+    fn group_f(&mut self, n: u8, bus: &mut impl Bus) {
+        match n & 0x7 {
+            // LDX and LDI
+            0x0 => self.d = self.operand(n, bus),
+            0x1 => self.d |= self.operand(n, bus),
+            0x2 => self.d &= self.operand(n, bus),
+            0x3 => self.d ^= self.operand(n, bus),
+            0x4 => {
+                let m = self.operand(n, bus);
+                self.add(m, self.d, false);
+            },
+            // SD and SDI
+            0x5 => {
+                let m = self.operand(n, bus);
+                self.sub(m, self.d, false);
+            },
+            // SHR and SHL
+            0x6 => {
+                if n == 0x6 {
+                    self.df = self.d & 0x01 != 0;
+                    self.d >>= 1;
+                } else {
+                    self.df = self.d & 0x80 != 0;
+                    self.d <<= 1;
+                }
+            },
+            // SM and SMI
+            _ => {
+                let m = self.operand(n, bus);
+                self.sub(self.d, m, false);
+            },
+        }
+    }
+
+    // C0-CF: long branches (two address bytes follow) and long skips (over
+    // the next two bytes).
+    fn long_branch(&mut self, n: u8, bus: &mut impl Bus) {
+        let p = self.p as usize;
+        let (skip, taken) = match n {
+            0x0 => (false, true),
+            0x1 => (false, self.q),
+            0x2 => (false, self.d == 0),
+            0x3 => (false, self.df),
+            // NOP
+            0x4 => return,
+            0x5 => (true, !self.q),
+            0x6 => (true, self.d != 0),
+            0x7 => (true, !self.df),
+            0x8 => (true, true),
+            0x9 => (false, !self.q),
+            0xA => (false, self.d != 0),
+            0xB => (false, !self.df),
+            0xC => (true, self.ie),
+            0xD => (true, self.q),
+            0xE => (true, self.d == 0),
+            _ => (true, self.df),
+        };
+
+        if taken && !skip {
+            let high = bus.read(self.r[p]);
+            let low = bus.read(self.r[p].wrapping_add(1));
+            self.r[p] = (high as u16) << 8 | low as u16;
+        } else if taken || !skip {
+            self.r[p] = self.r[p].wrapping_add(2);
+        }
+    }
+
+    // Takes an interrupt if they are enabled: saves X and P in T and
+    // continues with R1 as program counter and R2 as X. Returns the cycles
+    // used.
+    pub fn interrupt(&mut self) -> u32 {
+        if !self.ie {
+            return 0;
+        }
+        self.t = self.x << 4 | self.p;
+        self.p = 1;
+        self.x = 2;
+        self.ie = false;
+        self.idle = false;
+        1
+    }
+
+    // One DMA output cycle: returns M(R0) and advances R0.
+    pub fn dma_out(&mut self, bus: &mut impl Bus) -> u8 {
+        let value = bus.read(self.r[0]);
+        self.r[0] = self.r[0].wrapping_add(1);
+        self.idle = false;
+        value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Ram {
+        memory: Vec<u8>,
+        ports: Vec<(u8, u8)>,
+        ef: [bool; 4],
+    }
+
+    impl Bus for Ram {
+        fn read(&mut self, address: u16) -> u8 {
+            self.memory[address as usize % self.memory.len()]
+        }
+
+        fn write(&mut self, address: u16, value: u8) {
+            let len = self.memory.len();
+            self.memory[address as usize % len] = value;
+        }
+
+        fn output(&mut self, port: u8, value: u8) {
+            self.ports.push((port, value));
+        }
+
+        fn input(&mut self, port: u8) -> u8 {
+            0x40 | port
+        }
+
+        fn ef(&self, flag: u8) -> bool {
+            self.ef[flag as usize - 1]
+        }
+    }
+
+    // Runs `program` from address 0 for `steps` instructions.
+    fn run(program: &[u8], steps: usize) -> (Cdp1802, Ram) {
+        let mut ram = Ram { memory: vec![0; 0x100], ports: Vec::new(), ef: [false, false, true, false] };
+        ram.memory[..program.len()].copy_from_slice(program);
+        let mut cpu = Cdp1802::new();
+        for _ in 0..steps {
+            cpu.step(&mut ram);
+        }
+        (cpu, ram)
+    }
+
+    #[test]
+    fn register_loads() {
+        // LDI 12, PHI R5, LDI 34, PLO R5, INC R5, GHI R5
+        let (cpu, _) = run(&[0xF8, 0x12, 0xB5, 0xF8, 0x34, 0xA5, 0x15, 0x95], 6);
+        assert_eq!(cpu.r[5], 0x1235);
+        assert_eq!(cpu.d, 0x12);
+    }
+
+    #[test]
+    fn arithmetic_flags() {
+        // LDI F0, ADI 20 -> 10 with carry, ADCI 00 -> 11
+        let (cpu, _) = run(&[0xF8, 0xF0, 0xFC, 0x20, 0x7C, 0x00], 3);
+        assert_eq!((cpu.d, cpu.df), (0x11, false));
+
+        // LDI 10, SMI 20 -> F0 with borrow (DF clear), SMBI 00 -> EF
+        let (cpu, _) = run(&[0xF8, 0x10, 0xFF, 0x20, 0x7F, 0x00], 3);
+        assert_eq!((cpu.d, cpu.df), (0xEF, true));
+
+        // LDI 10, SDI 30 -> 20 without borrow
+        let (cpu, _) = run(&[0xF8, 0x10, 0xFD, 0x30], 2);
+        assert_eq!((cpu.d, cpu.df), (0x20, true));
+    }
+
+    #[test]
+    fn shifts() {
+        // LDI 81, SHR -> 40 DF, SHRC -> A0, SHLC -> 40 DF, SHL -> 80
+        let (cpu, _) = run(&[0xF8, 0x81, 0xF6, 0x76], 3);
+        assert_eq!((cpu.d, cpu.df), (0xA0, false));
+        let (cpu, _) = run(&[0xF8, 0xA0, 0x7E, 0xFE], 3);
+        assert_eq!((cpu.d, cpu.df), (0x80, false));
+    }
+
+    #[test]
+    fn branches() {
+        // BZ with D=0 is taken, B3 follows EF3, BN3 falls through.
+        let (cpu, _) = run(&[0x32, 0x10], 1);
+        assert_eq!(cpu.r[0], 0x10);
+        let (cpu, _) = run(&[0x36, 0x20], 1);
+        assert_eq!(cpu.r[0], 0x20);
+        let (cpu, _) = run(&[0x3E, 0x20], 1);
+        assert_eq!(cpu.r[0], 0x02);
+
+        // LBR 0040, long skip LSZ over three bytes.
+        let (cpu, _) = run(&[0xC0, 0x00, 0x40], 1);
+        assert_eq!(cpu.r[0], 0x40);
+        let (cpu, _) = run(&[0xCE, 0x00, 0x00], 1);
+        assert_eq!(cpu.r[0], 0x03);
+    }
+
+    #[test]
+    fn long_branch_takes_three_cycles() {
+        let mut ram = Ram { memory: vec![0xC4; 0x10], ports: Vec::new(), ef: [false; 4] };
+        let mut cpu = Cdp1802::new();
+        assert_eq!(cpu.step(&mut ram), 3);
+    }
+
+    #[test]
+    fn mark_and_return() {
+        // R2 = 80, SEX 3, MARK, then RET via X=2 restores X=3, P=0.
+        let (cpu, ram) = run(&[0xF8, 0x80, 0xA2, 0xE3, 0x79, 0xE2, 0x12, 0x70], 7);
+        assert_eq!(ram.memory[0x80], 0x30);
+        assert_eq!((cpu.x, cpu.p, cpu.r[2]), (3, 0, 0x81));
+        assert!(cpu.ie);
+    }
+
+    #[test]
+    fn io_ports() {
+        // R3 = 40, SEX 3, LDI 55, STR R3, OUT 2, DEC R3, INP 1
+        let (cpu, ram) = run(&[0xF8, 0x40, 0xA3, 0xE3, 0xF8, 0x55, 0x53, 0x62, 0x23, 0x69], 8);
+        assert_eq!(ram.ports, vec![(2, 0x55)]);
+        assert_eq!(cpu.d, 0x41);
+        assert_eq!(ram.memory[0x40], 0x41);
+    }
+
+    #[test]
+    fn interrupt_and_idle() {
+        let (mut cpu, mut ram) = run(&[0x00], 1);
+        assert!(cpu.idle());
+        assert_eq!(cpu.interrupt(), 1);
+        assert!(!cpu.idle());
+        assert_eq!((cpu.t, cpu.p, cpu.x, cpu.ie), (0x00, 1, 2, false));
+        assert_eq!(cpu.interrupt(), 0);
+        cpu.r[0] = 0x00;
+        assert_eq!(cpu.dma_out(&mut ram), 0x00);
+        assert_eq!(cpu.r[0], 1);
+    }
+}
@@ -1,6 +1,138 @@
-use std::io::prelude::*;
-use std::fs::File;
-use rand::Rng;
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::sync::mpsc::Sender;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use crate::alu;
+use crate::coverage::{Access, Coverage};
+use crate::crash::History;
+use crate::diagnostics::Diagnostic;
+use crate::display::{self, Frame, Rgba};
+use crate::font::{self, Font};
+use crate::instruction::{self, Instruction};
+use crate::machine::Machine;
+use crate::profile::Profiler;
+use crate::quirks::Quirks;
+use crate::rpl::{FlagStore, NoStore, FLAG_COUNT};
+use crate::stack::{CallFrame, CallStack};
+use crate::state::{self, StateReader, StateWriter};
+use crate::timing;
+
+const MEMORY_SIZE: usize = 4096;
+const PROGRAM_START: usize = 0x200;
+
+// Where the font goes by default, clear of any program. The VIP interpreters
+// read their digits from the monitor ROM, out of a program's reach, so this
+// is only the usual spot; `Variant::font_start` and `set_font` (and
+// --font-base) move it.
+pub const FONT_START: usize = 0x50;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Chip8Error {
+    InvalidOpcode { pc: u16, opcode: u16 },
+    StackOverflow { pc: u16 },
+    StackUnderflow { pc: u16 },
+    MemoryOutOfBounds { pc: u16, address: usize },
+    InvalidKey { pc: u16, key: u8 },
+    RomTooLarge { size: usize, max: usize },
+    Io(String),
+}
+
+impl fmt::Display for Chip8Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Chip8Error::InvalidOpcode { pc, opcode } => write!(f, "Invalid opcode: {:#06x} at {:#05x}", opcode, pc),
+            Chip8Error::StackOverflow { pc } => write!(f, "Stack overflow at {:#05x}", pc),
+            Chip8Error::StackUnderflow { pc } => write!(f, "Return with an empty stack at {:#05x}", pc),
+            Chip8Error::MemoryOutOfBounds { pc, address } => write!(f, "Memory access out of bounds ({:#x}) at {:#05x}", address, pc),
+            Chip8Error::InvalidKey { pc, key } => write!(f, "Invalid key {:#04x} at {:#05x}", key, pc),
+            Chip8Error::RomTooLarge { size, max } => write!(f, "ROM is too large ({} bytes, at most {} fit)", size, max),
+            Chip8Error::Io(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for Chip8Error {}
+
+impl Chip8Error {
+    // Where the program was when it failed, if it was running.
+    pub fn pc(&self) -> Option<u16> {
+        match *self {
+            Chip8Error::InvalidOpcode { pc, .. }
+            | Chip8Error::StackOverflow { pc }
+            | Chip8Error::StackUnderflow { pc }
+            | Chip8Error::MemoryOutOfBounds { pc, .. }
+            | Chip8Error::InvalidKey { pc, .. } => Some(pc),
+            Chip8Error::RomTooLarge { .. } | Chip8Error::Io(_) => None,
+        }
+    }
+}
+
+// The instruction set a program was written for. CHIP-8X ran on a VIP with
+// the VP-590 colour board and VP-595 second keypad, CHIP-8E was a rework of
+// the original interpreter.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Variant {
+    Chip8,
+    Chip8X,
+    Chip8E,
+}
+
+impl Variant {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "chip8" => Some(Variant::Chip8),
+            "chip8x" => Some(Variant::Chip8X),
+            "chip8e" => Some(Variant::Chip8E),
+            _ => None,
+        }
+    }
+
+    // The CHIP-8X interpreter is a page longer.
+    pub fn program_start(self) -> usize {
+        match self {
+            Variant::Chip8X => 0x300,
+            _ => PROGRAM_START,
+        }
+    }
+
+    // Where the font goes unless told otherwise. CHIP-8X programs start a page
+    // later, so its font takes that page, where the longer interpreter sat.
+    pub fn font_start(self) -> usize {
+        match self {
+            Variant::Chip8X => PROGRAM_START,
+            Variant::Chip8 | Variant::Chip8E => FONT_START,
+        }
+    }
+
+    pub fn decode(self, opcode: u16) -> Option<Instruction> {
+        match self {
+            Variant::Chip8 => Instruction::decode(opcode),
+            Variant::Chip8X => Instruction::decode_chip8x(opcode),
+            Variant::Chip8E => Instruction::decode_chip8e(opcode),
+        }
+    }
+}
+
+// The VP-590 colours, indexed by the 3 bit colour value.
+const CHIP8X_PALETTE: [Rgba; 8] = [
+    [0, 0, 0, 255],
+    [255, 0, 0, 255],
+    [0, 0, 255, 255],
+    [255, 0, 255, 255],
+    [0, 255, 0, 255],
+    [255, 255, 0, 255],
+    [0, 255, 255, 255],
+    [255, 255, 255, 255],
+];
+
+// The order 02A0 steps through, as palette indices.
+const CHIP8X_BACKGROUNDS: [u8; 4] = [2, 0, 4, 1];
+
+// Colour is held per 8 pixel wide, 1 pixel high zone.
+const ZONE_COLUMNS: usize = 8;
+const ZONE_ROWS: usize = 32;
 
 #[derive(Debug)]
 pub struct Chip8 {
@@ -9,93 +141,534 @@ pub struct Chip8 {
     v_reg: [u8; 16],
     i_reg: u16,
 
-    stack: [u16; 16],
-    sp: u16,
+    stack: CallStack,
 
-    pub gfx: [u8; 64 * 32],
+    // 64 pixels wide, 32 rows or 64 on the HIRES interpreter.
+    pub gfx: Vec<u8>,
+    hires: bool,
     pub keypad: [u8; 16],
     pub sound_timer: u8,
     delay_timer: u8,
+
+    // A Dxyn is parked until the next vertical blank (display wait quirk).
+    waiting_for_vblank: bool,
+    vblank_ready: bool,
+
+    quirks: Quirks,
+    // Charge instructions their COSMAC VIP cycles in `Machine::step`.
+    vip_timing: bool,
+
+    variant: Variant,
+    // CHIP-8X colour zones (palette indices) and background step.
+    zones: [u8; ZONE_COLUMNS * ZONE_ROWS],
+    background: usize,
+    pub keypad2: [u8; 16],
+    // The byte port: the last value written, and a value waiting to be read.
+    pub port_output: Option<u8>,
+    pub port_input: Option<u8>,
+    // CHIP-8E Fx4F has loaded the delay timer and is waiting for it.
+    delay_wait: bool,
+
+    // RPL user flags (Fx75/Fx85), written through to `flag_store`.
+    flags: [u8; FLAG_COUNT],
+    flag_store: Box<dyn FlagStore>,
+
+    // The small glyphs start here, the large ones follow, `font_len` bytes
+    // in all.
+    font_start: usize,
+    font_len: usize,
+
+    history: History,
+    coverage: Coverage,
+    profiler: Option<Profiler>,
+
+    diagnostics: Option<Sender<Diagnostic>>,
+    // Instructions overwritten during the current one, with their old
+    // opcodes and who wrote them, and every one reported so far.
+    code_writes: Vec<(usize, u16, u16)>,
+    rewritten: HashSet<usize>,
+    // Every byte the program stored, while someone is watching.
+    write_log: Option<Vec<(usize, u8)>>,
+    // Cxnn's numbers come from here once seeded, otherwise from the OS.
+    rng: Option<StdRng>,
 }
 
 
+impl Default for Chip8 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Chip8 {
     pub fn new() -> Self {
-        Chip8 {
+        Chip8::with_quirks(Quirks::default())
+    }
+
+    pub fn with_quirks(quirks: Quirks) -> Self {
+        let mut c8 = Chip8 {
             pc: 0x200,
             memory: [0; 4096],
             v_reg: [0; 16],
             i_reg: 0,
-            stack: [0; 16],
-            sp: 0,
-            gfx: [0; 64 * 32],
+            stack: CallStack::new(quirks.stack_depth),
+            gfx: vec![0; 64 * 32],
+            hires: false,
             keypad: [0; 16],
             delay_timer: 0,
             sound_timer: 0,
+            waiting_for_vblank: false,
+            vblank_ready: false,
+            quirks,
+            vip_timing: false,
+            variant: Variant::Chip8,
+            zones: [1; ZONE_COLUMNS * ZONE_ROWS],
+            background: 0,
+            keypad2: [0; 16],
+            port_output: None,
+            port_input: None,
+            delay_wait: false,
+            flags: [0; FLAG_COUNT],
+            flag_store: Box::new(NoStore),
+            font_start: FONT_START,
+            font_len: 0,
+            history: History::default(),
+            coverage: Coverage::new(MEMORY_SIZE),
+            profiler: None,
+            diagnostics: None,
+            code_writes: Vec::new(),
+            rewritten: HashSet::new(),
+            write_log: None,
+            rng: None,
+        };
+        c8.set_font(&Font::default(), FONT_START).unwrap();
+        c8
+    }
+
+    // Replaces the font, placing it at `start`. Load it before the program,
+    // which may share the memory.
+    pub fn set_font(&mut self, font: &Font, start: usize) -> Result<(), Chip8Error> {
+        let bytes = font.bytes();
+        if start + bytes.len() > MEMORY_SIZE {
+            return Err(Chip8Error::MemoryOutOfBounds { pc: self.pc, address: start + bytes.len() - 1 });
+        }
+        self.write_memory(start, &bytes);
+        self.font_start = start;
+        self.font_len = bytes.len();
+        Ok(())
+    }
+
+    // Switches the instruction set; programs of the variant start at its
+    // own address, so call this before loading. A font still at the old
+    // variant's default moves to the new one's.
+    pub fn set_variant(&mut self, variant: Variant) {
+        if self.font_start == self.variant.font_start() {
+            let font = self.memory[self.font_start..self.font_start + self.font_len].to_vec();
+            self.write_memory(variant.font_start(), &font);
+            self.font_start = variant.font_start();
         }
+        self.variant = variant;
+        self.pc = variant.program_start() as u16;
+    }
+
+    pub fn variant(&self) -> Variant {
+        self.variant
+    }
+
+    // Keeps the RPL flags in `store` from now on, starting from what it holds.
+    pub fn set_flag_store(&mut self, mut store: Box<dyn FlagStore>) -> Result<(), Chip8Error> {
+        self.flags = store.load().map_err(|e| Chip8Error::Io(format!("Error loading RPL flags: {}", e)))?;
+        self.flag_store = store;
+        Ok(())
+    }
+
+    pub fn flags(&self) -> &[u8; FLAG_COUNT] {
+        &self.flags
+    }
+
+    // Switches between the 64x32 display and the two-page 64x64 one of the
+    // HIRES interpreter, clearing the screen.
+    pub fn set_hires(&mut self, hires: bool) {
+        self.hires = hires;
+        self.gfx = vec![0; 64 * self.display_height()];
+    }
+
+    pub fn hires(&self) -> bool {
+        self.hires
     }
 
-    pub fn load_rom(&mut self, path: &str) -> () {
-        let f = File::open(path).unwrap_or_else(|_| panic!("Error opening file {}", path));
-        let mut i = 0x200;
+    pub fn display_height(&self) -> usize {
+        if self.hires { 64 } else { 32 }
+    }
 
-        for byte in f.bytes() {
-            self.memory[i] = byte.unwrap();
-            i += 1;
+    pub fn quirks(&self) -> Quirks {
+        self.quirks
+    }
+
+    pub fn set_vip_timing(&mut self, enabled: bool) {
+        self.vip_timing = enabled;
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+
+    pub fn i_reg(&self) -> u16 {
+        self.i_reg
+    }
+
+    pub fn v_reg(&self) -> &[u8; 16] {
+        &self.v_reg
+    }
+
+    pub fn sp(&self) -> usize {
+        self.stack.sp()
+    }
+
+    pub fn delay_timer(&self) -> u8 {
+        self.delay_timer
+    }
+
+    // True while a draw is suspended until the next `vblank`.
+    pub fn waiting_for_vblank(&self) -> bool {
+        self.waiting_for_vblank
+    }
+
+    // The instruction at pc, if there is a valid one.
+    pub fn instruction(&self) -> Option<Instruction> {
+        let pc = self.pc as usize;
+        let opcode = u16::from_be_bytes([*self.memory.get(pc)?, *self.memory.get(pc + 1)?]);
+        self.variant.decode(opcode)
+    }
+
+    // The calls still waiting to return, oldest first.
+    pub fn call_stack(&self) -> &[CallFrame] {
+        self.stack.frames()
+    }
+
+    pub fn stack_depth(&self) -> Option<usize> {
+        self.stack.depth()
+    }
+
+    // Sets how many calls fit on the stack, `None` for no limit, over what
+    // the quirks say. This empties the stack, so do it before running.
+    pub fn set_stack_depth(&mut self, depth: Option<usize>) {
+        self.quirks.stack_depth = depth;
+        self.stack = CallStack::new(depth);
+    }
+
+    // Starts profiling from here on; call after `set_variant`.
+    pub fn enable_profiler(&mut self) {
+        self.profiler = Some(Profiler::new(MEMORY_SIZE, self.variant));
+    }
+
+    pub fn profiler(&self) -> Option<&Profiler> {
+        self.profiler.as_ref()
+    }
+
+    // Sends warnings about what the program does to `sender`.
+    pub fn set_diagnostics(&mut self, sender: Sender<Diagnostic>) {
+        self.diagnostics = Some(sender);
+    }
+
+    // Makes Cxnn repeat the same numbers for the same seed.
+    pub fn seed_random(&mut self, seed: u64) {
+        self.rng = Some(StdRng::seed_from_u64(seed));
+    }
+
+    // Starts or stops keeping the (address, value) of every store the
+    // program makes, for `take_writes`.
+    pub fn log_writes(&mut self, enabled: bool) {
+        self.write_log = if enabled { Some(Vec::new()) } else { None };
+    }
+
+    // The stores made since the last call, oldest first.
+    pub fn take_writes(&mut self) -> Vec<(usize, u8)> {
+        self.write_log.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    // How each byte of memory has been used since the machine was created.
+    pub fn coverage(&self) -> &Coverage {
+        &self.coverage
+    }
+
+    pub fn memory(&self) -> &[u8; 4096] {
+        &self.memory
+    }
+
+    pub fn set_pc(&mut self, pc: u16) {
+        self.pc = pc;
+    }
+
+    pub fn set_i_reg(&mut self, i_reg: u16) {
+        self.i_reg = i_reg;
+    }
+
+    pub fn set_v_reg(&mut self, x: usize, value: u8) {
+        self.v_reg[x] = value;
+    }
+
+    pub fn set_delay_timer(&mut self, value: u8) {
+        self.delay_timer = value;
+    }
+
+    // Replaces the call stack with `returns` (oldest first) and points sp
+    // past them. Each call site is taken to be just before its return.
+    pub fn set_stack(&mut self, returns: &[u16]) {
+        let frames: Vec<_> = returns.iter()
+            .map(|&address| CallFrame { call_site: (address as usize).saturating_sub(2), return_address: address as usize })
+            .collect();
+        self.stack.set_frames(&frames);
+    }
+
+    // Everything but the flag store, which stays with the session.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut out = StateWriter::new();
+        out.u16(self.pc);
+        out.bytes(&self.memory);
+        out.bytes(&self.v_reg);
+        out.u16(self.i_reg);
+        self.stack.save(&mut out);
+        out.bool(self.hires);
+        out.bytes(&self.gfx);
+        out.bytes(&self.keypad);
+        out.u8(self.sound_timer);
+        out.u8(self.delay_timer);
+        out.bool(self.waiting_for_vblank);
+        out.bool(self.vblank_ready);
+        out.quirks(self.quirks);
+        out.bool(self.vip_timing);
+        out.u8(self.variant as u8);
+        out.bytes(&self.zones);
+        out.u8(self.background as u8);
+        out.bytes(&self.keypad2);
+        out.option_u8(self.port_output);
+        out.option_u8(self.port_input);
+        out.bool(self.delay_wait);
+        out.bytes(&self.flags);
+        out.u16(self.font_start as u16);
+        out.finish()
+    }
+
+    // Restores a `save_state`, leaving the machine untouched if it's invalid.
+    pub fn load_state(&mut self, bytes: &[u8]) -> Result<(), Chip8Error> {
+        let mut input = StateReader::new(bytes)?;
+        let pc = input.u16()?;
+        let mut memory = [0; MEMORY_SIZE];
+        input.bytes_into(&mut memory)?;
+        let mut v_reg = [0; 16];
+        input.bytes_into(&mut v_reg)?;
+        let i_reg = input.u16()?;
+        let stack = CallStack::load(&mut input)?;
+        let hires = input.bool()?;
+        let mut gfx = vec![0; 64 * if hires { 64 } else { 32 }];
+        input.bytes_into(&mut gfx)?;
+        let mut keypad = [0; 16];
+        input.bytes_into(&mut keypad)?;
+        let sound_timer = input.u8()?;
+        let delay_timer = input.u8()?;
+        let waiting_for_vblank = input.bool()?;
+        let vblank_ready = input.bool()?;
+        let quirks = input.quirks()?;
+        let vip_timing = input.bool()?;
+        let variant = match input.u8()? {
+            0 => Variant::Chip8,
+            1 => Variant::Chip8X,
+            2 => Variant::Chip8E,
+            _ => return Err(state::invalid("unknown variant")),
+        };
+        let mut zones = [0; ZONE_COLUMNS * ZONE_ROWS];
+        input.bytes_into(&mut zones)?;
+        let background = input.u8()? as usize % CHIP8X_BACKGROUNDS.len();
+        let mut keypad2 = [0; 16];
+        input.bytes_into(&mut keypad2)?;
+        let port_output = input.option_u8()?;
+        let port_input = input.option_u8()?;
+        let delay_wait = input.bool()?;
+        let mut flags = [0; FLAG_COUNT];
+        input.bytes_into(&mut flags)?;
+        let font_start = input.u16()? as usize;
+        if font_start >= MEMORY_SIZE {
+            return Err(state::invalid("font outside memory"));
+        }
+
+        *self = Chip8 {
+            pc, memory, v_reg, i_reg, stack, gfx, hires, keypad, sound_timer, delay_timer,
+            waiting_for_vblank, vblank_ready, quirks, vip_timing, variant, zones, background,
+            keypad2, port_output, port_input, delay_wait, flags, font_start,
+            font_len: self.font_len.min(MEMORY_SIZE - font_start),
+            flag_store: std::mem::replace(&mut self.flag_store, Box::new(NoStore)),
+            history: History::default(),
+            coverage: std::mem::replace(&mut self.coverage, Coverage::new(0)),
+            profiler: self.profiler.take(),
+            diagnostics: self.diagnostics.take(),
+            code_writes: Vec::new(),
+            rewritten: HashSet::new(),
+            write_log: self.write_log.take(),
+            rng: self.rng.take(),
+        };
+        Ok(())
+    }
+
+    pub fn write_memory(&mut self, address: usize, bytes: &[u8]) {
+        self.memory[address..address + bytes.len()].copy_from_slice(bytes);
+    }
+
+    pub fn load_rom(&mut self, path: &str) -> Result<(), Chip8Error> {
+        let rom = fs::read(path).map_err(|e| Chip8Error::Io(format!("Error opening file {}: {}", path, e)))?;
+        self.load_bytes(&rom)
+    }
+
+    pub fn load_bytes(&mut self, rom: &[u8]) -> Result<(), Chip8Error> {
+        let start = self.variant.program_start();
+        if rom.len() > MEMORY_SIZE - start {
+            return Err(Chip8Error::RomTooLarge { size: rom.len(), max: MEMORY_SIZE - start });
+        }
+
+        self.write_memory(start, rom);
+
+        // HIRES programs start by jumping over the 1802 patch to the VIP
+        // interpreter that they carry at 0x202-0x25F, into its setup code at
+        // 0x260-0x2BF. The display is emulated here, so jump straight to the
+        // program at 0x2C0 instead.
+        if self.variant == Variant::Chip8 && rom.starts_with(&[0x12, 0x60]) {
+            self.set_hires(true);
+            self.write_memory(start, &[0x12, 0xC0]);
         }
+        Ok(())
+    }
+
+    fn read(&self, address: usize) -> Result<u8, Chip8Error> {
+        self.memory.get(address).copied().ok_or(Chip8Error::MemoryOutOfBounds { pc: self.pc, address })
     }
 
-    pub fn load_fontset(&mut self) -> () {
-        let fontset: [u8; 80] = [
-            0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
-            0x20, 0x60, 0x20, 0x20, 0x70, // 1
-            0xF0, 0x10, 0xF0, 0x80, 0xF0, // 2
-            0xF0, 0x10, 0xF0, 0x10, 0xF0, // 3
-            0x90, 0x90, 0xF0, 0x10, 0x10, // 4
-            0xF0, 0x80, 0xF0, 0x10, 0xF0, // 5
-            0xF0, 0x80, 0xF0, 0x90, 0xF0, // 6
-            0xF0, 0x10, 0x20, 0x40, 0x40, // 7
-            0xF0, 0x90, 0xF0, 0x90, 0xF0, // 8
-            0xF0, 0x90, 0xF0, 0x10, 0xF0, // 9
-            0xF0, 0x90, 0xF0, 0x90, 0x90, // A
-            0xE0, 0x90, 0xE0, 0x90, 0xE0, // B
-            0xF0, 0x80, 0x80, 0x80, 0xF0, // C
-            0xE0, 0x90, 0x90, 0x90, 0xE0, // D
-            0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
-            0xF0, 0x80, 0xF0, 0x80, 0x80  // F
-        ];
+    fn write(&mut self, address: usize, value: u8) -> Result<(), Chip8Error> {
+        let pc = self.pc;
+        if address < MEMORY_SIZE && self.coverage.touched(address, Access::Execute) {
+            self.note_code_write(address);
+        }
+        let byte = self.memory.get_mut(address).ok_or(Chip8Error::MemoryOutOfBounds { pc, address })?;
+        *byte = value;
+        if let Some(log) = &mut self.write_log {
+            log.push((address, value));
+        }
+        Ok(())
+    }
 
-        for i in 0..80 {
-            self.memory[i + 0x50] = fontset[i];
+    // Remembers the instruction `address` belongs to as it was before the
+    // write, to report once the writing instruction is done.
+    fn note_code_write(&mut self, address: usize) {
+        let start = if self.coverage.instruction_start(address) { address } else { address - 1 };
+        if self.diagnostics.is_none() || self.rewritten.contains(&start)
+            || self.code_writes.iter().any(|&(pending, _, _)| pending == start) {
+            return;
         }
+        let old_opcode = u16::from_be_bytes([self.memory[start], self.memory.get(start + 1).copied().unwrap_or(0)]);
+        self.code_writes.push((start, old_opcode, self.pc));
     }
 
-    pub fn interpret(&mut self) -> () {
-        let opcode: u16 = 
-            (self.memory[self.pc as usize] as u16) << 8 | self.memory[(self.pc + 1) as usize] as u16;
+    // Each instruction is reported the first time it changes; programs that
+    // patch themselves tend to do it over and over.
+    fn report_code_writes(&mut self) {
+        for (start, old_opcode, writer) in std::mem::take(&mut self.code_writes) {
+            let new_opcode = u16::from_be_bytes([self.memory[start], self.memory.get(start + 1).copied().unwrap_or(0)]);
+            if new_opcode == old_opcode {
+                continue;
+            }
+            self.rewritten.insert(start);
+            if let Some(sender) = &self.diagnostics {
+                let diagnostic = Diagnostic::SelfModifyingCode { address: start as u16, writer, old_opcode, new_opcode };
+                // Nobody listening any more is fine.
+                let _ = sender.send(diagnostic);
+            }
+        }
+    }
+
+    fn key(&self, key: u8) -> Result<u8, Chip8Error> {
+        self.keypad.get(key as usize).copied().ok_or(Chip8Error::InvalidKey { pc: self.pc, key })
+    }
+
+    // The 60 Hz frame boundary: ticks the timers and lets a draw that is
+    // waiting for the display go ahead on the next instruction.
+    pub fn vblank(&mut self) {
+        if let Some(profiler) = &mut self.profiler {
+            profiler.end_frame();
+        }
+
+        if self.delay_timer > 0 {
+            self.delay_timer -= 1;
+        }
+
+        if self.sound_timer > 0 {
+            self.sound_timer -= 1;
+        }
+
+        if self.waiting_for_vblank {
+            self.vblank_ready = true;
+        }
+    }
+
+    pub fn interpret(&mut self) -> Result<(), Chip8Error> {
+        let result = self.run_instruction();
+        if !self.code_writes.is_empty() {
+            self.report_code_writes();
+        }
+        result
+    }
+
+    fn run_instruction(&mut self) -> Result<(), Chip8Error> {
+        let opcode: u16 =
+            (self.read(self.pc as usize)? as u16) << 8 | self.read(self.pc as usize + 1)? as u16;
         //println!("{:#4x?}", opcode);
+        // A draw waiting for the frame to end was recorded when it first
+        // came up; its retries aren't further instructions.
+        if !self.waiting_for_vblank {
+            self.history.record(self.pc as usize, opcode);
+            self.coverage.record(self.pc as usize, 2, Access::Execute);
+            if let Some(profiler) = &mut self.profiler {
+                profiler.record(self.pc as usize, opcode);
+            }
+        }
+
+        // The variants decode through their own tables; what they share with
+        // CHIP-8 runs below.
+        if self.variant != Variant::Chip8 {
+            match self.variant.decode(opcode) {
+                None => return Err(Chip8Error::InvalidOpcode { pc: self.pc, opcode }),
+                Some(instruction) => {
+                    if self.execute_extension(instruction)? {
+                        return Ok(());
+                    }
+                },
+            }
+        }
 
         match opcode & 0xF000 {
             0x0000 =>
-                match opcode & 0x000F {
-                    0x0000 => {
+                match opcode {
+                    0x00E0 => {
                         self.gfx.iter_mut().for_each(|n| *n = 0);
                         self.pc += 2;
                     },
 
-                    0x000E => {
-                        self.sp -= 1;
-                        self.pc = self.stack[self.sp as usize];
+                    // The HIRES interpreter clears both pages with 0230.
+                    0x0230 if self.hires => {
+                        self.gfx.iter_mut().for_each(|n| *n = 0);
+                        self.pc += 2;
+                    },
+
+                    0x00EE => {
+                        self.pc = self.stack.pop(self.pc as usize, self.quirks.wrap_stack)?.return_address as u16;
                     },
 
-                    _ => throw_error(opcode),
+                    _ => return Err(Chip8Error::InvalidOpcode { pc: self.pc, opcode }),
                 },
             
             0x1000 => self.pc = opcode & 0x0FFF,
 
             0x2000 => {
-                self.stack[self.sp as usize] = self.pc + 2;
-                self.sp += 1;
+                let frame = CallFrame { call_site: self.pc as usize, return_address: self.pc as usize + 2 };
+                self.stack.push(frame, self.quirks.wrap_stack)?;
                 self.pc = opcode & 0x0FFF;
             },
 
@@ -112,7 +685,7 @@ impl Chip8 {
             0x5000 => {
                 let x = ((opcode & 0x0F00) >> 8) as usize;
                 let y = ((opcode & 0x00F0) >> 4) as usize;
-                if self.v_reg[x as usize] == self.v_reg[y as usize] {
+                if self.v_reg[x] == self.v_reg[y] {
                     self.pc += 4;
                 } else {
                     self.pc += 2;
@@ -132,95 +705,15 @@ impl Chip8 {
                 self.pc += 2;
             },
 
-            0x8000 =>
-                match opcode & 0x000F {
-                    0x0000 => {
-                        self.v_reg[((opcode & 0x0F00) >> 8) as usize] = 
-                            self.v_reg[((opcode & 0x00F0) >> 4) as usize];
-                        self.pc += 2;
-                    },
-
-                    0x0001 => {
-                        self.v_reg[((opcode & 0x0F00) >> 8) as usize] |= 
-                            self.v_reg[((opcode & 0x00F0) >> 4) as usize];
-                        self.pc += 2;
-                    },
-
-                    0x0002 => {
-                        self.v_reg[((opcode & 0x0F00) >> 8) as usize] &= 
-                            self.v_reg[((opcode & 0x00F0) >> 4) as usize];
-                        self.pc += 2;
-                    },
-
-                    0x0003 => {
-                        self.v_reg[((opcode & 0x0F00) >> 8) as usize] ^= 
-                            self.v_reg[((opcode & 0x00F0) >> 4) as usize];
-                        self.pc += 2;
-                    },
-
-                    0x0004 => {
-                        let x = ((opcode & 0x0F00) >> 8) as usize;
-                        let y = ((opcode & 0x00F0) >> 4) as usize;
-
-                        let sum = self.v_reg[x] as u16 + self.v_reg[y] as u16;
-
-                        self.v_reg[0xF] = if sum > 255 { 1 } else { 0 };
-                        self.v_reg[x] = (sum & 0x00FF) as u8;
-                        self.pc += 2;
-                    },
-
-                    0x0005 => {
-                        let x = ((opcode & 0x0F00) >> 8) as usize;
-                        let y = ((opcode & 0x00F0) >> 4) as usize;
-                        
-                        if self.v_reg[x] > self.v_reg[y] {
-                            self.v_reg[0xF] = 1;
-                            self.v_reg[x] -= self.v_reg[y];
-                        } else {
-                            self.v_reg[0xF] = 0;
-                            self.v_reg[x] = self.v_reg[x].wrapping_sub(self.v_reg[y]);
-                        }
-
-                        self.pc += 2;
-                    },
-
-                    0x0006 => {
-                        let x = ((opcode & 0x0F00) >> 8) as usize;
-                        let y = ((opcode & 0x00F0) >> 4) as usize;
-
-                        self.v_reg[0xF] = self.v_reg[y] & 0b00000001;
-
-                        self.v_reg[x] = self.v_reg[y] >> 1;
-                        self.pc += 2;
-                    },
-
-                    0x0007 => {
-                        let x = ((opcode & 0x0F00) >> 8) as usize;
-                        let y = ((opcode & 0x00F0) >> 4) as usize;
-
-                        if self.v_reg[y] > self.v_reg[x] {
-                            self.v_reg[0xF] = 1;
-                            self.v_reg[x] = self.v_reg[y] - self.v_reg[x];
-                        } else {
-                            self.v_reg[0xF] = 0;
-                            self.v_reg[x] = self.v_reg[y].wrapping_sub(self.v_reg[x]);
-                        }
-
-                        self.pc += 2;
-                    },
-
-                    0x000E => {
-                        let x = ((opcode & 0x0F00) >> 8) as usize;
-                        let y = ((opcode & 0x00F0) >> 4) as usize;
-
-                        self.v_reg[0xF] = self.v_reg[y] & 0b10000000;
-
-                        self.v_reg[x] = self.v_reg[y] << 1;
-                        self.pc += 2;
-                    },
+            0x8000 => {
+                let x = ((opcode & 0x0F00) >> 8) as usize;
+                let y = ((opcode & 0x00F0) >> 4) as usize;
 
-                    _ => throw_error(opcode),
-                },
+                if !alu::execute(&mut self.v_reg, (opcode & 0x000F) as u8, x, y, &self.quirks) {
+                    return Err(Chip8Error::InvalidOpcode { pc: self.pc, opcode });
+                }
+                self.pc += 2;
+            },
 
             0x9000 => {
                 let x = ((opcode & 0x0F00) >> 8) as usize;
@@ -234,40 +727,44 @@ impl Chip8 {
                 self.pc += 2;
             },
 
-            0xB000 => self.pc = (opcode & 0x0FFF) + self.v_reg[0] as u16,
+            0xB000 => {
+                let offset = if self.quirks.jump_vx { self.v_reg[((opcode & 0x0F00) >> 8) as usize] } else { self.v_reg[0] };
+                self.pc = (opcode & 0x0FFF) + offset as u16;
+            },
 
             0xC000 => {
-                let mut rng = rand::thread_rng();
-                self.v_reg[((opcode & 0x0F00) >> 8) as usize] =
-                    (opcode & 0x00FF) as u8 & rng.gen_range(0..=255);
+                let random: u8 = match &mut self.rng {
+                    Some(rng) => rng.gen_range(0..=255),
+                    None => rand::thread_rng().gen_range(0..=255),
+                };
+                self.v_reg[((opcode & 0x0F00) >> 8) as usize] = (opcode & 0x00FF) as u8 & random;
 
                 self.pc += 2;
             },
 
             0xD000 => {
+                // Retried on every instruction until the frame ends, like
+                // Fx0A waits for a key.
+                if self.quirks.display_wait && !self.vblank_ready {
+                    self.waiting_for_vblank = true;
+                    return Ok(());
+                }
+                self.waiting_for_vblank = false;
+                self.vblank_ready = false;
+
                 let x = ((opcode & 0x0F00) >> 8) as usize;
                 let y = ((opcode & 0x00F0) >> 4) as usize;
-                let height = opcode & 0x000F;
-
-                let x_pos = self.v_reg[x];
-                let y_pos = self.v_reg[y];
-
-                self.v_reg[0xF] = 0;
+                let height = (opcode & 0x000F) as usize;
 
+                let mut sprite = Vec::with_capacity(height);
                 for row in 0..height {
-                    let pixel = self.memory[(self.i_reg + row) as usize];
-
-                    for col in 0..8 {
-                        if (pixel & (0x80 >> col)) != 0 {
-                            let pos = ((x_pos as u16 + col as u16) + ((y_pos as u16 + row) * 64)) % 2048;
-
-                            if self.gfx[pos as usize] == 1 {
-                                self.v_reg[0xF] = 1;
-                            }
-                            self.gfx[pos as usize] ^= 1;
-                        }
-                    }
+                    sprite.push(self.read(self.i_reg as usize + row)?);
                 }
+                self.coverage.record(self.i_reg as usize, height, Access::Sprite);
+
+                let collided_rows = display::draw_sprite(
+                    &mut self.gfx, 64, self.v_reg[x] as usize, self.v_reg[y] as usize, &sprite, false, self.quirks.wrap_sprites);
+                self.v_reg[0xF] = (collided_rows > 0) as u8;
 
                 self.pc += 2;
             },
@@ -277,16 +774,16 @@ impl Chip8 {
                     0x000E => {
                         let x = ((opcode & 0x0F00) >> 8) as usize;
                         self.pc += 
-                            if self.keypad[self.v_reg[x] as usize] != 0 { 4 } else { 2 };
+                            if self.key(self.v_reg[x])? != 0 { 4 } else { 2 };
                     },
 
                     0x0001 => {
                         let x = ((opcode & 0x0F00) >> 8) as usize;
                         self.pc += 
-                            if self.keypad[self.v_reg[x] as usize] == 0 { 4 } else { 2 };
+                            if self.key(self.v_reg[x])? == 0 { 4 } else { 2 };
                     },
 
-                    _ => throw_error(opcode),
+                    _ => return Err(Chip8Error::InvalidOpcode { pc: self.pc, opcode }),
             },
 
             0xF000 =>
@@ -360,12 +857,19 @@ impl Chip8 {
                     },
 
                     0x001E => {
-                        self.i_reg += self.v_reg[((opcode & 0x0F00) >> 8) as usize] as u16;
+                        self.i_reg = self.i_reg.wrapping_add(self.v_reg[((opcode & 0x0F00) >> 8) as usize] as u16);
                         self.pc += 2;
                     },
 
                     0x0029 => {
-                        self.i_reg = 0x50 + 5 * self.v_reg[((opcode & 0x0F00) >> 8) as usize] as u16;
+                        let digit = self.v_reg[((opcode & 0x0F00) >> 8) as usize] as usize;
+                        self.i_reg = (self.font_start + font::SMALL_GLYPH * digit) as u16;
+                        self.pc += 2;
+                    },
+
+                    0x0030 => {
+                        let digit = self.v_reg[((opcode & 0x0F00) >> 8) as usize] as usize;
+                        self.i_reg = (self.font_start + font::SMALL_SIZE + font::LARGE_GLYPH * digit) as u16;
                         self.pc += 2;
                     },
 
@@ -373,12 +877,13 @@ impl Chip8 {
                         let x = ((opcode & 0x0F00) >> 8) as usize;
                         let mut val = self.v_reg[x];
 
-                        self.memory[(self.i_reg + 2) as usize] = val % 10;
+                        self.write(self.i_reg as usize + 2, val % 10)?;
                         val /= 10;
-                        self.memory[(self.i_reg + 1) as usize] = val % 10;
+                        self.write(self.i_reg as usize + 1, val % 10)?;
                         val /= 10;
-                        self.memory[self.i_reg as usize] = val % 10;
-                        
+                        self.write(self.i_reg as usize, val % 10)?;
+                        self.coverage.record(self.i_reg as usize, 3, Access::Write);
+
                         self.pc += 2;
                     },
 
@@ -386,10 +891,13 @@ impl Chip8 {
                         let x = ((opcode & 0x0F00) >> 8) as usize;
 
                         for i in 0..=x {
-                            self.memory[self.i_reg as usize + i] = self.v_reg[i];
+                            self.write(self.i_reg as usize + i, self.v_reg[i])?;
                         }
+                        self.coverage.record(self.i_reg as usize, x + 1, Access::Write);
 
-                        self.i_reg += (x + 1) as u16;
+                        if self.quirks.increment_i {
+                            self.i_reg = self.i_reg.wrapping_add((x + 1) as u16);
+                        }
                         self.pc += 2;
                     },
 
@@ -397,30 +905,1149 @@ impl Chip8 {
                         let x = ((opcode & 0x0F00) >> 8) as usize;
 
                         for i in 0..=x {
-                            self.v_reg[i] = self.memory[self.i_reg as usize + i];
+                            self.v_reg[i] = self.read(self.i_reg as usize + i)?;
+                        }
+                        self.coverage.record(self.i_reg as usize, x + 1, Access::Read);
+                        if self.quirks.increment_i {
+                            self.i_reg = self.i_reg.wrapping_add((x + 1) as u16);
                         }
-                        self.i_reg += (x + 1) as u16;
                         self.pc += 2;
                     },
 
-                    _ => throw_error(opcode),
+                    // SCHIP only had V0-V7 worth of flags, XO-CHIP has all
+                    // 16; programs for the former never ask for more.
+                    0x0075 => {
+                        let x = ((opcode & 0x0F00) >> 8) as usize;
+
+                        self.flags[..=x].copy_from_slice(&self.v_reg[..=x]);
+                        self.flag_store.save(&self.flags)
+                            .map_err(|e| Chip8Error::Io(format!("Error saving RPL flags: {}", e)))?;
+                        self.pc += 2;
+                    },
+
+                    0x0085 => {
+                        let x = ((opcode & 0x0F00) >> 8) as usize;
+
+                        self.v_reg[..=x].copy_from_slice(&self.flags[..=x]);
+                        self.pc += 2;
+                    },
+
+                    _ => return Err(Chip8Error::InvalidOpcode { pc: self.pc, opcode }),
                 },
 
-            _ => throw_error(opcode),
+            _ => return Err(Chip8Error::InvalidOpcode { pc: self.pc, opcode }),
         }
 
-        if self.delay_timer > 0 {
-            self.delay_timer -= 1;
+        Ok(())
+    }
+
+    // Runs `instruction` if it's one of the variant-only ones, returning
+    // whether it was.
+    fn execute_extension(&mut self, instruction: Instruction) -> Result<bool, Chip8Error> {
+        let next = self.pc.wrapping_add(2);
+        match instruction {
+            Instruction::BgColor => {
+                self.background = (self.background + 1) % CHIP8X_BACKGROUNDS.len();
+                self.pc = next;
+            },
+
+            Instruction::AddNibbles(x, y) => {
+                let (vx, vy) = (self.v_reg[x], self.v_reg[y]);
+                let high = ((vx >> 4) + (vy >> 4)) & 0x7;
+                let low = ((vx & 0xF) + (vy & 0xF)) & 0x7;
+                self.v_reg[x] = high << 4 | low;
+                self.pc = next;
+            },
+
+            Instruction::Color(x, y, n) => {
+                let color = self.v_reg[y] & 0x7;
+                let horizontal = self.v_reg[x];
+                let vertical = self.v_reg[(x + 1) & 0xF];
+
+                if n == 0 {
+                    // Low nibbles place the block, high nibbles size it minus
+                    // one, vertically in units of 4 rows.
+                    let left = (horizontal & 0xF) as usize;
+                    let width = (horizontal >> 4) as usize + 1;
+                    let top = (vertical & 0xF) as usize * 4;
+                    let height = ((vertical >> 4) as usize + 1) * 4;
+                    for row in top..top + height {
+                        for column in left..left + width {
+                            self.zones[(row % ZONE_ROWS) * ZONE_COLUMNS + column % ZONE_COLUMNS] = color;
+                        }
+                    }
+                } else {
+                    let column = horizontal as usize / 8 % ZONE_COLUMNS;
+                    for row in vertical as usize..vertical as usize + n as usize {
+                        self.zones[(row % ZONE_ROWS) * ZONE_COLUMNS + column] = color;
+                    }
+                }
+                self.pc = next;
+            },
+
+            Instruction::Skp2(x) | Instruction::Sknp2(x) => {
+                let key = self.v_reg[x];
+                let pressed = *self.keypad2.get(key as usize).ok_or(Chip8Error::InvalidKey { pc: self.pc, key })? != 0;
+                let taken = pressed == (instruction == Instruction::Skp2(x));
+                self.pc = next.wrapping_add(if taken { 2 } else { 0 });
+            },
+
+            Instruction::Stop => {},
+
+            Instruction::Nop => self.pc = next,
+
+            Instruction::WaitDt => {
+                if self.delay_timer == 0 {
+                    self.pc = next;
+                }
+            },
+
+            Instruction::Skip => self.pc = next.wrapping_add(2),
+
+            Instruction::Sgt(x, y) => {
+                self.pc = next.wrapping_add(if self.v_reg[x] > self.v_reg[y] { 2 } else { 0 });
+            },
+
+            Instruction::StoreRange(x, y) => {
+                for (offset, register) in register_range(x, y).enumerate() {
+                    self.write(self.i_reg as usize + offset, self.v_reg[register])?;
+                }
+                self.coverage.record(self.i_reg as usize, register_range(x, y).count(), Access::Write);
+                self.pc = next;
+            },
+
+            Instruction::LoadRange(x, y) => {
+                for (offset, register) in register_range(x, y).enumerate() {
+                    self.v_reg[register] = self.read(self.i_reg as usize + offset)?;
+                }
+                self.coverage.record(self.i_reg as usize, register_range(x, y).count(), Access::Read);
+                self.pc = next;
+            },
+
+            Instruction::JpBack(nn) => self.pc = next.wrapping_sub(nn as u16),
+
+            Instruction::JpForward(nn) => self.pc = next.wrapping_add(nn as u16),
+
+            Instruction::SkipBytes(x) => self.pc = next.wrapping_add(self.v_reg[x] as u16),
+
+            Instruction::Delay(x) => {
+                if !self.delay_wait {
+                    self.delay_timer = self.v_reg[x];
+                    self.delay_wait = true;
+                }
+                if self.delay_timer == 0 {
+                    self.delay_wait = false;
+                    self.pc = next;
+                }
+            },
+
+            Instruction::Out(x) => {
+                self.port_output = Some(self.v_reg[x]);
+                self.pc = next;
+            },
+
+            // Retried until something arrives, like Fx0A.
+            Instruction::In(x) => {
+                if let Some(value) = self.port_input.take() {
+                    self.v_reg[x] = value;
+                    self.pc = next;
+                }
+            },
+
+            Instruction::Inp(x) => {
+                self.v_reg[x] = self.port_input.unwrap_or(0);
+                self.pc = next;
+            },
+
+            _ => return Ok(false),
         }
 
-        if self.sound_timer > 0 {
-            //println!("BEEP");
-            self.sound_timer -= 1;
+        Ok(true)
+    }
+
+    // What the display shows. CHIP-8X lights pixels in the colour of their
+    // zone over the selected background; the zones only cover 32 rows, so a
+    // 64 row screen repeats them.
+    pub fn frame(&self) -> Frame {
+        if self.variant != Variant::Chip8X {
+            return Frame::monochrome(&self.gfx, 64);
         }
+
+        let background = CHIP8X_PALETTE[CHIP8X_BACKGROUNDS[self.background] as usize];
+        let pixels = self.gfx.iter().enumerate().map(|(i, &pixel)| {
+            if pixel != 0 {
+                CHIP8X_PALETTE[self.zones[(i / 64 % ZONE_ROWS) * ZONE_COLUMNS + (i % 64) / 8] as usize]
+            } else {
+                background
+            }
+        }).collect();
+        Frame { width: 64, height: self.display_height(), pixels }
     }
+}
 
+// Vx to Vy, counting down when x is the larger.
+fn register_range(x: usize, y: usize) -> Box<dyn Iterator<Item = usize>> {
+    if x <= y {
+        Box::new(x..=y)
+    } else {
+        Box::new((y..=x).rev())
+    }
 }
 
-fn throw_error(opcode: u16) -> () {
-    panic!("Invalid opcode: {:#4x?}", opcode);
+
+impl Machine for Chip8 {
+    fn step(&mut self) -> Result<u32, Chip8Error> {
+        if self.vip_timing {
+            timing::step_vip(self)
+        } else {
+            self.interpret().map(|_| 1)
+        }
+    }
+
+    fn vblank(&mut self) {
+        Chip8::vblank(self);
+    }
+
+    fn keypad(&mut self) -> &mut [u8; 16] {
+        &mut self.keypad
+    }
+
+    fn frame(&self) -> Frame {
+        Chip8::frame(self)
+    }
+
+    fn lit_pixels(&self) -> (Vec<u8>, usize) {
+        (self.gfx.clone(), 64)
+    }
+
+    fn sound_active(&self) -> bool {
+        self.sound_timer > 0
+    }
+
+    fn call_stack(&self) -> &[CallFrame] {
+        Chip8::call_stack(self)
+    }
+
+    fn quirks(&self) -> Option<Quirks> {
+        Some(self.quirks)
+    }
+
+    fn history(&self) -> Vec<String> {
+        self.history.entries()
+            .flat_map(|&(address, opcode)| instruction::disassemble(&opcode.to_be_bytes(), address as u16, self.variant))
+            .collect()
+    }
+
+    fn listing(&self, before: usize, after: usize) -> Vec<String> {
+        let pc = self.pc as usize;
+        let start = pc.saturating_sub(before * 2);
+        let end = (pc + after * 2 + 2).min(MEMORY_SIZE);
+        instruction::disassemble(&self.memory[start.min(end)..end], start as u16, self.variant)
+    }
+
+    fn save_state(&self) -> Option<Vec<u8>> {
+        Some(Chip8::save_state(self))
+    }
+
+    fn coverage(&self) -> Option<&Coverage> {
+        Some(&self.coverage)
+    }
+
+    fn profiler(&self) -> Option<&Profiler> {
+        Chip8::profiler(self)
+    }
+
+    fn as_chip8(&mut self) -> Option<&mut Chip8> {
+        Some(self)
+    }
+
+    fn register_lines(&self) -> Vec<String> {
+        let mut lines = vec![format!("PC {:04X}", self.pc), format!("I  {:04X}", self.i_reg)];
+        for i in 0..8 {
+            lines.push(format!("V{:X} {:02X} V{:X} {:02X}", i, self.v_reg[i], i + 8, self.v_reg[i + 8]));
+        }
+        lines.push(format!("SP {:X}", self.stack.sp()));
+        lines.push(format!("DT {:02X} ST {:02X}", self.delay_timer, self.sound_timer));
+        if self.waiting_for_vblank {
+            lines.push(String::from("VBLANK WAIT"));
+        }
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alu;
+    use crate::stack;
+
+    // Single steps never reach a frame boundary, so draws mustn't wait for one.
+    fn try_step(opcode: u16, setup: impl FnOnce(&mut Chip8)) -> Result<Chip8, Chip8Error> {
+        let mut c8 = Chip8::with_quirks(Quirks { display_wait: false, ..Quirks::CHIP8 });
+        c8.write_memory(0x200, &opcode.to_be_bytes());
+        setup(&mut c8);
+        c8.interpret()?;
+        Ok(c8)
+    }
+
+    fn step(opcode: u16, setup: impl FnOnce(&mut Chip8)) -> Chip8 {
+        try_step(opcode, setup).unwrap()
+    }
+
+    #[test]
+    fn clear_screen() {
+        let c8 = step(0x00E0, |c8| c8.gfx.iter_mut().for_each(|n| *n = 1));
+        assert!(c8.gfx.iter().all(|&n| n == 0));
+        assert_eq!(c8.pc(), 0x202);
+    }
+
+    #[test]
+    fn return_from_subroutine() {
+        let c8 = step(0x00EE, |c8| c8.set_stack(&[0x300, 0x346]));
+        assert_eq!(c8.pc(), 0x346);
+        assert_eq!(c8.sp(), 1);
+    }
+
+    #[test]
+    fn jump() {
+        assert_eq!(step(0x1345, |_| {}).pc(), 0x345);
+    }
+
+    #[test]
+    fn call_subroutine() {
+        let c8 = step(0x2345, |_| {});
+        assert_eq!(c8.pc(), 0x345);
+        assert_eq!(c8.sp(), 1);
+        assert_eq!(c8.call_stack(), [CallFrame { call_site: 0x200, return_address: 0x202 }]);
+    }
+
+    #[test]
+    fn skip_if_equal_immediate() {
+        assert_eq!(step(0x3342, |c8| c8.set_v_reg(3, 0x42)).pc(), 0x204);
+        assert_eq!(step(0x3343, |c8| c8.set_v_reg(3, 0x42)).pc(), 0x202);
+    }
+
+    #[test]
+    fn skip_if_not_equal_immediate() {
+        assert_eq!(step(0x4342, |c8| c8.set_v_reg(3, 0x42)).pc(), 0x202);
+        assert_eq!(step(0x4343, |c8| c8.set_v_reg(3, 0x42)).pc(), 0x204);
+    }
+
+    #[test]
+    fn skip_if_registers_equal() {
+        assert_eq!(step(0x5340, |c8| { c8.set_v_reg(3, 7); c8.set_v_reg(4, 7) }).pc(), 0x204);
+        assert_eq!(step(0x5340, |c8| { c8.set_v_reg(3, 7); c8.set_v_reg(4, 8) }).pc(), 0x202);
+    }
+
+    #[test]
+    fn skip_if_registers_not_equal() {
+        assert_eq!(step(0x9340, |c8| { c8.set_v_reg(3, 7); c8.set_v_reg(4, 7) }).pc(), 0x202);
+        assert_eq!(step(0x9340, |c8| { c8.set_v_reg(3, 7); c8.set_v_reg(4, 8) }).pc(), 0x204);
+    }
+
+    #[test]
+    fn load_immediate() {
+        let c8 = step(0x6A42, |_| {});
+        assert_eq!(c8.v_reg()[0xA], 0x42);
+        assert_eq!(c8.pc(), 0x202);
+    }
+
+    #[test]
+    fn add_immediate_wraps_without_carry() {
+        let c8 = step(0x7A10, |c8| { c8.set_v_reg(0xA, 0xF8); c8.set_v_reg(0xF, 0x55) });
+        assert_eq!(c8.v_reg()[0xA], 0x08);
+        assert_eq!(c8.v_reg()[0xF], 0x55);
+    }
+
+    #[test]
+    fn load_i() {
+        assert_eq!(step(0xA345, |_| {}).i_reg(), 0x345);
+    }
+
+    #[test]
+    fn jump_with_offset() {
+        assert_eq!(step(0xB300, |c8| c8.set_v_reg(0, 0x46)).pc(), 0x346);
+    }
+
+    #[test]
+    fn random_is_masked() {
+        for _ in 0..64 {
+            let c8 = step(0xC30F, |_| {});
+            assert_eq!(c8.v_reg()[3] & 0xF0, 0);
+        }
+        assert_eq!(step(0xC300, |c8| c8.set_v_reg(3, 0xFF)).v_reg()[3], 0);
+    }
+
+    #[test]
+    fn draw_sprite_and_collide() {
+        let mut c8 = step(0xD125, |c8| {
+            c8.set_i_reg(0x50);
+            c8.set_v_reg(1, 2);
+            c8.set_v_reg(2, 1);
+        });
+        assert_eq!(&c8.gfx[64 + 2..64 + 6], &[1, 1, 1, 1]);
+        assert_eq!(c8.gfx[64 + 6], 0);
+        assert_eq!(c8.v_reg()[0xF], 0);
+
+        c8.set_pc(0x200);
+        c8.interpret().unwrap();
+        assert!(c8.gfx.iter().all(|&n| n == 0));
+        assert_eq!(c8.v_reg()[0xF], 1);
+    }
+
+    #[test]
+    fn skip_if_key_pressed() {
+        assert_eq!(step(0xE39E, |c8| { c8.set_v_reg(3, 0xA); c8.keypad[0xA] = 1 }).pc(), 0x204);
+        assert_eq!(step(0xE39E, |c8| c8.set_v_reg(3, 0xA)).pc(), 0x202);
+    }
+
+    #[test]
+    fn skip_if_key_not_pressed() {
+        assert_eq!(step(0xE3A1, |c8| { c8.set_v_reg(3, 0xA); c8.keypad[0xA] = 1 }).pc(), 0x202);
+        assert_eq!(step(0xE3A1, |c8| c8.set_v_reg(3, 0xA)).pc(), 0x204);
+    }
+
+    #[test]
+    fn read_delay_timer() {
+        assert_eq!(step(0xF307, |c8| c8.set_delay_timer(0x20)).v_reg()[3], 0x20);
+    }
+
+    #[test]
+    fn wait_for_key() {
+        let c8 = step(0xF30A, |_| {});
+        assert_eq!(c8.pc(), 0x200);
+
+        let c8 = step(0xF30A, |c8| c8.keypad[0xB] = 1);
+        assert_eq!(c8.pc(), 0x202);
+        assert_eq!(c8.v_reg()[3], 0xB);
+    }
+
+    // Timers hold their value until the next vertical blank counts them down.
+    #[test]
+    fn set_timers() {
+        let mut c8 = step(0xF315, |c8| c8.set_v_reg(3, 0x20));
+        assert_eq!(c8.delay_timer(), 0x20);
+        c8.vblank();
+        assert_eq!(c8.delay_timer(), 0x1F);
+
+        let mut c8 = step(0xF318, |c8| c8.set_v_reg(3, 0x20));
+        assert_eq!(c8.sound_timer, 0x20);
+        c8.vblank();
+        assert_eq!(c8.sound_timer, 0x1F);
+    }
+
+    #[test]
+    fn add_to_i() {
+        let c8 = step(0xF31E, |c8| { c8.set_i_reg(0x300); c8.set_v_reg(3, 0x46) });
+        assert_eq!(c8.i_reg(), 0x346);
+    }
+
+    #[test]
+    fn font_character() {
+        for digit in 0..16 {
+            let c8 = step(0xF329, |c8| c8.set_v_reg(3, digit));
+            assert_eq!(c8.i_reg(), 0x50 + 5 * digit as u16);
+        }
+    }
+
+    #[test]
+    fn large_font_character() {
+        let c8 = step(0xF330, |c8| c8.set_v_reg(3, 2));
+        assert_eq!(c8.i_reg(), 0x50 + 80 + 20);
+        assert_eq!(c8.memory()[c8.i_reg() as usize..][..10], Font::default().large[20..30]);
+    }
+
+    #[test]
+    fn selected_font_and_base() {
+        let c8 = step(0xF329, |c8| {
+            c8.set_font(&Font::named("vip").unwrap(), 0x100).unwrap();
+            c8.set_v_reg(3, 1);
+        });
+        assert_eq!(c8.i_reg(), 0x105);
+        assert_eq!(c8.memory()[0x105..0x10A], [0x60, 0x20, 0x20, 0x20, 0x70]);
+        assert!(Chip8::new().set_font(&Font::default(), 0xF80).is_err());
+    }
+
+    #[test]
+    fn bcd_digits() {
+        for value in 0..=255 {
+            let c8 = step(0xF333, |c8| { c8.set_i_reg(0x300); c8.set_v_reg(3, value) });
+            assert_eq!(&c8.memory()[0x300..0x303], &[value / 100, value / 10 % 10, value % 10], "BCD of {}", value);
+        }
+    }
+
+    #[test]
+    fn store_registers() {
+        let c8 = step(0xF255, |c8| {
+            c8.set_i_reg(0x300);
+            c8.set_v_reg(0, 1);
+            c8.set_v_reg(1, 2);
+            c8.set_v_reg(2, 3);
+            c8.set_v_reg(3, 4);
+        });
+        assert_eq!(&c8.memory()[0x300..0x304], &[1, 2, 3, 0]);
+        assert_eq!(c8.i_reg(), 0x303);
+    }
+
+    #[test]
+    fn load_registers() {
+        let c8 = step(0xF265, |c8| {
+            c8.set_i_reg(0x300);
+            c8.write_memory(0x300, &[1, 2, 3, 4]);
+        });
+        assert_eq!(&c8.v_reg()[0..4], &[1, 2, 3, 0]);
+        assert_eq!(c8.i_reg(), 0x303);
+    }
+
+    #[test]
+    fn invalid_opcode() {
+        assert_eq!(try_step(0xFFFF, |_| {}).unwrap_err(), Chip8Error::InvalidOpcode { pc: 0x200, opcode: 0xFFFF });
+    }
+
+    #[test]
+    fn quirk_shift_vx() {
+        let mut c8 = Chip8::with_quirks(Quirks::SCHIP);
+        c8.write_memory(0x200, &[0x81, 0x26]);
+        c8.set_v_reg(1, 0x05);
+        c8.set_v_reg(2, 0xF0);
+        c8.interpret().unwrap();
+        assert_eq!(c8.v_reg()[1], 0x02);
+        assert_eq!(c8.v_reg()[0xF], 1);
+    }
+
+    #[test]
+    fn quirk_increment_i() {
+        let mut c8 = Chip8::with_quirks(Quirks::SCHIP);
+        c8.write_memory(0x200, &[0xF2, 0x55]);
+        c8.set_i_reg(0x300);
+        c8.interpret().unwrap();
+        assert_eq!(c8.i_reg(), 0x300);
+    }
+
+    #[test]
+    fn quirk_jump_vx() {
+        let mut c8 = Chip8::with_quirks(Quirks::SCHIP);
+        c8.write_memory(0x200, &[0xB3, 0x40]);
+        c8.set_v_reg(0, 0x10);
+        c8.set_v_reg(3, 0x06);
+        c8.interpret().unwrap();
+        assert_eq!(c8.pc(), 0x346);
+    }
+
+    #[test]
+    fn sprite_start_wraps_and_pixels_clip() {
+        let c8 = step(0xD12F, |c8| {
+            c8.set_i_reg(0x300);
+            c8.write_memory(0x300, &[0xFF; 15]);
+            c8.set_v_reg(1, 64 + 60);
+            c8.set_v_reg(2, 32 + 20);
+        });
+        let lit: Vec<usize> = (0..64 * 32).filter(|&i| c8.gfx[i] != 0).collect();
+        assert_eq!(lit.len(), 4 * 12);
+        assert!(lit.iter().all(|&i| i % 64 >= 60 && i / 64 >= 20));
+    }
+
+    #[test]
+    fn quirk_wrap_sprites() {
+        let mut c8 = Chip8::with_quirks(Quirks::XO_CHIP);
+        c8.write_memory(0x200, &[0xD1, 0x2F]);
+        c8.write_memory(0x300, &[0xFF; 15]);
+        c8.set_i_reg(0x300);
+        c8.set_v_reg(1, 60);
+        c8.set_v_reg(2, 20);
+        c8.interpret().unwrap();
+        assert_eq!(c8.gfx.iter().filter(|&&n| n != 0).count(), 8 * 15);
+        assert_eq!(c8.gfx[0], 1);
+        assert_eq!(c8.gfx[2 * 64 + 3], 1);
+        assert_eq!(c8.gfx[3 * 64 + 3], 0);
+    }
+
+    #[test]
+    fn quirk_display_wait() {
+        let mut c8 = Chip8::with_quirks(Quirks::CHIP8);
+        c8.write_memory(0x200, &[0xD0, 0x05, 0xD0, 0x05]);
+        c8.set_i_reg(0x50);
+
+        // Nothing is drawn until the frame ends, and only once per frame.
+        for _ in 0..3 {
+            c8.interpret().unwrap();
+            assert_eq!(c8.pc(), 0x200);
+            assert!(c8.waiting_for_vblank());
+        }
+        c8.vblank();
+        c8.interpret().unwrap();
+        assert_eq!(c8.pc(), 0x202);
+        assert_eq!(c8.gfx[0], 1);
+        assert!(!c8.waiting_for_vblank());
+
+        c8.interpret().unwrap();
+        assert_eq!(c8.pc(), 0x202);
+        c8.vblank();
+        c8.interpret().unwrap();
+        assert_eq!(c8.pc(), 0x204);
+        assert_eq!(c8.gfx[0], 0);
+    }
+
+    #[test]
+    fn waiting_draw_runs_once() {
+        let mut c8 = Chip8::with_quirks(Quirks::CHIP8);
+        c8.enable_profiler();
+        c8.write_memory(0x200, &[0xD0, 0x05, 0x12, 0x02]);
+        (0..5).for_each(|_| c8.interpret().unwrap());
+        c8.vblank();
+        (0..2).for_each(|_| c8.interpret().unwrap());
+
+        assert_eq!(c8.history(), ["200: D005  DRW V0, V0, 5", "202: 1202  JP 202"]);
+        assert_eq!(c8.profiler().unwrap().count(0x200), 1);
+    }
+
+    #[test]
+    fn hires_signature() {
+        let mut c8 = Chip8::new();
+        c8.load_bytes(&[0x12, 0x60, 0xAA]).unwrap();
+        assert!(c8.hires());
+        assert_eq!(&c8.memory()[0x200..0x203], &[0x12, 0xC0, 0xAA]);
+
+        c8.interpret().unwrap();
+        assert_eq!(c8.pc(), 0x2C0);
+
+        // The lower page is drawable and 0230 clears both.
+        c8.write_memory(0x2C0, &[0xD0, 0x11, 0x02, 0x30]);
+        c8.set_i_reg(0x300);
+        c8.write_memory(0x300, &[0x80]);
+        c8.set_v_reg(1, 50);
+        c8.interpret().unwrap();
+        c8.vblank();
+        c8.interpret().unwrap();
+        assert_eq!(c8.gfx[50 * 64], 1);
+        let frame = c8.frame();
+        assert_eq!((frame.width, frame.height), (64, 64));
+        c8.interpret().unwrap();
+        assert!(c8.gfx.iter().all(|&n| n == 0));
+
+        let mut c8 = Chip8::new();
+        c8.load_bytes(&[0x12, 0x34]).unwrap();
+        assert!(!c8.hires());
+        assert_eq!(c8.gfx.len(), 64 * 32);
+    }
+
+    #[test]
+    fn hires_clear_only_in_hires() {
+        assert_eq!(try_step(0x0230, |_| {}).unwrap_err(), Chip8Error::InvalidOpcode { pc: 0x200, opcode: 0x0230 });
+        assert_eq!(try_step(0x00F0, |_| {}).unwrap_err(), Chip8Error::InvalidOpcode { pc: 0x200, opcode: 0x00F0 });
+        assert_eq!(try_step(0x001E, |_| {}).unwrap_err(), Chip8Error::InvalidOpcode { pc: 0x200, opcode: 0x001E });
+
+        let c8 = step(0x0230, |c8| {
+            c8.set_hires(true);
+            c8.gfx[63 * 64] = 1;
+        });
+        assert!(c8.gfx.iter().all(|&n| n == 0));
+        assert_eq!(c8.pc(), 0x202);
+    }
+
+    #[derive(Debug, Default)]
+    struct SharedStore(std::rc::Rc<std::cell::RefCell<[u8; FLAG_COUNT]>>);
+
+    impl FlagStore for SharedStore {
+        fn load(&mut self) -> std::io::Result<[u8; FLAG_COUNT]> {
+            Ok(*self.0.borrow())
+        }
+
+        fn save(&mut self, flags: &[u8; FLAG_COUNT]) -> std::io::Result<()> {
+            *self.0.borrow_mut() = *flags;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn rpl_flags_persist() {
+        let saved = std::rc::Rc::new(std::cell::RefCell::new([0; FLAG_COUNT]));
+
+        let c8 = step(0xF375, |c8| {
+            c8.set_flag_store(Box::new(SharedStore(saved.clone()))).unwrap();
+            (0..16).for_each(|x| c8.set_v_reg(x, x as u8 + 1));
+        });
+        assert_eq!(&c8.flags()[..5], &[1, 2, 3, 4, 0]);
+        assert_eq!(&saved.borrow()[..5], &[1, 2, 3, 4, 0]);
+
+        // A new session starts from the saved flags.
+        let c8 = step(0xFF85, |c8| c8.set_flag_store(Box::new(SharedStore(saved.clone()))).unwrap());
+        assert_eq!(&c8.v_reg()[..5], &[1, 2, 3, 4, 0]);
+    }
+
+    #[test]
+    fn coverage_of_draw_and_store() {
+        let c8 = step(0xD015, |c8| c8.set_i_reg(0x300));
+        let coverage = c8.coverage();
+        assert!(coverage.touched(0x200, Access::Execute) && coverage.touched(0x201, Access::Execute));
+        assert!((0x300..0x305).all(|address| coverage.touched(address, Access::Sprite)));
+        assert!(!coverage.touched(0x305, Access::Sprite));
+
+        let c8 = step(0xF233, |c8| c8.set_i_reg(0x400));
+        assert!((0x400..0x403).all(|address| c8.coverage().touched(address, Access::Write)));
+        let c8 = step(0xF165, |c8| c8.set_i_reg(0x400));
+        assert_eq!(c8.coverage().count(0x401), 1);
+        assert!(c8.coverage().touched(0x401, Access::Read) && !c8.coverage().touched(0x402, Access::Read));
+    }
+
+    #[test]
+    fn reports_self_modifying_code() {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let mut c8 = Chip8::with_quirks(Quirks { display_wait: false, ..Quirks::CHIP8 });
+        c8.set_diagnostics(sender);
+        // Runs 206 once, then patches it with V0-V1 and runs it again.
+        c8.load_bytes(&[0x22, 0x06, 0xA2, 0x06, 0xF1, 0x55, 0x00, 0xEE]).unwrap();
+        c8.set_v_reg(0, 0x12);
+        c8.set_v_reg(1, 0x06);
+        (0..4).for_each(|_| c8.interpret().unwrap());
+
+        let expected = Diagnostic::SelfModifyingCode { address: 0x206, writer: 0x204, old_opcode: 0x00EE, new_opcode: 0x1206 };
+        assert_eq!(receiver.try_iter().collect::<Vec<_>>(), [expected]);
+        c8.interpret().unwrap();
+        assert_eq!(c8.pc(), 0x206);
+
+        // Rewriting it again isn't news.
+        c8.set_pc(0x204);
+        c8.set_v_reg(1, 0x08);
+        c8.interpret().unwrap();
+        assert_eq!(receiver.try_iter().count(), 0);
+    }
+
+    #[test]
+    fn configured_stack_depth() {
+        // The VIP had room for 12 calls, later interpreters for 16.
+        assert_eq!(Chip8::new().stack_depth(), Some(12));
+        assert_eq!(Chip8::with_quirks(Quirks::SCHIP).stack_depth(), Some(16));
+
+        let frames = [0x200; 12];
+        let result = try_step(0x2300, |c8| {
+            c8.set_stack_depth(Some(12));
+            c8.set_stack(&frames);
+        });
+        assert_eq!(result.unwrap_err(), Chip8Error::StackOverflow { pc: 0x200 });
+
+        let c8 = step(0x2300, |c8| {
+            c8.set_stack_depth(None);
+            c8.set_stack(&[0x200; 100]);
+        });
+        assert_eq!(c8.sp(), 101);
+        assert_eq!(c8.stack_depth(), None);
+    }
+
+    #[test]
+    fn wrapping_stack() {
+        let mut c8 = Chip8::with_quirks(Quirks { wrap_stack: true, ..Quirks::CHIP8 });
+        c8.set_stack_depth(Some(2));
+        c8.set_stack(&[0x202, 0x302]);
+        c8.write_memory(0x200, &[0x24, 0x00]);
+        c8.write_memory(0x400, &[0x00, 0xEE, 0x00, 0xEE]);
+        c8.interpret().unwrap();
+        assert_eq!(c8.call_stack(), [CallFrame { call_site: 0x200, return_address: 0x202 }]);
+
+        // Returning past the bottom comes back round to the top slot.
+        c8.interpret().unwrap();
+        assert_eq!(c8.pc(), 0x202);
+        c8.set_pc(0x402);
+        c8.interpret().unwrap();
+        assert_eq!(c8.pc(), 0x302);
+        assert_eq!(c8.sp(), 1);
+    }
+
+    // CHIP-8X and CHIP-8E.
+
+    fn step_variant(variant: Variant, opcode: u16, setup: impl FnOnce(&mut Chip8)) -> Chip8 {
+        let mut c8 = Chip8::new();
+        c8.set_variant(variant);
+        c8.write_memory(c8.pc() as usize, &opcode.to_be_bytes());
+        setup(&mut c8);
+        c8.interpret().unwrap();
+        c8
+    }
+
+    #[test]
+    fn chip8x_loads_at_0x300() {
+        let mut c8 = Chip8::new();
+        c8.set_variant(Variant::Chip8X);
+        c8.load_bytes(&[0x12, 0x34]).unwrap();
+        assert_eq!(c8.pc(), 0x300);
+        assert_eq!(&c8.memory()[0x300..0x302], &[0x12, 0x34]);
+        assert_eq!(c8.load_bytes(&[0; 0xD01]).unwrap_err(), Chip8Error::RomTooLarge { size: 0xD01, max: 0xD00 });
+    }
+
+    #[test]
+    fn font_follows_variant() {
+        let c8 = step_variant(Variant::Chip8X, 0xF329, |c8| c8.set_v_reg(3, 1));
+        assert_eq!(c8.i_reg(), 0x205);
+        assert_eq!(c8.memory()[0x205..0x20A], Font::default().small[5..10]);
+        let c8 = step_variant(Variant::Chip8X, 0xF329, |c8| {
+            c8.set_font(&Font::named("vip").unwrap(), 0x100).unwrap();
+            c8.set_v_reg(3, 1);
+        });
+        assert_eq!(c8.i_reg(), 0x105);
+
+        let c8 = step_variant(Variant::Chip8E, 0xF330, |c8| c8.set_v_reg(3, 2));
+        assert_eq!(c8.i_reg(), 0x50 + 80 + 20);
+        let mut c8 = Chip8::new();
+        c8.set_font(&Font::default(), 0x100).unwrap();
+        c8.set_variant(Variant::Chip8X);
+        c8.write_memory(0x300, &[0xF3, 0x29]);
+        c8.interpret().unwrap();
+        assert_eq!(c8.i_reg(), 0x100);
+    }
+
+    #[test]
+    fn chip8x_add_nibbles() {
+        let c8 = step_variant(Variant::Chip8X, 0x5121, |c8| { c8.set_v_reg(1, 0x35); c8.set_v_reg(2, 0x64) });
+        assert_eq!(c8.v_reg()[1], 0x11);
+        assert_eq!(c8.pc(), 0x302);
+    }
+
+    #[test]
+    fn chip8x_colour_zones() {
+        // Two zones wide from column 1, one 4-row unit high from row 4, yellow.
+        let mut c8 = step_variant(Variant::Chip8X, 0xB130, |c8| {
+            c8.set_v_reg(1, 0x11);
+            c8.set_v_reg(2, 0x01);
+            c8.set_v_reg(3, 0x05);
+        });
+        c8.gfx[4 * 64 + 8] = 1;
+        c8.gfx[4 * 64 + 24] = 1;
+        c8.gfx[8 * 64 + 8] = 1;
+
+        let frame = c8.frame();
+        assert_eq!(frame.pixel(8, 4), CHIP8X_PALETTE[5]);
+        assert_eq!(frame.pixel(24, 4), CHIP8X_PALETTE[1]);
+        assert_eq!(frame.pixel(8, 8), CHIP8X_PALETTE[1]);
+        assert_eq!(frame.pixel(0, 0), CHIP8X_PALETTE[2]);
+
+        // Single rows in the zone column holding Vx.
+        let c8 = step_variant(Variant::Chip8X, 0xB132, |c8| {
+            c8.set_v_reg(1, 20);
+            c8.set_v_reg(2, 10);
+            c8.set_v_reg(3, 6);
+        });
+        assert_eq!(c8.zones[10 * ZONE_COLUMNS + 2], 6);
+        assert_eq!(c8.zones[11 * ZONE_COLUMNS + 2], 6);
+        assert_eq!(c8.zones[12 * ZONE_COLUMNS + 2], 1);
+    }
+
+    #[test]
+    fn chip8x_hires_repeats_zones() {
+        let mut c8 = Chip8::new();
+        c8.set_variant(Variant::Chip8X);
+        c8.set_hires(true);
+        c8.gfx[40 * 64 + 8] = 1;
+        c8.zones[8 * ZONE_COLUMNS + 1] = 5;
+        let frame = c8.frame();
+        assert_eq!(frame.height, 64);
+        assert_eq!(frame.pixel(8, 40), CHIP8X_PALETTE[5]);
+    }
+
+    #[test]
+    fn chip8x_background_cycles() {
+        let mut c8 = Chip8::new();
+        c8.set_variant(Variant::Chip8X);
+        c8.load_bytes(&[0x02, 0xA0, 0x02, 0xA0]).unwrap();
+        c8.interpret().unwrap();
+        assert_eq!(c8.frame().pixel(0, 0), CHIP8X_PALETTE[0]);
+        c8.interpret().unwrap();
+        assert_eq!(c8.frame().pixel(0, 0), CHIP8X_PALETTE[4]);
+    }
+
+    #[test]
+    fn chip8x_second_keypad_and_ports() {
+        assert_eq!(step_variant(Variant::Chip8X, 0xE1F2, |c8| { c8.set_v_reg(1, 3); c8.keypad2[3] = 1 }).pc(), 0x304);
+        assert_eq!(step_variant(Variant::Chip8X, 0xE1F5, |c8| { c8.set_v_reg(1, 3); c8.keypad2[3] = 1 }).pc(), 0x302);
+        assert_eq!(step_variant(Variant::Chip8X, 0xF1F8, |c8| c8.set_v_reg(1, 0x42)).port_output, Some(0x42));
+
+        let c8 = step_variant(Variant::Chip8X, 0xF1FB, |_| {});
+        assert_eq!(c8.pc(), 0x300);
+        let c8 = step_variant(Variant::Chip8X, 0xF1FB, |c8| c8.port_input = Some(0x42));
+        assert_eq!((c8.pc(), c8.v_reg()[1], c8.port_input), (0x302, 0x42, None));
+    }
+
+    #[test]
+    fn chip8e_skips_and_branches() {
+        assert_eq!(step_variant(Variant::Chip8E, 0x0188, |_| {}).pc(), 0x204);
+        assert_eq!(step_variant(Variant::Chip8E, 0x5121, |c8| c8.set_v_reg(1, 2)).pc(), 0x204);
+        assert_eq!(step_variant(Variant::Chip8E, 0x5121, |c8| c8.set_v_reg(2, 2)).pc(), 0x202);
+        assert_eq!(step_variant(Variant::Chip8E, 0xBB06, |_| {}).pc(), 0x1FC);
+        assert_eq!(step_variant(Variant::Chip8E, 0xBF06, |_| {}).pc(), 0x208);
+        assert_eq!(step_variant(Variant::Chip8E, 0xF31B, |c8| c8.set_v_reg(3, 5)).pc(), 0x207);
+        assert_eq!(step_variant(Variant::Chip8E, 0x00ED, |_| {}).pc(), 0x200);
+
+        let mut c8 = Chip8::new();
+        c8.set_variant(Variant::Chip8E);
+        c8.write_memory(0x200, &[0xB1, 0x23]);
+        assert_eq!(c8.interpret(), Err(Chip8Error::InvalidOpcode { pc: 0x200, opcode: 0xB123 }));
+    }
+
+    #[test]
+    fn chip8e_register_ranges() {
+        let c8 = step_variant(Variant::Chip8E, 0x5242, |c8| {
+            c8.set_i_reg(0x400);
+            (2..=4).for_each(|x| c8.set_v_reg(x, x as u8 * 3));
+        });
+        assert_eq!(&c8.memory()[0x400..0x404], &[6, 9, 12, 0]);
+        assert_eq!(c8.i_reg(), 0x400);
+
+        let c8 = step_variant(Variant::Chip8E, 0x5423, |c8| {
+            c8.set_i_reg(0x400);
+            c8.write_memory(0x400, &[1, 2, 3]);
+        });
+        assert_eq!(&c8.v_reg()[2..5], &[3, 2, 1]);
+    }
+
+    #[test]
+    fn chip8e_delay_waits_for_timer() {
+        let mut c8 = Chip8::new();
+        c8.set_variant(Variant::Chip8E);
+        c8.write_memory(0x200, &[0xF1, 0x4F]);
+        c8.set_v_reg(1, 2);
+
+        c8.interpret().unwrap();
+        assert_eq!((c8.pc(), c8.delay_timer()), (0x200, 2));
+        c8.vblank();
+        c8.interpret().unwrap();
+        assert_eq!((c8.pc(), c8.delay_timer()), (0x200, 1));
+        c8.vblank();
+        c8.interpret().unwrap();
+        assert_eq!(c8.pc(), 0x202);
+    }
+
+    // Regressions for inputs that used to panic.
+
+    #[test]
+    fn call_with_full_stack() {
+        let frames = [0x200; stack::VIP_DEPTH];
+        let result = try_step(0x2200, |c8| c8.set_stack(&frames));
+        assert_eq!(result.unwrap_err(), Chip8Error::StackOverflow { pc: 0x200 });
+    }
+
+    #[test]
+    fn stack_below_first_call_site() {
+        let c8 = step(0x00EE, |c8| c8.set_stack(&[0x000, 0x001]));
+        assert_eq!(c8.pc(), 0x001);
+        assert_eq!(c8.call_stack(), [CallFrame { call_site: 0, return_address: 0 }]);
+    }
+
+    #[test]
+    fn return_with_empty_stack() {
+        assert_eq!(try_step(0x00EE, |_| {}).unwrap_err(), Chip8Error::StackUnderflow { pc: 0x200 });
+    }
+
+    #[test]
+    fn fetch_past_end_of_memory() {
+        let mut c8 = Chip8::new();
+        c8.set_pc(0xFFF);
+        assert_eq!(c8.interpret().unwrap_err(), Chip8Error::MemoryOutOfBounds { pc: 0xFFF, address: 0x1000 });
+
+        let mut c8 = step(0xBFFF, |c8| c8.set_v_reg(0, 0xFF));
+        assert!(matches!(c8.interpret(), Err(Chip8Error::MemoryOutOfBounds { .. })));
+    }
+
+    #[test]
+    fn register_transfer_past_end_of_memory() {
+        let result = try_step(0xFF55, |c8| c8.set_i_reg(0xFF8));
+        assert_eq!(result.unwrap_err(), Chip8Error::MemoryOutOfBounds { pc: 0x200, address: 0x1000 });
+
+        let result = try_step(0xFF65, |c8| c8.set_i_reg(0xFFF0));
+        assert!(matches!(result, Err(Chip8Error::MemoryOutOfBounds { .. })));
+    }
+
+    #[test]
+    fn bcd_past_end_of_memory() {
+        let result = try_step(0xF033, |c8| c8.set_i_reg(0xFFE));
+        assert_eq!(result.unwrap_err(), Chip8Error::MemoryOutOfBounds { pc: 0x200, address: 0x1000 });
+    }
+
+    #[test]
+    fn sprite_past_end_of_memory() {
+        let result = try_step(0xD00F, |c8| c8.set_i_reg(0xFFFF));
+        assert!(matches!(result, Err(Chip8Error::MemoryOutOfBounds { .. })));
+    }
+
+    #[test]
+    fn add_to_i_wraps() {
+        assert_eq!(step(0xF01E, |c8| { c8.set_i_reg(0xFFFF); c8.set_v_reg(0, 2) }).i_reg(), 1);
+    }
+
+    #[test]
+    fn key_above_f() {
+        assert_eq!(try_step(0xE09E, |c8| c8.set_v_reg(0, 0x10)).unwrap_err(), Chip8Error::InvalidKey { pc: 0x200, key: 0x10 });
+        assert_eq!(try_step(0xE0A1, |c8| c8.set_v_reg(0, 0xFF)).unwrap_err(), Chip8Error::InvalidKey { pc: 0x200, key: 0xFF });
+    }
+
+    // With VF as an operand the flag is written last and wins.
+    #[test]
+    fn subtract_into_vf() {
+        let c8 = step(0x8F05, |c8| { c8.set_v_reg(0xF, 0x80); c8.set_v_reg(0, 0x40) });
+        assert_eq!((c8.v_reg()[0], c8.v_reg()[0xF]), (0x40, 1));
+        let c8 = step(0x80F7, |c8| { c8.set_v_reg(0xF, 0x30); c8.set_v_reg(0, 0x40) });
+        assert_eq!((c8.v_reg()[0], c8.v_reg()[0xF]), (0xF0, 0));
+    }
+
+    #[test]
+    fn rom_too_large() {
+        let mut c8 = Chip8::new();
+        assert!(c8.load_bytes(&[0; 0xE00]).is_ok());
+        assert_eq!(c8.load_bytes(&[0; 0xE01]).unwrap_err(), Chip8Error::RomTooLarge { size: 0xE01, max: 0xE00 });
+    }
+
+    type AluCase = (u16, u8, u8, [(u8, u8); 3]);
+
+    // Worked out by hand from how the VIP, SCHIP 1.1 and Octo behave: the
+    // opcode, Vx and Vy, then Vx and VF afterwards under CHIP8, SCHIP and
+    // XO_CHIP. VF starts at 0x55 unless it is an operand, so a flag that is
+    // left alone shows.
+    const ALU_CASES: &[AluCase] = &[
+        (0x8120, 0x0C, 0x0A, [(0x0A, 0x55), (0x0A, 0x55), (0x0A, 0x55)]),
+        (0x8121, 0x0C, 0x0A, [(0x0E, 0x00), (0x0E, 0x55), (0x0E, 0x55)]),
+        (0x8122, 0x0C, 0x0A, [(0x08, 0x00), (0x08, 0x55), (0x08, 0x55)]),
+        (0x8123, 0x0C, 0x0A, [(0x06, 0x00), (0x06, 0x55), (0x06, 0x55)]),
+        (0x8124, 0xC8, 0x64, [(0x2C, 0x01), (0x2C, 0x01), (0x2C, 0x01)]),
+        (0x8124, 0x64, 0x64, [(0xC8, 0x00), (0xC8, 0x00), (0xC8, 0x00)]),
+        (0x8125, 0x64, 0x1E, [(0x46, 0x01), (0x46, 0x01), (0x46, 0x01)]),
+        (0x8125, 0x1E, 0x64, [(0xBA, 0x00), (0xBA, 0x00), (0xBA, 0x00)]),
+        (0x8125, 0x32, 0x32, [(0x00, 0x01), (0x00, 0x01), (0x00, 0x01)]),
+        (0x8126, 0x0C, 0x0B, [(0x05, 0x01), (0x06, 0x00), (0x05, 0x01)]),
+        (0x8127, 0x1E, 0x64, [(0x46, 0x01), (0x46, 0x01), (0x46, 0x01)]),
+        (0x8127, 0x64, 0x1E, [(0xBA, 0x00), (0xBA, 0x00), (0xBA, 0x00)]),
+        (0x8127, 0x32, 0x32, [(0x00, 0x01), (0x00, 0x01), (0x00, 0x01)]),
+        (0x812E, 0x41, 0xC3, [(0x86, 0x01), (0x82, 0x00), (0x86, 0x01)]),
+        (0x812E, 0x41, 0xC1, [(0x82, 0x01), (0x82, 0x00), (0x82, 0x01)]),
+        // VF as Vx: the flag is written last, so it replaces the result.
+        (0x8F21, 0x0C, 0x0A, [(0x00, 0x00), (0x0E, 0x0E), (0x0E, 0x0E)]),
+        (0x8F24, 0xC8, 0x64, [(0x01, 0x01), (0x01, 0x01), (0x01, 0x01)]),
+        (0x8F24, 0x10, 0x20, [(0x00, 0x00), (0x00, 0x00), (0x00, 0x00)]),
+        (0x8F25, 0x64, 0x1E, [(0x01, 0x01), (0x01, 0x01), (0x01, 0x01)]),
+        (0x8F26, 0x0C, 0x0B, [(0x01, 0x01), (0x00, 0x00), (0x01, 0x01)]),
+        (0x8F27, 0x1E, 0x64, [(0x01, 0x01), (0x01, 0x01), (0x01, 0x01)]),
+        (0x8F2E, 0x0C, 0xC1, [(0x01, 0x01), (0x00, 0x00), (0x01, 0x01)]),
+        (0x8FF5, 0x64, 0x64, [(0x01, 0x01), (0x01, 0x01), (0x01, 0x01)]),
+        // VF as Vy.
+        (0x81F4, 0xC8, 0x64, [(0x2C, 0x01), (0x2C, 0x01), (0x2C, 0x01)]),
+        (0x81F5, 0x1E, 0x64, [(0xBA, 0x00), (0xBA, 0x00), (0xBA, 0x00)]),
+        (0x81F7, 0x64, 0x1E, [(0xBA, 0x00), (0xBA, 0x00), (0xBA, 0x00)]),
+        (0x81FE, 0x01, 0x81, [(0x02, 0x01), (0x02, 0x00), (0x02, 0x01)]),
+    ];
+
+    const GENERAL: &[(usize, usize)] = &[(0x1, 0x2), (0x3, 0x3)];
+    const VF_OPERAND: &[(usize, usize)] = &[(0xF, 0x2), (0x1, 0xF), (0xF, 0xF)];
+
+    // Runs `8xyN` for every Vx and Vy under every quirks preset and compares
+    // the whole register file with `alu::tests::reference`, the result going
+    // to Vx before the flag goes to VF.
+    fn check_alu(op: u8, registers: &[(usize, usize)]) {
+        for (name, quirks) in Quirks::PRESETS.iter() {
+            let mut c8 = Chip8::with_quirks(*quirks);
+
+            for &(x, y) in registers {
+                let opcode = 0x8000 | (x as u16) << 8 | (y as u16) << 4 | op as u16;
+                c8.write_memory(0x200, &opcode.to_be_bytes());
+
+                for vx in 0..=255 {
+                    for vy in 0..=if x == y { 0 } else { 255 } {
+                        let mut v_reg = [0u8; 16];
+                        for (i, v) in v_reg.iter_mut().enumerate() {
+                            *v = (i as u8).wrapping_mul(0x11);
+                        }
+                        v_reg[x] = vx;
+                        if x != y {
+                            v_reg[y] = vy;
+                        }
+                        for (i, &v) in v_reg.iter().enumerate() {
+                            c8.set_v_reg(i, v);
+                        }
+                        c8.set_pc(0x200);
+                        c8.interpret().unwrap();
+
+                        let (value, flag) = alu::tests::reference(name, op, v_reg[x], v_reg[y]).unwrap();
+                        v_reg[x] = value;
+                        if let Some(flag) = flag {
+                            v_reg[0xF] = flag;
+                        }
+                        assert_eq!(c8.v_reg(), &v_reg, "{:04X} with Vx={:02X} Vy={:02X} under {}", opcode, vx, vy, name);
+                        assert_eq!(c8.pc(), 0x202);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn alu_8xy0() {
+        check_alu(0x0, GENERAL);
+        check_alu(0x0, VF_OPERAND);
+    }
+
+    #[test]
+    fn alu_8xy1() {
+        check_alu(0x1, GENERAL);
+        check_alu(0x1, VF_OPERAND);
+    }
+
+    #[test]
+    fn alu_8xy2() {
+        check_alu(0x2, GENERAL);
+        check_alu(0x2, VF_OPERAND);
+    }
+
+    #[test]
+    fn alu_8xy3() {
+        check_alu(0x3, GENERAL);
+        check_alu(0x3, VF_OPERAND);
+    }
+
+    #[test]
+    fn alu_8xy4() {
+        check_alu(0x4, GENERAL);
+        check_alu(0x4, VF_OPERAND);
+    }
+
+    #[test]
+    fn alu_8xy5() {
+        check_alu(0x5, GENERAL);
+        check_alu(0x5, VF_OPERAND);
+    }
+
+    #[test]
+    fn alu_8xy6() {
+        check_alu(0x6, GENERAL);
+        check_alu(0x6, VF_OPERAND);
+    }
+
+    #[test]
+    fn alu_8xy7() {
+        check_alu(0x7, GENERAL);
+        check_alu(0x7, VF_OPERAND);
+    }
+
+    #[test]
+    fn alu_8xye() {
+        check_alu(0xE, GENERAL);
+        check_alu(0xE, VF_OPERAND);
+    }
+
+    #[test]
+    fn alu_matches_reference_tables() {
+        for &(opcode, vx, vy, expected) in ALU_CASES {
+            let x = ((opcode & 0x0F00) >> 8) as usize;
+            let y = ((opcode & 0x00F0) >> 4) as usize;
+
+            for ((name, quirks), &(result, flag)) in Quirks::PRESETS.iter().zip(expected.iter()) {
+                let mut c8 = Chip8::with_quirks(*quirks);
+                c8.write_memory(0x200, &opcode.to_be_bytes());
+                c8.set_v_reg(0xF, 0x55);
+                c8.set_v_reg(x, vx);
+                if y != x {
+                    c8.set_v_reg(y, vy);
+                }
+                c8.interpret().unwrap();
+                assert_eq!((c8.v_reg()[x], c8.v_reg()[0xF]), (result, flag),
+                    "{:04X} with Vx={:02X} Vy={:02X} under {}", opcode, vx, vy, name);
+                assert_eq!(c8.pc(), 0x202);
+            }
+        }
+    }
 }
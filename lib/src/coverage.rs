@@ -0,0 +1,175 @@
+use crate::display::{Frame, Rgba};
+
+// What each byte of memory was used for: run as code, drawn as sprite data,
+// or loaded and stored as variables (Fx33/Fx55/Fx65). Good for telling code
+// from data in a ROM nobody documented, and for seeing what tests exercise.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    Execute,
+    Sprite,
+    Read,
+    Write,
+}
+
+impl Access {
+    pub const ALL: [Access; 4] = [Access::Execute, Access::Sprite, Access::Read, Access::Write];
+
+    fn bit(self) -> u8 {
+        1 << self as u8
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Access::Execute => "executed",
+            Access::Sprite => "sprite",
+            Access::Read => "read",
+            Access::Write => "written",
+        }
+    }
+}
+
+// Marks bytes an instruction was fetched from, rather than its second byte.
+const INSTRUCTION_START: u8 = 0x80;
+
+// Bytes per heatmap row.
+const HEATMAP_WIDTH: usize = 64;
+const UNTOUCHED: Rgba = [24, 24, 24, 255];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Coverage {
+    // One bit per `Access` for each byte.
+    kinds: Vec<u8>,
+    counts: Vec<u32>,
+}
+
+impl Coverage {
+    pub fn new(size: usize) -> Self {
+        Coverage { kinds: vec![0; size], counts: vec![0; size] }
+    }
+
+    // Marks `len` bytes from `address`; anything past the end of memory is
+    // left to the access itself to fail on.
+    pub fn record(&mut self, address: usize, len: usize, access: Access) {
+        let end = (address + len).min(self.kinds.len());
+        for i in address.min(end)..end {
+            self.kinds[i] |= access.bit();
+            self.counts[i] = self.counts[i].saturating_add(1);
+        }
+        if access == Access::Execute && address < end {
+            self.kinds[address] |= INSTRUCTION_START;
+        }
+    }
+
+    // Whether an instruction was run from `address` itself.
+    pub fn instruction_start(&self, address: usize) -> bool {
+        self.kinds[address] & INSTRUCTION_START != 0
+    }
+
+    pub fn len(&self) -> usize {
+        self.kinds.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.kinds.is_empty()
+    }
+
+    pub fn touched(&self, address: usize, access: Access) -> bool {
+        self.kinds[address] & access.bit() != 0
+    }
+
+    // Accesses of any kind.
+    pub fn count(&self, address: usize) -> u32 {
+        self.counts[address]
+    }
+
+    fn accesses(&self, address: usize) -> impl Iterator<Item = Access> + '_ {
+        Access::ALL.iter().copied().filter(move |&access| self.touched(address, access))
+    }
+
+    // Totals per kind and for untouched bytes, then every touched byte.
+    pub fn to_json(&self) -> String {
+        let mut out = format!("{{\n  \"size\": {},\n  \"summary\": {{", self.len());
+        for access in Access::ALL.iter() {
+            let total = (0..self.len()).filter(|&address| self.touched(address, *access)).count();
+            out += &format!("\"{}\": {}, ", access.name(), total);
+        }
+        out += &format!("\"untouched\": {}}},\n  \"bytes\": [", self.kinds.iter().filter(|&&kinds| kinds == 0).count());
+
+        let touched: Vec<String> = (0..self.len()).filter(|&address| self.kinds[address] != 0).map(|address| {
+            let accesses: Vec<String> = self.accesses(address).map(|access| format!("\"{}\"", access.name())).collect();
+            format!("\n    {{\"address\": {}, \"access\": [{}], \"count\": {}}}", address, accesses.join(", "), self.counts[address])
+        }).collect();
+        out += &touched.join(",");
+        out += "\n  ]\n}\n";
+        out
+    }
+
+    // A row for every byte, untouched ones included.
+    pub fn to_csv(&self) -> String {
+        let mut out = String::from("address,executed,sprite,read,written,count\n");
+        for address in 0..self.len() {
+            out += &format!("{:03X}", address);
+            for access in Access::ALL.iter() {
+                out += if self.touched(address, *access) { ",1" } else { ",0" };
+            }
+            out += &format!(",{}\n", self.counts[address]);
+        }
+        out
+    }
+
+    // One pixel per byte, 64 to a row: red for code, green for sprites, blue
+    // for variables, mixed where a byte was several, brighter the more it
+    // was used.
+    pub fn heatmap(&self) -> Frame {
+        let max = self.counts.iter().copied().max().unwrap_or(0).max(1) as f64;
+        let mut pixels: Vec<Rgba> = (0..self.len()).map(|address| {
+            if self.kinds[address] == 0 {
+                return UNTOUCHED;
+            }
+            let heat = (self.counts[address] as f64).ln_1p() / max.ln_1p();
+            let level = (96.0 + 159.0 * heat) as u8;
+            let on = |lit: bool| if lit { level } else { 0 };
+            [
+                on(self.touched(address, Access::Execute)),
+                on(self.touched(address, Access::Sprite)),
+                on(self.touched(address, Access::Read) || self.touched(address, Access::Write)),
+                255,
+            ]
+        }).collect();
+        pixels.resize(self.len().div_ceil(HEATMAP_WIDTH) * HEATMAP_WIDTH, UNTOUCHED);
+        Frame { width: HEATMAP_WIDTH, height: pixels.len() / HEATMAP_WIDTH, pixels }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_and_exports() {
+        let mut coverage = Coverage::new(128);
+        coverage.record(0x10, 2, Access::Execute);
+        coverage.record(0x10, 2, Access::Execute);
+        coverage.record(0x11, 3, Access::Sprite);
+        coverage.record(126, 4, Access::Write);
+
+        assert!(coverage.touched(0x11, Access::Execute) && coverage.touched(0x11, Access::Sprite));
+        assert_eq!(coverage.count(0x11), 3);
+        assert!(coverage.touched(127, Access::Write));
+        assert!(coverage.instruction_start(0x10) && !coverage.instruction_start(0x11));
+
+        let json = coverage.to_json();
+        assert!(json.contains("\"summary\": {\"executed\": 2, \"sprite\": 3, \"read\": 0, \"written\": 2, \"untouched\": 122}"));
+        assert!(json.contains("{\"address\": 17, \"access\": [\"executed\", \"sprite\"], \"count\": 3}"));
+        let csv = coverage.to_csv();
+        assert_eq!(csv.lines().count(), 129);
+        assert_eq!(csv.lines().nth(0x12), Some("011,1,1,0,0,3"));
+
+        let heatmap = coverage.heatmap();
+        assert_eq!((heatmap.width, heatmap.height), (64, 2));
+        assert_eq!(heatmap.pixel(0x11, 0), [255, 255, 0, 255]);
+        assert_eq!(heatmap.pixel(0, 0), UNTOUCHED);
+        assert_eq!(heatmap.pixel(63, 1)[..3], [0, 0, 175]);
+    }
+}
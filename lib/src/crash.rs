@@ -0,0 +1,227 @@
+use std::collections::VecDeque;
+use crate::chip8::Chip8Error;
+use crate::harness;
+use crate::machine::Machine;
+use crate::quirks::Quirks;
+use crate::rom;
+
+// Crash reports: everything needed to understand why a program stopped,
+// plus a save state of the moment it did so it can be reopened and poked at.
+
+pub const HISTORY_LEN: usize = 64;
+
+// Instructions shown either side of the faulting one.
+const CONTEXT: usize = 8;
+
+const STATE_HEADER: &str = "== Save state ==";
+const STATE_LINE_BYTES: usize = 32;
+
+// The last `HISTORY_LEN` instructions run, as (address, opcode).
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct History {
+    entries: VecDeque<(usize, u16)>,
+}
+
+impl History {
+    pub fn record(&mut self, address: usize, opcode: u16) {
+        if self.entries.len() == HISTORY_LEN {
+            self.entries.pop_front();
+        }
+        self.entries.push_back((address, opcode));
+    }
+
+    // Oldest first.
+    pub fn entries(&self) -> impl Iterator<Item = &(usize, u16)> {
+        self.entries.iter()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CrashReport {
+    pub error: Chip8Error,
+    pub rom_name: String,
+    pub rom_hash: String,
+    pub quirks: Option<Quirks>,
+    pub listing: Vec<String>,
+    pub registers: Vec<String>,
+    // Newest first.
+    pub call_stack: Vec<String>,
+    pub history: Vec<String>,
+    pub screen: String,
+    pub state: Option<Vec<u8>>,
+}
+
+impl CrashReport {
+    pub fn new(error: Chip8Error, rom_name: &str, rom: &[u8], machine: &dyn Machine) -> Self {
+        CrashReport {
+            error,
+            rom_name: String::from(rom_name),
+            rom_hash: rom::hash(rom),
+            quirks: machine.quirks(),
+            listing: machine.listing(CONTEXT, CONTEXT),
+            registers: machine.register_lines(),
+            call_stack: machine.call_stack().iter().rev()
+                .map(|call| format!("RET {:03X} AT {:03X}", call.return_address, call.call_site))
+                .collect(),
+            history: machine.history(),
+            screen: to_ascii(machine),
+            state: machine.save_state(),
+        }
+    }
+
+    // The listing line of the faulting instruction.
+    pub fn faulting_line(&self) -> Option<&String> {
+        let pc = self.error.pc()? as usize;
+        self.listing.iter().find(|line| line_address(line) == Some(pc))
+    }
+
+    // A few lines for the terminal.
+    pub fn summary(&self) -> String {
+        let mut out = format!("{}\n", self.error);
+        if let Some(line) = self.faulting_line() {
+            out += &format!("  {}\n", line);
+        }
+        out += &format!("ROM {} ({})\n", self.rom_name, self.rom_hash);
+        out
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::from("rc8emu crash report\n\n");
+        out += &format!("Error:  {}\n", self.error);
+        out += &format!("ROM:    {}\n", self.rom_name);
+        out += &format!("SHA-1:  {}\n", self.rom_hash);
+        out += &format!("Quirks: {}\n", self.quirks.map_or_else(|| String::from("n/a"), describe_quirks));
+        if let Some(pc) = self.error.pc() {
+            out += &format!("PC:     {:03X}\n", pc);
+        }
+        if let Some(line) = self.faulting_line() {
+            out += &format!("Opcode: {}\n", line);
+        }
+
+        let pc = self.error.pc().map(usize::from);
+        out += "\n== Disassembly ==\n";
+        for line in &self.listing {
+            let marker = if line_address(line) == pc && pc.is_some() { "> " } else { "  " };
+            out += &format!("{}{}\n", marker, line);
+        }
+        section(&mut out, "Registers", &self.registers);
+        section(&mut out, "Call stack", &self.call_stack);
+        section(&mut out, &format!("Last {} instructions", HISTORY_LEN), &self.history);
+        out += "\n== Screen ==\n";
+        out += &self.screen;
+
+        out += &format!("\n{}\n", STATE_HEADER);
+        match &self.state {
+            Some(state) => {
+                for chunk in state.chunks(STATE_LINE_BYTES) {
+                    out.extend(chunk.iter().map(|byte| format!("{:02x}", byte)));
+                    out.push('\n');
+                }
+            },
+            None => out += "(not supported on this machine)\n",
+        }
+        out
+    }
+}
+
+// The save state embedded in a rendered report.
+pub fn extract_state(report: &str) -> Option<Vec<u8>> {
+    let mut lines = report.lines().skip_while(|line| *line != STATE_HEADER).skip(1);
+    let mut state = Vec::new();
+    for line in lines.by_ref().take_while(|line| !line.is_empty() && !line.starts_with("==")) {
+        if line.len() % 2 != 0 {
+            return None;
+        }
+        for i in (0..line.len()).step_by(2) {
+            state.push(u8::from_str_radix(line.get(i..i + 2)?, 16).ok()?);
+        }
+    }
+    if state.is_empty() { None } else { Some(state) }
+}
+
+// The screen as `harness::to_ascii` draws it.
+pub fn to_ascii(machine: &dyn Machine) -> String {
+    let (screen, width) = machine.lit_pixels();
+    harness::to_ascii(&screen, width)
+}
+
+fn describe_quirks(quirks: Quirks) -> String {
+    match Quirks::PRESETS.iter().find(|(_, preset)| *preset == quirks) {
+        Some((name, _)) => String::from(*name),
+        None => format!("{:?}", quirks),
+    }
+}
+
+fn section(out: &mut String, title: &str, lines: &[String]) {
+    *out += &format!("\n== {} ==\n", title);
+    if lines.is_empty() {
+        *out += "(empty)\n";
+    }
+    for line in lines {
+        *out += &format!("{}\n", line);
+    }
+}
+
+// Listing lines start with the address in hex, "204: ...".
+fn line_address(line: &str) -> Option<usize> {
+    usize::from_str_radix(line.split(':').next()?, 16).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip8::{Chip8, Variant};
+    use crate::megachip::MegaChip;
+
+    #[test]
+    fn report_for_invalid_opcode() {
+        let mut c8 = Chip8::new();
+        let rom = [0x60, 0x2A, 0x22, 0x06, 0x00, 0x00, 0xA2, 0x1E, 0xFF, 0xFF];
+        c8.load_bytes(&rom).unwrap();
+        let error = (0..10).find_map(|_| c8.interpret().err()).unwrap();
+        assert_eq!(error, Chip8Error::InvalidOpcode { pc: 0x208, opcode: 0xFFFF });
+
+        let report = CrashReport::new(error, "test.ch8", &rom, &c8);
+        assert_eq!(report.faulting_line().unwrap(), "208: FFFF  DW FFFF");
+        assert_eq!(report.call_stack, ["RET 204 AT 202"]);
+        assert_eq!(report.history, ["200: 602A  LD V0, 2A", "202: 2206  CALL 206", "206: A21E  LD I, 21E", "208: FFFF  DW FFFF"]);
+
+        let text = report.render();
+        assert!(text.contains("Quirks: chip8\n"));
+        assert!(text.contains("> 208: FFFF  DW FFFF\n"));
+        assert!(text.contains("V0 2A V8 00\n"));
+        assert!(report.summary().starts_with("Invalid opcode: 0xffff at 0x208\n  208: FFFF  DW FFFF\n"));
+
+        let mut restored = Chip8::new();
+        restored.load_state(&extract_state(&text).unwrap()).unwrap();
+        assert_eq!(restored.save_state(), c8.save_state());
+        assert_eq!(restored.pc(), 0x208);
+        assert_eq!(restored.i_reg(), 0x21E);
+    }
+
+    #[test]
+    fn screen_of_colour_machines() {
+        let mut c8 = Chip8::new();
+        c8.set_variant(Variant::Chip8X);
+        c8.gfx[1] = 1;
+        let screen = to_ascii(&c8);
+        assert!(screen.starts_with(".#..."));
+        assert_eq!(screen.matches('#').count(), 1);
+
+        let mut mega = MegaChip::new();
+        mega.write_memory(0x200, &[0x00, 0x10]);
+        mega.interpret().unwrap();
+        let screen = to_ascii(&mega);
+        assert_eq!(screen.lines().count(), 192);
+        assert!(!screen.contains('#'));
+    }
+
+    #[test]
+    fn history_keeps_the_newest() {
+        let mut history = History::default();
+        (0..100).for_each(|i| history.record(i, 0));
+        assert_eq!(history.entries().count(), HISTORY_LEN);
+        assert_eq!(history.entries().next(), Some(&(100 - HISTORY_LEN, 0)));
+        assert_eq!(extract_state("== Save state ==\nzz\n"), None);
+    }
+}
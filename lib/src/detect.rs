@@ -0,0 +1,254 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use crate::analysis::{self, Platform, SpriteTable};
+use crate::chip8::{Chip8Error, Variant};
+use crate::quirks::Quirks;
+use crate::rom;
+
+// Working out what a ROM was written for, since file extensions can't be
+// trusted. A ROM database entry settles it; otherwise the code reachable
+// from the start is checked for instructions only one platform has, then
+// the rest of the file, sprites aside, is scanned for the tell-tale opcodes
+// of the two common extensions, and anything bigger than CHIP-8 memory must
+// be XO-CHIP. It is only ever a suggestion.
+
+// What fits between the program start and the end of 4K of memory.
+const CHIP8_ROM_LIMIT: usize = 4096 - 0x200;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DatabaseEntry {
+    pub platform: Platform,
+    pub title: String,
+}
+
+// Known ROMs by SHA-1. One per line, "<sha1> <platform> <title>", with
+// blank lines and lines starting with '#' ignored. Platforms go by our
+// names or the community CHIP-8 database's, so its sha1-hashes.json and
+// programs.json can be turned into this with a few lines of script.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RomDatabase {
+    entries: HashMap<String, DatabaseEntry>,
+}
+
+impl RomDatabase {
+    pub fn parse(text: &str) -> Result<Self, Chip8Error> {
+        let mut entries = HashMap::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = || Chip8Error::Io(format!("Invalid ROM database line {}: {}", number + 1, line));
+            let mut fields = line.splitn(3, char::is_whitespace);
+            let hash = fields.next().filter(|hash| hash.len() == 40).ok_or_else(invalid)?;
+            let platform = fields.next().and_then(platform_from_name).ok_or_else(invalid)?;
+            let title = String::from(fields.next().unwrap_or("").trim());
+            entries.insert(hash.to_ascii_lowercase(), DatabaseEntry { platform, title });
+        }
+        Ok(RomDatabase { entries })
+    }
+
+    pub fn load(path: &str) -> Result<Self, Chip8Error> {
+        let text = fs::read_to_string(path).map_err(|e| Chip8Error::Io(format!("Error opening ROM database {}: {}", path, e)))?;
+        RomDatabase::parse(&text)
+    }
+
+    pub fn lookup(&self, rom: &[u8]) -> Option<&DatabaseEntry> {
+        self.entries.get(&rom::hash(rom))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+fn platform_from_name(name: &str) -> Option<Platform> {
+    Platform::from_name(name).or(match name {
+        "originalChip8" | "hybridVIP" | "modernChip8" => Some(Platform::Chip8),
+        "chip48" | "superchip1" | "superchip" => Some(Platform::Schip),
+        "megachip8" => Some(Platform::MegaChip),
+        _ => None,
+    })
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Detection {
+    pub platform: Platform,
+    // The database's name for the ROM, when it knew it.
+    pub title: Option<String>,
+    // What the guess rests on, for the user to judge.
+    pub reasons: Vec<String>,
+}
+
+impl Detection {
+    // MegaChip is the machine that runs SCHIP's instructions, and does so
+    // until a program switches its own mode on. None of them runs XO-CHIP.
+    pub fn machine(&self) -> Option<&'static str> {
+        match self.platform {
+            Platform::Schip | Platform::MegaChip => Some("megachip"),
+            Platform::XoChip => None,
+            _ => Some("chip8"),
+        }
+    }
+
+    pub fn variant(&self) -> Variant {
+        match self.platform {
+            Platform::Chip8X => Variant::Chip8X,
+            Platform::Chip8E => Variant::Chip8E,
+            _ => Variant::Chip8,
+        }
+    }
+
+    pub fn quirks_name(&self) -> &'static str {
+        match self.platform {
+            Platform::Schip | Platform::MegaChip => "schip",
+            Platform::XoChip => "xochip",
+            _ => "chip8",
+        }
+    }
+
+    pub fn quirks(&self) -> Quirks {
+        Quirks::from_name(self.quirks_name()).unwrap()
+    }
+
+    // The options that would pick the same setup by hand, when a machine
+    // here can run it.
+    pub fn arguments(&self) -> Option<String> {
+        let machine = self.machine()?;
+        Some(format!("--machine {} --variant {} --quirks {}", machine, variant_name(self.variant()), self.quirks_name()))
+    }
+
+    pub fn report(&self) -> String {
+        let mut out = format!("Platform: {}", self.platform.name());
+        if let Some(title) = &self.title {
+            out += &format!(" ({})", title);
+        }
+        match self.arguments() {
+            Some(arguments) => out += &format!("\nSuggested: {}\n", arguments),
+            None => out += "\nNot supported by any machine here\n",
+        }
+        for reason in &self.reasons {
+            out += &format!("  {}\n", reason);
+        }
+        out
+    }
+}
+
+fn variant_name(variant: Variant) -> &'static str {
+    match variant {
+        Variant::Chip8 => "chip8",
+        Variant::Chip8X => "chip8x",
+        Variant::Chip8E => "chip8e",
+    }
+}
+
+pub fn detect(rom: &[u8], database: Option<&RomDatabase>) -> Detection {
+    if let Some(entry) = database.and_then(|database| database.lookup(rom)) {
+        return Detection {
+            platform: entry.platform,
+            title: Some(entry.title.clone()).filter(|title| !title.is_empty()),
+            reasons: vec![format!("ROM database entry for {}", rom::hash(rom))],
+        };
+    }
+
+    let mut reasons = Vec::new();
+    let analysis = analysis::analyze(rom, Variant::Chip8);
+    for (platform, addresses) in &analysis.platform_ops {
+        let addresses: Vec<String> = addresses.iter().map(|address| format!("{:03X}", address)).collect();
+        reasons.push(format!("{} instructions reached at {}", platform.name(), addresses.join(" ")));
+    }
+    let found = signatures(rom, &analysis.sprites);
+    for (platform, addresses) in &found {
+        let addresses: Vec<String> = addresses.iter().map(|address| format!("{:03X}", address)).collect();
+        reasons.push(format!("{} opcodes in the file at {}", platform.name(), addresses.join(" ")));
+    }
+    let too_big = rom.len() > CHIP8_ROM_LIMIT;
+    if too_big {
+        reasons.push(format!("{} bytes, more than CHIP-8 memory holds", rom.len()));
+    }
+
+    // XO-CHIP keeps everything SCHIP added, and the trace stops at its
+    // instructions, so its signs outrank a reached SCHIP one.
+    let reached = analysis.guess_platform();
+    let platform = match reached {
+        Platform::Chip8X | Platform::Chip8E | Platform::MegaChip => reached,
+        _ if too_big || found.contains_key(&Platform::XoChip) => Platform::XoChip,
+        Platform::Schip | Platform::XoChip => reached,
+        Platform::Chip8 if found.contains_key(&Platform::Schip) => Platform::Schip,
+        Platform::Chip8 => Platform::Chip8,
+    };
+    if reasons.is_empty() {
+        reasons.push(String::from("nothing beyond plain CHIP-8 found"));
+    }
+    Detection { platform, title: None, reasons }
+}
+
+// Opcodes only SCHIP (00FF, 00Cn) or XO-CHIP (F000 nnnn, Fn01, 5xy2/5xy3)
+// use, anywhere in the file outside the sprites the trace found. A blank
+// sprite row followed by a full one reads as 00FF, and sprites the trace
+// missed still can, which is why reached code is looked at first.
+fn signatures(rom: &[u8], sprites: &[SpriteTable]) -> BTreeMap<Platform, Vec<u16>> {
+    let start = Variant::Chip8.program_start();
+    let in_sprite = |address: usize| sprites.iter()
+        .any(|sprite| (sprite.address as usize..(sprite.address + sprite.len) as usize).contains(&address));
+
+    let mut found: BTreeMap<Platform, Vec<u16>> = BTreeMap::new();
+    for (i, word) in rom.chunks_exact(2).enumerate() {
+        let address = start + 2 * i;
+        if in_sprite(address) || in_sprite(address + 1) {
+            continue;
+        }
+        let opcode = u16::from_be_bytes([word[0], word[1]]);
+        let platform = match opcode {
+            0x00FF | 0x00C0..=0x00CF => Platform::Schip,
+            0xF000 => Platform::XoChip,
+            _ if opcode & 0xF0FF == 0xF001 || opcode & 0xF00E == 0x5002 => Platform::XoChip,
+            _ => continue,
+        };
+        found.entry(platform).or_default().push(address as u16);
+    }
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_from_code_size_and_database() {
+        // 00FF switches to SCHIP's high resolution.
+        let schip = [0x00, 0xFF, 0x12, 0x02];
+        let detection = detect(&schip, None);
+        assert_eq!(detection.platform, Platform::Schip);
+        assert_eq!(detection.arguments().unwrap(), "--machine megachip --variant chip8 --quirks schip");
+        assert_eq!(detection.reasons, ["schip instructions reached at 200", "schip opcodes in the file at 200"]);
+
+        // Fn01 picks XO-CHIP drawing planes; the trace can't follow it.
+        let xo = [0x00, 0xFF, 0xF3, 0x01, 0x12, 0x04];
+        assert_eq!(detect(&xo, None).platform, Platform::XoChip);
+        let mut big = vec![0x12, 0x00];
+        big.resize(CHIP8_ROM_LIMIT + 2, 0);
+        // Too big for CHIP-8 memory, so no machine here is suggested for it.
+        let detection = detect(&big, None);
+        assert_eq!((detection.platform, detection.machine(), detection.arguments()), (Platform::XoChip, None, None));
+        assert!(detection.report().starts_with("Platform: xochip\nNot supported by any machine here\n"));
+        assert_eq!(detect(&[0x12, 0x00], None).report(), "Platform: chip8\n\
+            Suggested: --machine chip8 --variant chip8 --quirks chip8\n  nothing beyond plain CHIP-8 found\n");
+
+        // 00 FF here is a sprite's blank row and full row.
+        let sprite = [0xA2, 0x06, 0xD0, 0x14, 0x12, 0x04, 0x00, 0xFF, 0x81, 0xC1];
+        assert_eq!(detect(&sprite, None).platform, Platform::Chip8);
+
+        let text = format!("# test\n\n{} megachip8 Some Game\n", rom::hash(&schip));
+        let database = RomDatabase::parse(&text).unwrap();
+        assert_eq!(database.len(), 1);
+        let detection = detect(&schip, Some(&database));
+        assert_eq!((detection.platform, detection.machine()), (Platform::MegaChip, Some("megachip")));
+        assert_eq!(detection.title.as_deref(), Some("Some Game"));
+        assert!(RomDatabase::parse("abc chip8").is_err());
+    }
+}
@@ -0,0 +1,21 @@
+use std::fmt;
+
+// Things a running program does that are allowed but worth knowing about.
+// Machines send them down an `mpsc` channel for the frontend, a log or a
+// test to drain, and carry on.
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Diagnostic {
+    // An instruction that already ran was overwritten, by the instruction
+    // at `writer`.
+    SelfModifyingCode { address: u16, writer: u16, old_opcode: u16, new_opcode: u16 },
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Diagnostic::SelfModifyingCode { address, writer, old_opcode, new_opcode } => write!(
+                f, "Code at {:03X} rewritten from {:04X} to {:04X} by {:03X}", address, old_opcode, new_opcode, writer),
+        }
+    }
+}
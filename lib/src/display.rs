@@ -0,0 +1,162 @@
+// Sprite drawing on a plain pixel buffer, one byte per pixel, kept free of
+// any machine state so every screen size and sprite format shares it, and the
+// RGBA frames machines hand to the frontends.
+
+pub type Rgba = [u8; 4];
+
+// Colours of monochrome machines: dark pixels on an LCD-green background.
+pub const BACKGROUND: Rgba = [156, 159, 76, 255];
+pub const FOREGROUND: Rgba = [57, 74, 30, 255];
+
+// A finished picture, row by row.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<Rgba>,
+}
+
+impl Frame {
+    // `screen` holds one byte per pixel, non-zero for lit.
+    pub fn monochrome(screen: &[u8], width: usize) -> Self {
+        let pixels = screen.iter().map(|&pixel| if pixel != 0 { FOREGROUND } else { BACKGROUND }).collect();
+        Frame { width, height: screen.len() / width, pixels }
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> Rgba {
+        self.pixels[y * self.width + x]
+    }
+
+    // Each pixel becomes a `factor` x `factor` block.
+    pub fn scaled(&self, factor: usize) -> Frame {
+        let pixels = (0..self.height * factor)
+            .flat_map(|y| (0..self.width * factor).map(move |x| (x / factor, y / factor)))
+            .map(|(x, y)| self.pixel(x, y))
+            .collect();
+        Frame { width: self.width * factor, height: self.height * factor, pixels }
+    }
+}
+
+// XORs `sprite` onto a `width` pixel wide `screen` with its top left corner
+// at (x, y). Rows are one byte each, or two bytes when `wide` (16x16 sprites).
+// The start coordinate always wraps around the screen; pixels running off the
+// edges wrap too when `wrap` is set and are clipped otherwise. Returns how
+// many sprite rows turned off at least one lit pixel.
+pub fn draw_sprite(screen: &mut [u8], width: usize, x: usize, y: usize, sprite: &[u8], wide: bool, wrap: bool) -> usize {
+    let height = screen.len() / width;
+    let x = x % width;
+    let y = y % height;
+    let bytes_per_row = if wide { 2 } else { 1 };
+
+    let mut collided_rows = 0;
+    for (row, bytes) in sprite.chunks_exact(bytes_per_row).enumerate() {
+        let bits = bytes.iter().fold(0u16, |bits, &byte| bits << 8 | byte as u16);
+        let mut py = y + row;
+        if py >= height {
+            if !wrap {
+                break;
+            }
+            py %= height;
+        }
+
+        let mut collided = false;
+        for col in 0..bytes_per_row * 8 {
+            if bits & (1 << (bytes_per_row * 8 - 1 - col)) == 0 {
+                continue;
+            }
+            let mut px = x + col;
+            if px >= width {
+                if !wrap {
+                    break;
+                }
+                px %= width;
+            }
+
+            let pixel = &mut screen[py * width + px];
+            collided |= *pixel == 1;
+            *pixel ^= 1;
+        }
+
+        if collided {
+            collided_rows += 1;
+        }
+    }
+
+    collided_rows
+}
+
+// Moves everything on a `width` pixel wide `screen` right by `dx` and down
+// by `dy` (left and up when negative), filling what comes in with `blank`.
+pub fn scroll<T: Copy>(screen: &mut [T], width: usize, dx: isize, dy: isize, blank: T) {
+    let height = (screen.len() / width) as isize;
+    let source = screen.to_vec();
+    for y in 0..height {
+        for x in 0..width as isize {
+            let (sx, sy) = (x - dx, y - dy);
+            let inside = (0..width as isize).contains(&sx) && (0..height).contains(&sy);
+            screen[(y * width as isize + x) as usize] = if inside { source[(sy * width as isize + sx) as usize] } else { blank };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WIDTH: usize = 16;
+    const HEIGHT: usize = 8;
+
+    fn lit(screen: &[u8]) -> Vec<(usize, usize)> {
+        screen.iter().enumerate().filter(|(_, &p)| p != 0).map(|(i, _)| (i % WIDTH, i / WIDTH)).collect()
+    }
+
+    #[test]
+    fn start_coordinate_wraps() {
+        let mut screen = [0; WIDTH * HEIGHT];
+        draw_sprite(&mut screen, WIDTH, WIDTH + 2, HEIGHT * 3 + 1, &[0x80], false, false);
+        assert_eq!(lit(&screen), vec![(2, 1)]);
+    }
+
+    #[test]
+    fn clips_at_edges() {
+        let mut screen = [0; WIDTH * HEIGHT];
+        draw_sprite(&mut screen, WIDTH, WIDTH - 1, HEIGHT - 1, &[0xC0, 0xC0], false, false);
+        assert_eq!(lit(&screen), vec![(WIDTH - 1, HEIGHT - 1)]);
+    }
+
+    #[test]
+    fn wraps_at_edges() {
+        let mut screen = [0; WIDTH * HEIGHT];
+        draw_sprite(&mut screen, WIDTH, WIDTH - 1, HEIGHT - 1, &[0xC0, 0xC0], false, true);
+        assert_eq!(lit(&screen), vec![(0, 0), (WIDTH - 1, 0), (0, HEIGHT - 1), (WIDTH - 1, HEIGHT - 1)]);
+    }
+
+    #[test]
+    fn wide_sprite() {
+        let mut screen = [0; WIDTH * HEIGHT];
+        draw_sprite(&mut screen, WIDTH, 0, 0, &[0x80, 0x01, 0x00, 0x00, 0xFF, 0xFF], true, false);
+        assert_eq!(lit(&screen)[..2], [(0, 0), (15, 0)]);
+        assert_eq!(lit(&screen).len(), 2 + 16);
+        assert!(lit(&screen)[2..].iter().all(|&(_, y)| y == 2));
+    }
+
+    #[test]
+    fn counts_collided_rows() {
+        let mut screen = [0; WIDTH * HEIGHT];
+        let sprite = [0xFF, 0x00, 0x81, 0x18];
+        assert_eq!(draw_sprite(&mut screen, WIDTH, 0, 0, &sprite, false, false), 0);
+        assert_eq!(draw_sprite(&mut screen, WIDTH, 0, 0, &[0x01, 0xFF, 0x01, 0x08], false, false), 3);
+        assert_eq!(draw_sprite(&mut screen, WIDTH, 0, 0, &[0; 4], false, false), 0);
+    }
+
+    #[test]
+    fn scrolls_in_blank_pixels() {
+        let mut screen = [0; WIDTH * HEIGHT];
+        screen[0] = 1;
+        screen[WIDTH * HEIGHT - 1] = 1;
+        scroll(&mut screen, WIDTH, 4, 2, 0);
+        assert_eq!(lit(&screen), vec![(4, 2)]);
+        scroll(&mut screen, WIDTH, -4, -1, 0);
+        assert_eq!(lit(&screen), vec![(0, 1)]);
+    }
+}
@@ -0,0 +1,221 @@
+use std::fs;
+
+// The hex digit fonts interpreters shipped with. Fx29 points I at a 4x5
+// small glyph (drawn from the top bits of each byte), SCHIP's Fx30 at an 8x10
+// large one. Machines copy the font into memory when they're built; the large
+// glyphs follow the small ones.
+
+pub const SMALL_GLYPH: usize = 5;
+pub const LARGE_GLYPH: usize = 10;
+pub const SMALL_SIZE: usize = 16 * SMALL_GLYPH;
+
+// The bundled fonts, for option parsing and help text.
+pub const NAMES: [&str; 5] = ["vip", "dream6800", "eti660", "schip", "octo"];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Font {
+    pub small: [u8; SMALL_SIZE],
+    // Digits 0-9, or 0-F, 10 bytes each.
+    pub large: Vec<u8>,
+}
+
+impl Default for Font {
+    fn default() -> Self {
+        Font { small: SCHIP_SMALL, large: SCHIP_LARGE.to_vec() }
+    }
+}
+
+impl Font {
+    // One of `NAMES`. The fonts of machines without a large font get SCHIP's,
+    // which is what programs using Fx30 were written against.
+    pub fn named(name: &str) -> Option<Self> {
+        let (small, large): ([u8; SMALL_SIZE], &[u8]) = match name {
+            "vip" => (VIP_SMALL, &SCHIP_LARGE),
+            "dream6800" => (DREAM6800_SMALL, &SCHIP_LARGE),
+            "eti660" => (ETI660_SMALL, &SCHIP_LARGE),
+            "schip" => (SCHIP_SMALL, &SCHIP_LARGE),
+            "octo" => (SCHIP_SMALL, &OCTO_LARGE),
+            _ => return None,
+        };
+        Some(Font { small, large: large.to_vec() })
+    }
+
+    // A font file holds the 80 bytes of the small font, optionally followed
+    // by 10 or 16 large glyphs. Without them SCHIP's large font is used.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        if bytes.len() < SMALL_SIZE {
+            return Err(format!("A font needs at least {} bytes, this one has {}", SMALL_SIZE, bytes.len()));
+        }
+
+        let (small, large) = bytes.split_at(SMALL_SIZE);
+        let large = match large.len() {
+            0 => SCHIP_LARGE.to_vec(),
+            n if n == 10 * LARGE_GLYPH || n == 16 * LARGE_GLYPH => large.to_vec(),
+            n => return Err(format!("The large font must be 10 or 16 glyphs of {} bytes, not {} bytes", LARGE_GLYPH, n)),
+        };
+
+        let mut font = Font { small: [0; SMALL_SIZE], large };
+        font.small.copy_from_slice(small);
+        Ok(font)
+    }
+
+    // A bundled font by name, or else a font file at `name_or_path`.
+    pub fn load(name_or_path: &str) -> Result<Self, String> {
+        if let Some(font) = Font::named(name_or_path) {
+            return Ok(font);
+        }
+        let bytes = fs::read(name_or_path).map_err(|e| format!("Error opening font {}: {}", name_or_path, e))?;
+        Font::from_bytes(&bytes)
+    }
+
+    // The font as laid out in memory, small glyphs first.
+    pub fn bytes(&self) -> Vec<u8> {
+        let mut bytes = self.small.to_vec();
+        bytes.extend_from_slice(&self.large);
+        bytes
+    }
+}
+
+const VIP_SMALL: [u8; SMALL_SIZE] = [
+    0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
+    0x60, 0x20, 0x20, 0x20, 0x70, // 1
+    0xF0, 0x10, 0xF0, 0x80, 0xF0, // 2
+    0xF0, 0x10, 0xF0, 0x10, 0xF0, // 3
+    0xA0, 0xA0, 0xF0, 0x20, 0x20, // 4
+    0xF0, 0x80, 0xF0, 0x10, 0xF0, // 5
+    0xF0, 0x80, 0xF0, 0x90, 0xF0, // 6
+    0xF0, 0x10, 0x10, 0x10, 0x10, // 7
+    0xF0, 0x90, 0xF0, 0x90, 0xF0, // 8
+    0xF0, 0x90, 0xF0, 0x10, 0xF0, // 9
+    0xF0, 0x90, 0xF0, 0x90, 0x90, // A
+    0xF0, 0x50, 0x70, 0x50, 0xF0, // B
+    0xF0, 0x80, 0x80, 0x80, 0xF0, // C
+    0xF0, 0x50, 0x50, 0x50, 0xF0, // D
+    0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
+    0xF0, 0x80, 0xF0, 0x80, 0x80, // F
+];
+
+// 3 pixels wide.
+const DREAM6800_SMALL: [u8; SMALL_SIZE] = [
+    0xE0, 0xA0, 0xA0, 0xA0, 0xE0, // 0
+    0x40, 0x40, 0x40, 0x40, 0x40, // 1
+    0xE0, 0x20, 0xE0, 0x80, 0xE0, // 2
+    0xE0, 0x20, 0xE0, 0x20, 0xE0, // 3
+    0x80, 0xA0, 0xA0, 0xE0, 0x20, // 4
+    0xE0, 0x80, 0xE0, 0x20, 0xE0, // 5
+    0xE0, 0x80, 0xE0, 0xA0, 0xE0, // 6
+    0xE0, 0x20, 0x20, 0x20, 0x20, // 7
+    0xE0, 0xA0, 0xE0, 0xA0, 0xE0, // 8
+    0xE0, 0xA0, 0xE0, 0x20, 0xE0, // 9
+    0xE0, 0xA0, 0xE0, 0xA0, 0xA0, // A
+    0xC0, 0xA0, 0xE0, 0xA0, 0xC0, // B
+    0xE0, 0x80, 0x80, 0x80, 0xE0, // C
+    0xC0, 0xA0, 0xA0, 0xA0, 0xC0, // D
+    0xE0, 0x80, 0xE0, 0x80, 0xE0, // E
+    0xE0, 0x80, 0xC0, 0x80, 0x80, // F
+];
+
+// 3 pixels wide, with lowercase b and d.
+const ETI660_SMALL: [u8; SMALL_SIZE] = [
+    0xE0, 0xA0, 0xA0, 0xA0, 0xE0, // 0
+    0x20, 0x20, 0x20, 0x20, 0x20, // 1
+    0xE0, 0x20, 0xE0, 0x80, 0xE0, // 2
+    0xE0, 0x20, 0xE0, 0x20, 0xE0, // 3
+    0xA0, 0xA0, 0xE0, 0x20, 0x20, // 4
+    0xE0, 0x80, 0xE0, 0x20, 0xE0, // 5
+    0xE0, 0x80, 0xE0, 0xA0, 0xE0, // 6
+    0xE0, 0x20, 0x20, 0x20, 0x20, // 7
+    0xE0, 0xA0, 0xE0, 0xA0, 0xE0, // 8
+    0xE0, 0xA0, 0xE0, 0x20, 0xE0, // 9
+    0xE0, 0xA0, 0xE0, 0xA0, 0xA0, // A
+    0x80, 0x80, 0xE0, 0xA0, 0xE0, // b
+    0xE0, 0x80, 0x80, 0x80, 0xE0, // C
+    0x20, 0x20, 0xE0, 0xA0, 0xE0, // d
+    0xE0, 0x80, 0xE0, 0x80, 0xE0, // E
+    0xE0, 0x80, 0xC0, 0x80, 0x80, // F
+];
+
+// Also Octo's small font, and what this emulator always used.
+const SCHIP_SMALL: [u8; SMALL_SIZE] = [
+    0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
+    0x20, 0x60, 0x20, 0x20, 0x70, // 1
+    0xF0, 0x10, 0xF0, 0x80, 0xF0, // 2
+    0xF0, 0x10, 0xF0, 0x10, 0xF0, // 3
+    0x90, 0x90, 0xF0, 0x10, 0x10, // 4
+    0xF0, 0x80, 0xF0, 0x10, 0xF0, // 5
+    0xF0, 0x80, 0xF0, 0x90, 0xF0, // 6
+    0xF0, 0x10, 0x20, 0x40, 0x40, // 7
+    0xF0, 0x90, 0xF0, 0x90, 0xF0, // 8
+    0xF0, 0x90, 0xF0, 0x10, 0xF0, // 9
+    0xF0, 0x90, 0xF0, 0x90, 0x90, // A
+    0xE0, 0x90, 0xE0, 0x90, 0xE0, // B
+    0xF0, 0x80, 0x80, 0x80, 0xF0, // C
+    0xE0, 0x90, 0x90, 0x90, 0xE0, // D
+    0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
+    0xF0, 0x80, 0xF0, 0x80, 0x80, // F
+];
+
+// SCHIP 1.1 only has large digits.
+const SCHIP_LARGE: [u8; 10 * LARGE_GLYPH] = [
+    0x3C, 0x7E, 0xE7, 0xC3, 0xC3, 0xC3, 0xC3, 0xE7, 0x7E, 0x3C, // 0
+    0x18, 0x38, 0x58, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x3C, // 1
+    0x3E, 0x7F, 0xC3, 0x06, 0x0C, 0x18, 0x30, 0x60, 0xFF, 0xFF, // 2
+    0x3C, 0x7E, 0xC3, 0x03, 0x0E, 0x0E, 0x03, 0xC3, 0x7E, 0x3C, // 3
+    0x06, 0x0E, 0x1E, 0x36, 0x66, 0xC6, 0xFF, 0xFF, 0x06, 0x06, // 4
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFC, 0xFE, 0x03, 0xC3, 0x7E, 0x3C, // 5
+    0x3E, 0x7C, 0xC0, 0xC0, 0xFC, 0xFE, 0xC3, 0xC3, 0x7E, 0x3C, // 6
+    0xFF, 0xFF, 0x03, 0x06, 0x0C, 0x18, 0x30, 0x60, 0x60, 0x60, // 7
+    0x3C, 0x7E, 0xC3, 0xC3, 0x7E, 0x7E, 0xC3, 0xC3, 0x7E, 0x3C, // 8
+    0x3C, 0x7E, 0xC3, 0xC3, 0x7F, 0x3F, 0x03, 0x03, 0x3E, 0x7C, // 9
+];
+
+const OCTO_LARGE: [u8; 16 * LARGE_GLYPH] = [
+    0xFF, 0xFF, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, // 0
+    0x18, 0x78, 0x78, 0x18, 0x18, 0x18, 0x18, 0x18, 0xFF, 0xFF, // 1
+    0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // 2
+    0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 3
+    0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0x03, 0x03, // 4
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 5
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, // 6
+    0xFF, 0xFF, 0x03, 0x03, 0x06, 0x0C, 0x18, 0x18, 0x18, 0x18, // 7
+    0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, // 8
+    0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 9
+    0x7E, 0xFF, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xC3, // A
+    0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, // B
+    0x3C, 0xFF, 0xC3, 0xC0, 0xC0, 0xC0, 0xC0, 0xC3, 0xFF, 0x3C, // C
+    0xFC, 0xFE, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFE, 0xFC, // D
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // E
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xC0, 0xC0, // F
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bundled_fonts() {
+        for name in NAMES.iter() {
+            let font = Font::named(name).unwrap();
+            assert!(font.large.len() == 10 * LARGE_GLYPH || font.large.len() == 16 * LARGE_GLYPH, "{}", name);
+            assert_ne!(font.small[..SMALL_GLYPH], [0; SMALL_GLYPH], "{}", name);
+        }
+        assert_eq!(Font::named("vip").unwrap().small[5..10], [0x60, 0x20, 0x20, 0x20, 0x70]);
+        assert_eq!(Font::named("octo").unwrap().large.len(), 160);
+        assert_eq!(Font::named("missing"), None);
+    }
+
+    #[test]
+    fn font_files() {
+        let font = Font::from_bytes(&[0xAA; SMALL_SIZE]).unwrap();
+        assert_eq!(font.small, [0xAA; SMALL_SIZE]);
+        assert_eq!(font.large, SCHIP_LARGE.to_vec());
+
+        let mut bytes = vec![0x11; SMALL_SIZE + 16 * LARGE_GLYPH];
+        bytes[SMALL_SIZE] = 0x22;
+        let font = Font::from_bytes(&bytes).unwrap();
+        assert_eq!(font.bytes(), bytes);
+
+        assert!(Font::from_bytes(&[0; 79]).is_err());
+        assert!(Font::from_bytes(&[0; SMALL_SIZE + 15]).is_err());
+    }
+}
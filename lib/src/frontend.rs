@@ -0,0 +1,92 @@
+use crate::display::Frame;
+use crate::overlay::Overlay;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Hotkey {
+    TogglePause,
+    FrameAdvance,
+    FastForward,
+    Turbo,
+    SlowMotion,
+    ToggleStats,
+    ToggleRegisters,
+    ToggleHeatmap,
+    ReloadScript,
+}
+
+// Shared contract between the emulator loop and whatever is drawing the screen
+// and reading the keyboard (SDL window, terminal, ...).
+pub trait Frontend {
+    // Updates `keypad` from pending input. Returns true when the user asked to quit.
+    fn process_input(&mut self, keypad: &mut [u8]) -> bool;
+
+    // Draws `frame`, scaled to fit, with `overlay` on top of it.
+    fn render_frame(&mut self, frame: &Frame, overlay: &Overlay);
+
+    // Called every frame with whether the sound timer is currently running.
+    fn set_sound(&mut self, _active: bool) {}
+
+    // Plays digitised sound, mono at `audio::SAMPLE_RATE`, after what was
+    // queued before.
+    fn queue_audio(&mut self, _samples: &[i16]) {}
+
+    // Shows a memory heatmap beside the screen, or closes it when None.
+    fn show_heatmap(&mut self, _heatmap: Option<&Frame>) {}
+
+    // Emulator hotkeys seen since the last call.
+    fn poll_hotkeys(&mut self) -> Vec<Hotkey> {
+        Vec::new()
+    }
+}
+
+// Maps the usual QWERTY layout onto the hex keypad:
+//  1 2 3 4      1 2 3 C
+//  Q W E R  ->  4 5 6 D
+//  A S D F      7 8 9 E
+//  Z X C V      A 0 B F
+pub fn keypad_index(key: char) -> Option<usize> {
+    match key.to_ascii_lowercase() {
+        'x' => Some(0x0),
+        '1' => Some(0x1),
+        '2' => Some(0x2),
+        '3' => Some(0x3),
+        'q' => Some(0x4),
+        'w' => Some(0x5),
+        'e' => Some(0x6),
+        'a' => Some(0x7),
+        's' => Some(0x8),
+        'd' => Some(0x9),
+        'z' => Some(0xA),
+        'c' => Some(0xB),
+        '4' => Some(0xC),
+        'r' => Some(0xD),
+        'f' => Some(0xE),
+        'v' => Some(0xF),
+        _ => None,
+    }
+}
+
+// Hotkeys use keys outside the keypad layout.
+//  P    pause/resume
+//  N    advance one frame
+//  Tab  fast-forward
+//  T    turbo (uncapped)
+//  M    slow motion (0.5x, 0.25x)
+//  I    FPS/IPS counters
+//  G    register panel
+//  H    memory heatmap (SDL only)
+//  L    reload the script
+pub fn hotkey(key: char) -> Option<Hotkey> {
+    match key.to_ascii_lowercase() {
+        'p' => Some(Hotkey::TogglePause),
+        'n' => Some(Hotkey::FrameAdvance),
+        '\t' => Some(Hotkey::FastForward),
+        't' => Some(Hotkey::Turbo),
+        'm' => Some(Hotkey::SlowMotion),
+        'i' => Some(Hotkey::ToggleStats),
+        'g' => Some(Hotkey::ToggleRegisters),
+        'h' => Some(Hotkey::ToggleHeatmap),
+        'l' => Some(Hotkey::ReloadScript),
+        _ => None,
+    }
+}
\ No newline at end of file
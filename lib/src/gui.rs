@@ -1,12 +1,27 @@
-use sdl2::pixels::Color;
-use sdl2::rect::Point;
+use sdl2::audio::{AudioQueue, AudioSpecDesired};
+use sdl2::pixels::{Color, PixelFormatEnum};
+use sdl2::rect::Rect;
+use sdl2::render::BlendMode;
 use sdl2::video::Window;
-use sdl2::event::Event;
+use sdl2::event::{Event, WindowEvent};
 use sdl2::keyboard::Keycode;
+use crate::audio::SAMPLE_RATE;
+use crate::display::Frame;
+use crate::frontend::{Frontend, Hotkey};
+use crate::overlay::{self, Overlay, GLYPH_HEIGHT, GLYPH_WIDTH};
 
 pub struct Gui {
     canvas: sdl2::render::Canvas<Window>,
     event_pump: sdl2::EventPump,
+    hotkeys: Vec<Hotkey>,
+    scale: u32,
+    // Size of the last frame drawn; the window follows its shape.
+    frame_size: (usize, usize),
+    // None when there is no audio device; the emulator runs silent then.
+    audio: Option<AudioQueue<i16>>,
+    video: sdl2::VideoSubsystem,
+    // The memory heatmap's own window, while it's open.
+    heatmap: Option<sdl2::render::Canvas<Window>>,
 }
 
 impl Gui {
@@ -15,48 +30,126 @@ impl Gui {
         let video_subsystem = sdl_context.video().unwrap();
         let window = video_subsystem.window("RC8-Emu", 64*scale, 32*scale) //Multiply by scale
             .position_centered()
+            .resizable()
             .build()
             .unwrap();
 
+        // Drawn at window resolution so the overlay text isn't limited to 64x32.
         let mut canvas = window.into_canvas().build().unwrap();
-        canvas.set_logical_size(64, 32).unwrap();
+        canvas.set_blend_mode(BlendMode::Blend);
+
+        let spec = AudioSpecDesired { freq: Some(SAMPLE_RATE as i32), channels: Some(1), samples: None };
+        let audio = sdl_context.audio().and_then(|audio| audio.open_queue(None, &spec)).ok();
+        if let Some(queue) = &audio {
+            queue.resume();
+        }
 
         let event_pump = sdl_context.event_pump().unwrap();
         Gui {
-            canvas: canvas,
-            event_pump: event_pump,
+            canvas,
+            event_pump,
+            hotkeys: Vec::new(),
+            scale,
+            frame_size: (64, 32),
+            audio,
+            video: video_subsystem,
+            heatmap: None,
         }
     }
 
-    pub fn render_frame(&mut self, screen: &[u8]) -> () {
-        self.canvas.set_draw_color(Color::RGB(156, 159, 76));
-        self.canvas.clear();
+    // Draws `frame` stretched over `target`.
+    fn blit(canvas: &mut sdl2::render::Canvas<Window>, frame: &Frame, target: Rect) {
+        let bytes: Vec<u8> = frame.pixels.iter().flatten().copied().collect();
+        let creator = canvas.texture_creator();
+        let mut texture = creator.create_texture_streaming(PixelFormatEnum::RGBA32, frame.width as u32, frame.height as u32).unwrap();
+        texture.update(None, &bytes, frame.width * 4).unwrap();
+        canvas.copy(&texture, None, target).unwrap();
+    }
 
-        let mut x: i32 = 0;
-        let mut y: i32 = 0;
-        self.canvas.set_draw_color(Color::RGB(57, 74, 30));
+    fn draw_overlay(&mut self, overlay: &Overlay, width: u32, height: u32, px: u32) {
+        let cell_w = (GLYPH_WIDTH as u32 + 1) * px;
+        let cell_h = (GLYPH_HEIGHT as u32 + 1) * px;
 
-        for i in screen.iter() {
-            if *i != 0 {
-                self.canvas.draw_point(Point::new(x, y)).unwrap();
-            }
+        for text in overlay.layout((width / cell_w) as usize, (height / cell_h) as usize) {
+            let x = text.col as i32 * cell_w as i32;
+            let y = text.row as i32 * cell_h as i32;
+
+            self.canvas.set_draw_color(Color::RGBA(0, 0, 0, 160));
+            self.canvas.fill_rect(Rect::new(x, y, text.text.len() as u32 * cell_w + px, cell_h + px)).unwrap();
 
-            x += 1;
-            if x == 64 {
-                x = 0;
-                y += 1;
+            self.canvas.set_draw_color(Color::RGB(255, 255, 255));
+            for (i, c) in text.text.chars().enumerate() {
+                let glyph = overlay::glyph(c);
+                for (row, bits) in glyph.iter().enumerate() {
+                    for col in 0..GLYPH_WIDTH {
+                        if bits & (0b100 >> col) != 0 {
+                            let gx = x + (i as u32 * cell_w + (col as u32 + 1) * px) as i32;
+                            let gy = y + ((row as u32 + 1) * px) as i32;
+                            self.canvas.fill_rect(Rect::new(gx, gy, px, px)).unwrap();
+                        }
+                    }
+                }
             }
         }
+    }
+}
+
+impl Frontend for Gui {
+    fn render_frame(&mut self, frame: &Frame, overlay: &Overlay) {
+        // A machine switching display modes gets a window of the new shape,
+        // as wide as before.
+        if (frame.width, frame.height) != self.frame_size {
+            self.frame_size = (frame.width, frame.height);
+            let width = 64 * self.scale;
+            let height = width * frame.height as u32 / frame.width as u32;
+            self.canvas.window_mut().set_size(width, height).unwrap();
+        }
+
+        self.canvas.set_draw_color(Color::RGB(0, 0, 0));
+        self.canvas.clear();
+
+        let (width, height) = self.canvas.output_size().unwrap();
+        let (frame_width, frame_height) = (frame.width as u32, frame.height as u32);
+        // Whole pixels keep the picture sharp; a screen larger than the
+        // window is scaled down to fit.
+        let fit = (width as f64 / frame_width as f64).min(height as f64 / frame_height as f64);
+        let scale = if fit >= 1.0 { fit.floor() } else { fit };
+        let (scaled_width, scaled_height) = ((frame_width as f64 * scale) as u32, (frame_height as f64 * scale) as u32);
+        let left = (width as i32 - scaled_width as i32) / 2;
+        let top = (height as i32 - scaled_height as i32) / 2;
+
+        // Uploaded as a texture and scaled by SDL; a rectangle per pixel is
+        // too slow for the larger screens.
+        Gui::blit(&mut self.canvas, frame, Rect::new(left, top, scaled_width.max(1), scaled_height.max(1)));
+
+        self.draw_overlay(overlay, width, height, (scaled_width / 64 / 5).max(1));
 
         self.canvas.present();
     }
 
-    pub fn process_input(&mut self, keypad: &mut [u8]) -> bool {
+    fn process_input(&mut self, keypad: &mut [u8]) -> bool {
         for event in self.event_pump.poll_iter() {
             match event {
                 Event::Quit{..} => return true,
+                // Closing the heatmap only closes the heatmap.
+                Event::Window{window_id, win_event: WindowEvent::Close, ..}
+                    if self.heatmap.as_ref().map(|canvas| canvas.window().id()) == Some(window_id) => {
+                    self.hotkeys.push(Hotkey::ToggleHeatmap);
+                    break;
+                },
+                Event::Window{win_event: WindowEvent::Close, ..} => return true,
                 Event::KeyDown{keycode: Some(Keycode::Escape), ..} => return true,
 
+                Event::KeyDown{keycode: Some(Keycode::P), repeat: false, ..}   => {self.hotkeys.push(Hotkey::TogglePause); break;},
+                Event::KeyDown{keycode: Some(Keycode::N), ..}                  => {self.hotkeys.push(Hotkey::FrameAdvance); break;},
+                Event::KeyDown{keycode: Some(Keycode::Tab), repeat: false, ..} => {self.hotkeys.push(Hotkey::FastForward); break;},
+                Event::KeyDown{keycode: Some(Keycode::T), repeat: false, ..}   => {self.hotkeys.push(Hotkey::Turbo); break;},
+                Event::KeyDown{keycode: Some(Keycode::M), repeat: false, ..}   => {self.hotkeys.push(Hotkey::SlowMotion); break;},
+                Event::KeyDown{keycode: Some(Keycode::I), repeat: false, ..}   => {self.hotkeys.push(Hotkey::ToggleStats); break;},
+                Event::KeyDown{keycode: Some(Keycode::G), repeat: false, ..}   => {self.hotkeys.push(Hotkey::ToggleRegisters); break;},
+                Event::KeyDown{keycode: Some(Keycode::H), repeat: false, ..}   => {self.hotkeys.push(Hotkey::ToggleHeatmap); break;},
+                Event::KeyDown{keycode: Some(Keycode::L), repeat: false, ..}   => {self.hotkeys.push(Hotkey::ReloadScript); break;},
+
                 Event::KeyDown{keycode: Some(Keycode::X), ..}    => {keypad[0] = 1; break;},
                 Event::KeyDown{keycode: Some(Keycode::Num1), ..} => {keypad[1] = 1; break;},
                 Event::KeyDown{keycode: Some(Keycode::Num2), ..} => {keypad[2] = 1; break;},
@@ -96,6 +189,42 @@ impl Gui {
             }
         }
 
-        return false;
+        false
+    }
+
+    fn queue_audio(&mut self, samples: &[i16]) {
+        if let Some(queue) = &self.audio {
+            // Drop sound rather than let it lag further and further behind
+            // when the emulator runs fast; size() is in bytes, 2 per sample.
+            if queue.size() < SAMPLE_RATE / 4 * 2 {
+                queue.queue(samples);
+            }
+        }
+    }
+
+    fn show_heatmap(&mut self, heatmap: Option<&Frame>) {
+        let heatmap = match heatmap {
+            Some(heatmap) => heatmap,
+            None => {
+                self.heatmap = None;
+                return;
+            },
+        };
+        if self.heatmap.is_none() {
+            let size = 4 * self.scale.max(1);
+            let window = self.video.window("RC8-Emu memory", heatmap.width as u32 * size, heatmap.height as u32 * size)
+                .resizable()
+                .build()
+                .unwrap();
+            self.heatmap = Some(window.into_canvas().build().unwrap());
+        }
+        let canvas = self.heatmap.as_mut().unwrap();
+        let (width, height) = canvas.output_size().unwrap();
+        Gui::blit(canvas, heatmap, Rect::new(0, 0, width, height));
+        canvas.present();
+    }
+
+    fn poll_hotkeys(&mut self) -> Vec<Hotkey> {
+        std::mem::take(&mut self.hotkeys)
     }
 }
\ No newline at end of file
@@ -0,0 +1,371 @@
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread::{self, JoinHandle};
+use crate::chip8::{Chip8, Chip8Error, Variant};
+use crate::quirks::Quirks;
+
+// Gym-style environments for training agents on CHIP-8 games, headless and
+// deterministic for a given seed. An action is the keypad as a bitmask, bit
+// n holding key n down; the observation is the screen, one byte per pixel.
+// What counts as reward and as the end of an episode is read out of memory,
+// where games keep their score and lives.
+
+pub const DEFAULT_FRAME_SKIP: u32 = 4;
+pub const DEFAULT_INSTRUCTIONS_PER_FRAME: u32 = 10;
+
+// A number kept in memory.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value {
+    Byte(usize),
+    // Big-endian.
+    Word(usize),
+    // One decimal digit per byte, as Fx33 stores them, most significant first.
+    Bcd { address: usize, digits: usize },
+}
+
+impl Value {
+    fn read(self, memory: &[u8]) -> u32 {
+        let byte = |address: usize| memory.get(address).copied().unwrap_or(0) as u32;
+        match self {
+            Value::Byte(address) => byte(address),
+            Value::Word(address) => byte(address) << 8 | byte(address + 1),
+            Value::Bcd { address, digits } => (address..address + digits).fold(0, |total, address| total * 10 + byte(address)),
+        }
+    }
+}
+
+// Something about memory that holds or doesn't after a frame. Increased and
+// Decreased compare with the frame before.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Predicate {
+    Equals(Value, u32),
+    NotEquals(Value, u32),
+    Below(Value, u32),
+    Above(Value, u32),
+    Increased(Value),
+    Decreased(Value),
+}
+
+impl Predicate {
+    fn holds(self, memory: &[u8], previous: &[u8]) -> bool {
+        match self {
+            Predicate::Equals(value, n) => value.read(memory) == n,
+            Predicate::NotEquals(value, n) => value.read(memory) != n,
+            Predicate::Below(value, n) => value.read(memory) < n,
+            Predicate::Above(value, n) => value.read(memory) > n,
+            Predicate::Increased(value) => value.read(memory) > value.read(previous),
+            Predicate::Decreased(value) => value.read(memory) < value.read(previous),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub rom: Vec<u8>,
+    pub quirks: Quirks,
+    pub variant: Variant,
+    // Frames each step runs with its action held.
+    pub frame_skip: u32,
+    pub instructions_per_frame: u32,
+    // Each frame earns how much the score went up, plus the reward of every
+    // rule that holds.
+    pub score: Option<Value>,
+    pub rewards: Vec<(Predicate, f32)>,
+    // The episode ends after the first frame any of these hold.
+    pub done: Vec<Predicate>,
+    pub max_steps: Option<u32>,
+}
+
+impl Config {
+    pub fn new(rom: Vec<u8>) -> Self {
+        Config {
+            rom,
+            quirks: Quirks::default(),
+            variant: Variant::Chip8,
+            frame_skip: DEFAULT_FRAME_SKIP,
+            instructions_per_frame: DEFAULT_INSTRUCTIONS_PER_FRAME,
+            score: None,
+            rewards: Vec::new(),
+            done: Vec::new(),
+            max_steps: None,
+        }
+    }
+}
+
+// (observation, reward, done)
+pub type Transition = (Vec<u8>, f32, bool);
+
+pub struct Env {
+    config: Config,
+    c8: Chip8,
+    previous: Vec<u8>,
+    steps: u32,
+    done: bool,
+}
+
+impl Env {
+    pub fn new(config: Config) -> Result<Self, Chip8Error> {
+        let mut env = Env { config, c8: Chip8::new(), previous: Vec::new(), steps: 0, done: false };
+        env.reset(0)?;
+        Ok(env)
+    }
+
+    // Starts a new episode from power on, with Cxnn's numbers drawn from `seed`.
+    pub fn reset(&mut self, seed: u64) -> Result<Vec<u8>, Chip8Error> {
+        let mut c8 = Chip8::with_quirks(self.config.quirks);
+        c8.set_variant(self.config.variant);
+        c8.load_bytes(&self.config.rom)?;
+        c8.seed_random(seed);
+        self.previous = c8.memory().to_vec();
+        self.c8 = c8;
+        self.steps = 0;
+        self.done = false;
+        Ok(self.observation())
+    }
+
+    // Holds the keys in `action` for `frame_skip` frames, or until the
+    // episode ends. Stepping a finished episode changes nothing.
+    pub fn step(&mut self, action: u16) -> Result<Transition, Chip8Error> {
+        if self.done {
+            return Ok((self.observation(), 0.0, true));
+        }
+        for (key, held) in self.c8.keypad.iter_mut().enumerate() {
+            *held = (action >> key & 1) as u8;
+        }
+
+        let mut reward = 0.0;
+        for _ in 0..self.config.frame_skip.max(1) {
+            for _ in 0..self.config.instructions_per_frame {
+                self.c8.interpret()?;
+            }
+            self.c8.vblank();
+
+            let memory = self.c8.memory();
+            if let Some(score) = self.config.score {
+                reward += score.read(memory) as f32 - score.read(&self.previous) as f32;
+            }
+            for (predicate, value) in &self.config.rewards {
+                if predicate.holds(memory, &self.previous) {
+                    reward += value;
+                }
+            }
+            self.done = self.config.done.iter().any(|predicate| predicate.holds(memory, &self.previous));
+            self.previous.copy_from_slice(memory);
+            if self.done {
+                break;
+            }
+        }
+
+        self.steps += 1;
+        self.done |= self.config.max_steps.is_some_and(|max| self.steps >= max);
+        Ok((self.observation(), reward, self.done))
+    }
+
+    pub fn observation(&self) -> Vec<u8> {
+        self.c8.gfx.clone()
+    }
+
+    pub fn done(&self) -> bool {
+        self.done
+    }
+
+    pub fn machine(&self) -> &Chip8 {
+        &self.c8
+    }
+}
+
+enum Command {
+    Reset(Vec<u64>),
+    ResetOne(usize, u64),
+    Step(Vec<u16>),
+}
+
+struct Worker {
+    commands: Sender<Command>,
+    replies: Receiver<Vec<Result<Transition, Chip8Error>>>,
+    len: usize,
+    thread: Option<JoinHandle<()>>,
+}
+
+// Many environments stepped together, spread over worker threads. Each
+// worker owns its environments for their whole life, so nothing about the
+// machines needs to cross threads but actions and transitions.
+pub struct VecEnv {
+    workers: Vec<Worker>,
+}
+
+impl VecEnv {
+    pub fn new(config: &Config, count: usize, threads: usize) -> Result<Self, Chip8Error> {
+        // A config that fails fails the same way everywhere.
+        Env::new(config.clone())?;
+
+        let threads = threads.clamp(1, count.max(1));
+        let workers = (0..threads).map(|worker| {
+            let len = count / threads + if worker < count % threads { 1 } else { 0 };
+            let (commands, inbox) = mpsc::channel();
+            let (outbox, replies) = mpsc::channel();
+            let config = config.clone();
+            let thread = thread::spawn(move || run_worker(config, len, inbox, outbox));
+            Worker { commands, replies, len, thread: Some(thread) }
+        }).collect();
+        Ok(VecEnv { workers })
+    }
+
+    pub fn len(&self) -> usize {
+        self.workers.iter().map(|worker| worker.len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Resets every environment, the nth with the nth seed.
+    pub fn reset(&mut self, seeds: &[u64]) -> Vec<Result<Vec<u8>, Chip8Error>> {
+        assert_eq!(seeds.len(), self.len(), "one seed per environment");
+        let mut seeds = seeds.iter().copied();
+        let transitions = self.exchange(|len| Command::Reset(seeds.by_ref().take(len).collect()));
+        transitions.into_iter().map(|result| result.map(|(observation, _, _)| observation)).collect()
+    }
+
+    pub fn reset_one(&mut self, index: usize, seed: u64) -> Result<Vec<u8>, Chip8Error> {
+        let mut local = index;
+        for worker in &self.workers {
+            if local < worker.len {
+                worker.commands.send(Command::ResetOne(local, seed)).expect("environment worker stopped");
+                let mut reply = worker.replies.recv().expect("environment worker stopped");
+                return reply.remove(0).map(|(observation, _, _)| observation);
+            }
+            local -= worker.len;
+        }
+        panic!("No environment {}", index);
+    }
+
+    // Steps every environment, the nth with the nth action.
+    pub fn step(&mut self, actions: &[u16]) -> Vec<Result<Transition, Chip8Error>> {
+        assert_eq!(actions.len(), self.len(), "one action per environment");
+        let mut actions = actions.iter().copied();
+        self.exchange(|len| Command::Step(actions.by_ref().take(len).collect()))
+    }
+
+    // Sends every worker its share of a command, then collects the replies
+    // in order.
+    fn exchange(&mut self, mut command: impl FnMut(usize) -> Command) -> Vec<Result<Transition, Chip8Error>> {
+        for worker in &self.workers {
+            worker.commands.send(command(worker.len)).expect("environment worker stopped");
+        }
+        self.workers.iter()
+            .flat_map(|worker| worker.replies.recv().expect("environment worker stopped"))
+            .collect()
+    }
+}
+
+impl Drop for VecEnv {
+    fn drop(&mut self) {
+        for worker in &mut self.workers {
+            // Closing the channel is what tells the worker to finish.
+            let (closed, _) = mpsc::channel();
+            worker.commands = closed;
+            if let Some(thread) = worker.thread.take() {
+                let _ = thread.join();
+            }
+        }
+    }
+}
+
+fn run_worker(config: Config, len: usize, inbox: Receiver<Command>, outbox: Sender<Vec<Result<Transition, Chip8Error>>>) {
+    let mut envs: Vec<Env> = (0..len).map(|_| Env::new(config.clone()).expect("checked by VecEnv::new")).collect();
+    let reset = |env: &mut Env, seed| env.reset(seed).map(|observation| (observation, 0.0, false));
+    for command in inbox {
+        let reply = match command {
+            Command::Reset(seeds) => envs.iter_mut().zip(seeds).map(|(env, seed)| reset(env, seed)).collect(),
+            Command::ResetOne(index, seed) => vec![reset(&mut envs[index], seed)],
+            Command::Step(actions) => envs.iter_mut().zip(actions).map(|(env, action)| env.step(action)).collect(),
+        };
+        if outbox.send(reply).is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Adds 1 to a BCD score at 300 per frame while key 5 is held, draws a
+    // random byte's worth of sprite, and counts frames in V2.
+    const ROM: [u8; 36] = [
+        0x63, 0x05, // 200: LD V3, 05
+        0xC0, 0xFF, // 202: RND V0, FF
+        0xA2, 0x22, // 204: LD I, 222
+        0xF0, 0x55, // 206: LD [I], V0
+        0xD1, 0x11, // 208: DRW V1, V1, 1
+        0xE3, 0xA1, // 20A: SKNP V3
+        0x74, 0x01, // 20C: ADD V4, 01
+        0xA3, 0x00, // 20E: LD I, 300
+        0xF4, 0x33, // 210: LD B, V4
+        0x72, 0x01, // 212: ADD V2, 01
+        0xF5, 0x07, // 214: LD V5, DT
+        0x35, 0x00, // 216: SE V5, 00
+        0x12, 0x14, // 218: JP 214
+        0x60, 0x01, // 21A: LD V0, 01
+        0xF0, 0x15, // 21C: LD DT, V0
+        0x12, 0x0A, // 21E: JP 20A
+        0x00, 0x00, // 220: unused
+        0x00, 0x00, // 222: the random byte
+    ];
+
+    fn config() -> Config {
+        let mut config = Config::new(ROM.to_vec());
+        config.frame_skip = 2;
+        config.instructions_per_frame = 20;
+        config.score = Some(Value::Bcd { address: 0x300, digits: 3 });
+        config.done = vec![Predicate::Above(Value::Bcd { address: 0x300, digits: 3 }, 4)];
+        config
+    }
+
+    #[test]
+    fn rewards_and_ends_episodes() {
+        let mut env = Env::new(config()).unwrap();
+        assert_eq!(env.reset(7).unwrap().len(), 64 * 32);
+        assert_eq!(env.step(0).unwrap().1, 0.0);
+        // A point a frame while key 5 is held.
+        assert_eq!(env.step(1 << 5).unwrap().1, 2.0);
+        assert_eq!(env.step(1 << 5).unwrap(), (env.observation(), 2.0, false));
+        // 5 points ends it after the first frame of the step.
+        let (_, reward, done) = env.step(1 << 5).unwrap();
+        assert_eq!((reward, done), (1.0, true));
+        assert_eq!(env.step(1 << 5).unwrap().1, 0.0);
+        assert_eq!(env.machine().memory()[0x300..0x303], [0, 0, 5]);
+
+        // The same seed draws the same numbers.
+        let random = |env: &mut Env, seed| {
+            env.reset(seed).unwrap();
+            env.step(0).unwrap();
+            env.machine().memory()[0x222]
+        };
+        assert_eq!(random(&mut env, 7), random(&mut env, 7));
+        assert!((0..8).any(|seed| random(&mut env, seed) != random(&mut env, 7)));
+
+        let mut config = config();
+        config.max_steps = Some(1);
+        assert!(Env::new(config).unwrap().step(0).unwrap().2);
+        assert_eq!(Value::Word(0x300).read(&[0; 4096]), 0);
+    }
+
+    #[test]
+    fn vectorized_matches_single() {
+        let mut vec_env = VecEnv::new(&config(), 5, 2).unwrap();
+        assert_eq!(vec_env.len(), 5);
+        let seeds = [1, 2, 3, 4, 5];
+        let observations = vec_env.reset(&seeds);
+        let actions = [0, 1 << 5, 0, 1 << 5, 1 << 5];
+        let transitions = vec_env.step(&actions);
+
+        for (n, (&seed, &action)) in seeds.iter().zip(&actions).enumerate() {
+            let mut env = Env::new(config()).unwrap();
+            assert_eq!(observations[n], env.reset(seed));
+            assert_eq!(transitions[n], env.step(action));
+        }
+        assert_eq!(vec_env.reset_one(4, 1), vec_env.reset(&seeds).remove(0));
+        assert!(VecEnv::new(&Config::new(vec![0; 8192]), 2, 2).is_err());
+    }
+}
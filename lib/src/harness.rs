@@ -0,0 +1,81 @@
+use crate::chip8::Chip8Error;
+use crate::machine::Machine;
+
+// Headless execution for tests: runs a ROM for a fixed number of frames with
+// scripted keypad input and turns the resulting screen into ASCII art.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KeyEvent {
+    pub frame: u32,
+    pub key: usize,
+    pub pressed: bool,
+}
+
+impl KeyEvent {
+    pub const fn press(frame: u32, key: usize) -> Self {
+        KeyEvent { frame, key, pressed: true }
+    }
+
+    pub const fn release(frame: u32, key: usize) -> Self {
+        KeyEvent { frame, key, pressed: false }
+    }
+}
+
+// Runs `frames` frames of `instructions_per_frame` instructions each, with a
+// vertical blank after every frame. Key events are applied at the start of
+// their frame. Stops at the first error.
+pub fn run(machine: &mut dyn Machine, frames: u32, instructions_per_frame: u32, script: &[KeyEvent]) -> Result<(), Chip8Error> {
+    for frame in 0..frames {
+        for event in script.iter().filter(|event| event.frame == frame) {
+            machine.keypad()[event.key] = if event.pressed { 1 } else { 0 };
+        }
+
+        for _ in 0..instructions_per_frame {
+            machine.step()?;
+        }
+        machine.vblank();
+    }
+
+    Ok(())
+}
+
+// One line per `width` pixel row of `screen`, '#' for lit pixels and '.'
+// for dark ones.
+pub fn to_ascii(screen: &[u8], width: usize) -> String {
+    let mut out = String::new();
+    for row in screen.chunks(width.max(1)) {
+        out.extend(row.iter().map(|&pixel| if pixel != 0 { '#' } else { '.' }));
+        out.push('\n');
+    }
+    out
+}
+
+// Compares two ASCII screens. On mismatch returns both screens side by side
+// with a third column marking '+' for unexpected lit pixels and '-' for
+// missing ones.
+pub fn diff(expected: &str, actual: &str) -> Option<String> {
+    if expected.trim_end() == actual.trim_end() {
+        return None;
+    }
+
+    let expected: Vec<&str> = expected.lines().collect();
+    let actual: Vec<&str> = actual.lines().collect();
+    let rows = expected.len().max(actual.len());
+    let width = expected.iter().chain(actual.iter()).map(|line| line.len()).max().unwrap_or(0);
+
+    let mut out = format!("{:<w$}  {:<w$}  diff\n", "expected", "actual", w = width);
+    for row in 0..rows {
+        let e = expected.get(row).copied().unwrap_or("");
+        let a = actual.get(row).copied().unwrap_or("");
+        let marks: String = (0..width)
+            .map(|col| match (e.as_bytes().get(col), a.as_bytes().get(col)) {
+                (Some(x), Some(y)) if x == y => ' ',
+                (_, Some(b'#')) => '+',
+                _ => '-',
+            })
+            .collect();
+        out.push_str(&format!("{:<w$}  {:<w$}  {}\n", e, a, marks.trim_end(), w = width));
+    }
+
+    Some(out)
+}
\ No newline at end of file
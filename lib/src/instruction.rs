@@ -0,0 +1,519 @@
+use std::fmt;
+use crate::chip8::Variant;
+
+// A decoded CHIP-8 opcode. Register operands are indices into V0-VF; the
+// mnemonics follow Cowgod's reference, the variant-only ones their own
+// documentation where it names them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Instruction {
+    Cls,
+    Ret,
+    Sys(u16),
+    Jp(u16),
+    Call(u16),
+    SeImm(usize, u8),
+    SneImm(usize, u8),
+    Se(usize, usize),
+    LdImm(usize, u8),
+    AddImm(usize, u8),
+    // 8xyN, `op` being N.
+    Alu { op: u8, x: usize, y: usize },
+    Sne(usize, usize),
+    LdI(u16),
+    JpV0(u16),
+    Rnd(usize, u8),
+    Drw(usize, usize, u8),
+    Skp(usize),
+    Sknp(usize),
+    LdVxDt(usize),
+    LdKey(usize),
+    LdDt(usize),
+    LdSt(usize),
+    AddI(usize),
+    LdF(usize),
+    // Fx30, SCHIP's large digits.
+    LdHf(usize),
+    Bcd(usize),
+    Store(usize),
+    Load(usize),
+    // Fx75/Fx85, SCHIP's RPL user flags. Like Fx30 they don't clash with
+    // anything, so every table has them.
+    StoreFlags(usize),
+    LoadFlags(usize),
+
+    // CHIP-8X
+    // 02A0, steps the background through blue, black, green and red.
+    BgColor,
+    // 5xy1, adds each nibble of Vy to the one of Vx, modulo 8.
+    AddNibbles(usize, usize),
+    // Bxy0 colours a block of zones, BxyN N single rows; the colour is Vy.
+    Color(usize, usize, u8),
+    // ExF2/ExF5, the second keypad.
+    Skp2(usize),
+    Sknp2(usize),
+
+    // CHIP-8E
+    // 00ED, halts on the spot.
+    Stop,
+    // 00F2
+    Nop,
+    // 0151, waits for the delay timer to run out.
+    WaitDt,
+    // 0188
+    Skip,
+    // 5xy1
+    Sgt(usize, usize),
+    // 5xy2/5xy3, Vx to Vy from/to memory at I.
+    StoreRange(usize, usize),
+    LoadRange(usize, usize),
+    // BBnn/BFnn, relative to the next instruction.
+    JpBack(u8),
+    JpForward(u8),
+    // Fx1B, skips Vx bytes.
+    SkipBytes(usize),
+    // Fx4F, loads the delay timer and waits for it to run out.
+    Delay(usize),
+    // Fx03 (CHIP-8E) and FxF8 (CHIP-8X).
+    Out(usize),
+    // FxE3 (CHIP-8E) and FxFB (CHIP-8X) wait for the input strobe, FxE7
+    // (CHIP-8E) reads the port as it is.
+    In(usize),
+    Inp(usize),
+
+    // SCHIP
+    // 00Cn
+    ScrollDown(u8),
+    // 00FB/00FC, by 4 pixels.
+    ScrollRight,
+    ScrollLeft,
+    // 00FD
+    Exit,
+    // 00FE/00FF, 64x32 and 128x64.
+    Low,
+    High,
+
+    // MegaChip
+    // 0010/0011, the 256x192 colour mode.
+    MegaOn,
+    MegaOff,
+    // 01nn nnnn, a four byte instruction loading a 24 bit I; this holds the
+    // top byte, the following word the rest.
+    LdIHigh(u8),
+    // 02nn, nn palette entries from I.
+    LdPalette(u8),
+    // 03nn/04nn, the size of colour sprites; 0 is 256.
+    SpriteWidth(u8),
+    SpriteHeight(u8),
+    // 05nn, the screen's fade level.
+    Alpha(u8),
+    // 060n, the sample at I, looped unless n is 1; 0700 stops it.
+    PlaySample(u8),
+    StopSample,
+    // 080n
+    BlendMode(u8),
+    // 09nn, the palette index that counts as a collision.
+    CollisionColor(u8),
+    // 00Bn
+    ScrollUp(u8),
+}
+
+impl Instruction {
+    // Returns None for opcodes that aren't CHIP-8 instructions.
+    pub fn decode(opcode: u16) -> Option<Instruction> {
+        let x = ((opcode & 0x0F00) >> 8) as usize;
+        let y = ((opcode & 0x00F0) >> 4) as usize;
+        let n = (opcode & 0x000F) as u8;
+        let nn = opcode as u8;
+        let nnn = opcode & 0x0FFF;
+
+        let instruction = match opcode & 0xF000 {
+            0x0000 => match opcode {
+                0x00E0 => Instruction::Cls,
+                0x00EE => Instruction::Ret,
+                _ => Instruction::Sys(nnn),
+            },
+            0x1000 => Instruction::Jp(nnn),
+            0x2000 => Instruction::Call(nnn),
+            0x3000 => Instruction::SeImm(x, nn),
+            0x4000 => Instruction::SneImm(x, nn),
+            0x5000 if n == 0 => Instruction::Se(x, y),
+            0x6000 => Instruction::LdImm(x, nn),
+            0x7000 => Instruction::AddImm(x, nn),
+            0x8000 if n <= 0x7 || n == 0xE => Instruction::Alu { op: n, x, y },
+            0x9000 if n == 0 => Instruction::Sne(x, y),
+            0xA000 => Instruction::LdI(nnn),
+            0xB000 => Instruction::JpV0(nnn),
+            0xC000 => Instruction::Rnd(x, nn),
+            0xD000 => Instruction::Drw(x, y, n),
+            0xE000 if nn == 0x9E => Instruction::Skp(x),
+            0xE000 if nn == 0xA1 => Instruction::Sknp(x),
+            0xF000 => match nn {
+                0x07 => Instruction::LdVxDt(x),
+                0x0A => Instruction::LdKey(x),
+                0x15 => Instruction::LdDt(x),
+                0x18 => Instruction::LdSt(x),
+                0x1E => Instruction::AddI(x),
+                0x29 => Instruction::LdF(x),
+                0x30 => Instruction::LdHf(x),
+                0x33 => Instruction::Bcd(x),
+                0x55 => Instruction::Store(x),
+                0x65 => Instruction::Load(x),
+                0x75 => Instruction::StoreFlags(x),
+                0x85 => Instruction::LoadFlags(x),
+                _ => return None,
+            },
+            _ => return None,
+        };
+
+        Some(instruction)
+    }
+
+    // The CHIP-8X opcode table: CHIP-8 with colour, a second keypad and port
+    // I/O. Bnnn is taken over by the colour instruction.
+    pub fn decode_chip8x(opcode: u16) -> Option<Instruction> {
+        let x = ((opcode & 0x0F00) >> 8) as usize;
+        let y = ((opcode & 0x00F0) >> 4) as usize;
+        let n = (opcode & 0x000F) as u8;
+        let nn = opcode as u8;
+
+        let instruction = match opcode & 0xF000 {
+            0x0000 if opcode == 0x02A0 => Instruction::BgColor,
+            0x5000 if n == 1 => Instruction::AddNibbles(x, y),
+            0xB000 => Instruction::Color(x, y, n),
+            0xE000 if nn == 0xF2 => Instruction::Skp2(x),
+            0xE000 if nn == 0xF5 => Instruction::Sknp2(x),
+            0xF000 if nn == 0xF8 => Instruction::Out(x),
+            0xF000 if nn == 0xFB => Instruction::In(x),
+            _ => return Instruction::decode(opcode),
+        };
+
+        Some(instruction)
+    }
+
+    // The CHIP-8E opcode table: CHIP-8 with more skips, relative branches,
+    // register range transfers and port I/O. Bnnn is gone.
+    pub fn decode_chip8e(opcode: u16) -> Option<Instruction> {
+        let x = ((opcode & 0x0F00) >> 8) as usize;
+        let y = ((opcode & 0x00F0) >> 4) as usize;
+        let n = (opcode & 0x000F) as u8;
+        let nn = opcode as u8;
+
+        let instruction = match opcode & 0xF000 {
+            0x0000 => match opcode {
+                0x00ED => Instruction::Stop,
+                0x00F2 => Instruction::Nop,
+                0x0151 => Instruction::WaitDt,
+                0x0188 => Instruction::Skip,
+                _ => return Instruction::decode(opcode),
+            },
+            0x5000 if n == 1 => Instruction::Sgt(x, y),
+            0x5000 if n == 2 => Instruction::StoreRange(x, y),
+            0x5000 if n == 3 => Instruction::LoadRange(x, y),
+            0xB000 => match x {
+                0xB => Instruction::JpBack(nn),
+                0xF => Instruction::JpForward(nn),
+                _ => return None,
+            },
+            0xF000 => match nn {
+                0x03 => Instruction::Out(x),
+                0x1B => Instruction::SkipBytes(x),
+                0x4F => Instruction::Delay(x),
+                0xE3 => Instruction::In(x),
+                0xE7 => Instruction::Inp(x),
+                _ => return Instruction::decode(opcode),
+            },
+            _ => return Instruction::decode(opcode),
+        };
+
+        Some(instruction)
+    }
+}
+
+impl Instruction {
+    // The SCHIP opcode table, without the instructions that need a feature
+    // of their own (large font, RPL flags).
+    pub fn decode_schip(opcode: u16) -> Option<Instruction> {
+        let n = (opcode & 0x000F) as u8;
+
+        let instruction = match opcode {
+            0x00C0..=0x00CF => Instruction::ScrollDown(n),
+            0x00FB => Instruction::ScrollRight,
+            0x00FC => Instruction::ScrollLeft,
+            0x00FD => Instruction::Exit,
+            0x00FE => Instruction::Low,
+            0x00FF => Instruction::High,
+            _ => return Instruction::decode(opcode),
+        };
+
+        Some(instruction)
+    }
+
+    // The MegaChip opcode table: SCHIP plus the colour mode's 0nnn
+    // instructions.
+    pub fn decode_megachip(opcode: u16) -> Option<Instruction> {
+        let n = (opcode & 0x000F) as u8;
+        let nn = opcode as u8;
+
+        let instruction = match opcode {
+            0x0010 => Instruction::MegaOn,
+            0x0011 => Instruction::MegaOff,
+            0x00B0..=0x00BF => Instruction::ScrollUp(n),
+            0x0100..=0x01FF => Instruction::LdIHigh(nn),
+            0x0200..=0x02FF => Instruction::LdPalette(nn),
+            0x0300..=0x03FF => Instruction::SpriteWidth(nn),
+            0x0400..=0x04FF => Instruction::SpriteHeight(nn),
+            0x0500..=0x05FF => Instruction::Alpha(nn),
+            0x0600..=0x060F => Instruction::PlaySample(n),
+            0x0700 => Instruction::StopSample,
+            0x0800..=0x080F => Instruction::BlendMode(n),
+            0x0900..=0x09FF => Instruction::CollisionColor(nn),
+            _ => return Instruction::decode_schip(opcode),
+        };
+
+        Some(instruction)
+    }
+}
+
+// Where execution can go after an instruction, as far as can be told
+// without running it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Flow {
+    // On to the following instruction.
+    Next,
+    // The following instruction or the one after it.
+    Skip,
+    Jump(u16),
+    // To the subroutine, and back to the following instruction.
+    Call(u16),
+    Return,
+    // Depends on a register (Bnnn, Fx1B).
+    Computed,
+    // Nowhere: the program ends.
+    Stop,
+}
+
+impl Instruction {
+    // The flow out of this instruction when it sits at `pc`.
+    pub fn flow(&self, pc: u16) -> Flow {
+        let next = pc.wrapping_add(2);
+        match *self {
+            Instruction::Jp(nnn) => Flow::Jump(nnn),
+            Instruction::Call(nnn) => Flow::Call(nnn),
+            Instruction::Ret => Flow::Return,
+            Instruction::JpV0(_) | Instruction::SkipBytes(_) => Flow::Computed,
+            Instruction::Stop | Instruction::Exit => Flow::Stop,
+            Instruction::Skip => Flow::Jump(next.wrapping_add(2)),
+            Instruction::JpBack(nn) => Flow::Jump(next.wrapping_sub(nn as u16)),
+            Instruction::JpForward(nn) => Flow::Jump(next.wrapping_add(nn as u16)),
+            Instruction::SeImm(..) | Instruction::SneImm(..) | Instruction::Se(..) | Instruction::Sne(..)
+            | Instruction::Skp(_) | Instruction::Sknp(_) | Instruction::Skp2(_) | Instruction::Sknp2(_)
+            | Instruction::Sgt(..) => Flow::Skip,
+            _ => Flow::Next,
+        }
+    }
+
+    // What I holds after this instruction when it held `i` before, as far
+    // as a constant can be known.
+    pub fn i_after(&self, i: Option<u16>) -> Option<u16> {
+        match *self {
+            Instruction::LdI(nnn) => Some(nnn),
+            // Whether Fx55/Fx65 move I depends on the quirks.
+            Instruction::AddI(_) | Instruction::LdF(_) | Instruction::LdHf(_)
+            | Instruction::Store(_) | Instruction::Load(_) => None,
+            _ => i,
+        }
+    }
+}
+
+// One line per instruction for `bytes` loaded at `origin`: address, opcode
+// and mnemonic, or the raw word where it doesn't decode. A trailing odd byte
+// is shown on its own.
+pub fn disassemble(bytes: &[u8], origin: u16, variant: Variant) -> Vec<String> {
+    bytes.chunks(2).enumerate().map(|(i, word)| {
+        let address = origin.wrapping_add(i as u16 * 2);
+        match *word {
+            [high, low] => {
+                let opcode = u16::from_be_bytes([high, low]);
+                match variant.decode(opcode) {
+                    Some(instruction) => format!("{:03X}: {:04X}  {}", address, opcode, instruction),
+                    None => format!("{:03X}: {:04X}  DW {:04X}", address, opcode, opcode),
+                }
+            },
+            _ => format!("{:03X}: {:02X}    DB {:02X}", address, word[0], word[0]),
+        }
+    }).collect()
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Instruction::Cls => write!(f, "CLS"),
+            Instruction::Ret => write!(f, "RET"),
+            Instruction::Sys(nnn) => write!(f, "SYS {:03X}", nnn),
+            Instruction::Jp(nnn) => write!(f, "JP {:03X}", nnn),
+            Instruction::Call(nnn) => write!(f, "CALL {:03X}", nnn),
+            Instruction::SeImm(x, nn) => write!(f, "SE V{:X}, {:02X}", x, nn),
+            Instruction::SneImm(x, nn) => write!(f, "SNE V{:X}, {:02X}", x, nn),
+            Instruction::Se(x, y) => write!(f, "SE V{:X}, V{:X}", x, y),
+            Instruction::LdImm(x, nn) => write!(f, "LD V{:X}, {:02X}", x, nn),
+            Instruction::AddImm(x, nn) => write!(f, "ADD V{:X}, {:02X}", x, nn),
+            Instruction::Alu { op, x, y } => {
+                let mnemonic = match op {
+                    0x0 => "LD",
+                    0x1 => "OR",
+                    0x2 => "AND",
+                    0x3 => "XOR",
+                    0x4 => "ADD",
+                    0x5 => "SUB",
+                    0x6 => "SHR",
+                    0x7 => "SUBN",
+                    _ => "SHL",
+                };
+                write!(f, "{} V{:X}, V{:X}", mnemonic, x, y)
+            },
+            Instruction::Sne(x, y) => write!(f, "SNE V{:X}, V{:X}", x, y),
+            Instruction::LdI(nnn) => write!(f, "LD I, {:03X}", nnn),
+            Instruction::JpV0(nnn) => write!(f, "JP V0, {:03X}", nnn),
+            Instruction::Rnd(x, nn) => write!(f, "RND V{:X}, {:02X}", x, nn),
+            Instruction::Drw(x, y, n) => write!(f, "DRW V{:X}, V{:X}, {:X}", x, y, n),
+            Instruction::Skp(x) => write!(f, "SKP V{:X}", x),
+            Instruction::Sknp(x) => write!(f, "SKNP V{:X}", x),
+            Instruction::LdVxDt(x) => write!(f, "LD V{:X}, DT", x),
+            Instruction::LdKey(x) => write!(f, "LD V{:X}, K", x),
+            Instruction::LdDt(x) => write!(f, "LD DT, V{:X}", x),
+            Instruction::LdSt(x) => write!(f, "LD ST, V{:X}", x),
+            Instruction::AddI(x) => write!(f, "ADD I, V{:X}", x),
+            Instruction::LdF(x) => write!(f, "LD F, V{:X}", x),
+            Instruction::LdHf(x) => write!(f, "LD HF, V{:X}", x),
+            Instruction::Bcd(x) => write!(f, "LD B, V{:X}", x),
+            Instruction::Store(x) => write!(f, "LD [I], V{:X}", x),
+            Instruction::Load(x) => write!(f, "LD V{:X}, [I]", x),
+            Instruction::StoreFlags(x) => write!(f, "LD R, V{:X}", x),
+            Instruction::LoadFlags(x) => write!(f, "LD V{:X}, R", x),
+            Instruction::BgColor => write!(f, "BGCOL"),
+            Instruction::AddNibbles(x, y) => write!(f, "ADDNIB V{:X}, V{:X}", x, y),
+            Instruction::Color(x, y, 0) => write!(f, "COL V{:X}, V{:X}", x, y),
+            Instruction::Color(x, y, n) => write!(f, "COL V{:X}, V{:X}, {:X}", x, y, n),
+            Instruction::Skp2(x) => write!(f, "SKP2 V{:X}", x),
+            Instruction::Sknp2(x) => write!(f, "SKNP2 V{:X}", x),
+            Instruction::Stop => write!(f, "STOP"),
+            Instruction::Nop => write!(f, "NOP"),
+            Instruction::WaitDt => write!(f, "WAITDT"),
+            Instruction::Skip => write!(f, "SKIP"),
+            Instruction::Sgt(x, y) => write!(f, "SGT V{:X}, V{:X}", x, y),
+            Instruction::StoreRange(x, y) => write!(f, "LD [I], V{:X}-V{:X}", x, y),
+            Instruction::LoadRange(x, y) => write!(f, "LD V{:X}-V{:X}, [I]", x, y),
+            Instruction::JpBack(nn) => write!(f, "JB {:02X}", nn),
+            Instruction::JpForward(nn) => write!(f, "JF {:02X}", nn),
+            Instruction::SkipBytes(x) => write!(f, "SKIP V{:X}", x),
+            Instruction::Delay(x) => write!(f, "DELAY V{:X}", x),
+            Instruction::Out(x) => write!(f, "OUT V{:X}", x),
+            Instruction::In(x) => write!(f, "IN V{:X}", x),
+            Instruction::Inp(x) => write!(f, "INP V{:X}", x),
+            Instruction::ScrollDown(n) => write!(f, "SCD {:X}", n),
+            Instruction::ScrollRight => write!(f, "SCR"),
+            Instruction::ScrollLeft => write!(f, "SCL"),
+            Instruction::Exit => write!(f, "EXIT"),
+            Instruction::Low => write!(f, "LOW"),
+            Instruction::High => write!(f, "HIGH"),
+            Instruction::MegaOn => write!(f, "MEGAON"),
+            Instruction::MegaOff => write!(f, "MEGAOFF"),
+            Instruction::LdIHigh(nn) => write!(f, "LDHI I, {:02X}", nn),
+            Instruction::LdPalette(nn) => write!(f, "LDPAL {:02X}", nn),
+            Instruction::SpriteWidth(nn) => write!(f, "SPRW {:02X}", nn),
+            Instruction::SpriteHeight(nn) => write!(f, "SPRH {:02X}", nn),
+            Instruction::Alpha(nn) => write!(f, "ALPHA {:02X}", nn),
+            Instruction::PlaySample(n) => write!(f, "DIGISND {:X}", n),
+            Instruction::StopSample => write!(f, "STOPSND"),
+            Instruction::BlendMode(n) => write!(f, "BMODE {:X}", n),
+            Instruction::CollisionColor(nn) => write!(f, "CCOL {:02X}", nn),
+            Instruction::ScrollUp(n) => write!(f, "SCU {:X}", n),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_and_disassemble() {
+        let cases = [
+            (0x00E0, "CLS"),
+            (0x0123, "SYS 123"),
+            (0x5120, "SE V1, V2"),
+            (0x812E, "SHL V1, V2"),
+            (0xBABC, "JP V0, ABC"),
+            (0xD12F, "DRW V1, V2, F"),
+            (0xF355, "LD [I], V3"),
+            (0xF330, "LD HF, V3"),
+            (0xF775, "LD R, V7"),
+            (0xFF85, "LD VF, R"),
+        ];
+        for &(opcode, text) in cases.iter() {
+            assert_eq!(Instruction::decode(opcode).unwrap().to_string(), text);
+        }
+    }
+
+    #[test]
+    fn invalid_opcodes() {
+        for &opcode in [0x5121, 0x8128, 0x9121, 0xE100, 0xF100].iter() {
+            assert_eq!(Instruction::decode(opcode), None, "{:04X}", opcode);
+        }
+    }
+
+    #[test]
+    fn variant_tables() {
+        let cases = [
+            (Variant::Chip8X, 0x02A0, "BGCOL"),
+            (Variant::Chip8X, 0x5121, "ADDNIB V1, V2"),
+            (Variant::Chip8X, 0xB120, "COL V1, V2"),
+            (Variant::Chip8X, 0xB124, "COL V1, V2, 4"),
+            (Variant::Chip8X, 0xE3F2, "SKP2 V3"),
+            (Variant::Chip8X, 0xF3FB, "IN V3"),
+            (Variant::Chip8X, 0x00E0, "CLS"),
+            (Variant::Chip8E, 0x00ED, "STOP"),
+            (Variant::Chip8E, 0x5123, "LD V1-V2, [I]"),
+            (Variant::Chip8E, 0xBB04, "JB 04"),
+            (Variant::Chip8E, 0xF34F, "DELAY V3"),
+            (Variant::Chip8E, 0xF303, "OUT V3"),
+            (Variant::Chip8E, 0x0123, "SYS 123"),
+            (Variant::Chip8, 0xB124, "JP V0, 124"),
+        ];
+        for &(variant, opcode, text) in cases.iter() {
+            assert_eq!(variant.decode(opcode).unwrap().to_string(), text, "{:?} {:04X}", variant, opcode);
+        }
+
+        assert_eq!(Variant::Chip8E.decode(0xB123), None);
+        assert_eq!(Variant::Chip8.decode(0x02A0), Some(Instruction::Sys(0x2A0)));
+        assert_eq!(Variant::Chip8.decode(0x5121), None);
+    }
+
+    #[test]
+    fn megachip_table() {
+        let cases = [
+            (0x0010, "MEGAON"),
+            (0x0112, "LDHI I, 12"),
+            (0x0210, "LDPAL 10"),
+            (0x0601, "DIGISND 1"),
+            (0x0803, "BMODE 3"),
+            (0x00B4, "SCU 4"),
+            (0x00C4, "SCD 4"),
+            (0x00FF, "HIGH"),
+            (0x00E0, "CLS"),
+            (0x0123, "LDHI I, 23"),
+        ];
+        for &(opcode, text) in cases.iter() {
+            assert_eq!(Instruction::decode_megachip(opcode).unwrap().to_string(), text, "{:04X}", opcode);
+        }
+
+        assert_eq!(Instruction::decode_schip(0x00B4), Some(Instruction::Sys(0x0B4)));
+        assert_eq!(Instruction::decode_megachip(0x0610), Some(Instruction::Sys(0x610)));
+    }
+
+    #[test]
+    fn disassemble_listing() {
+        let listing = disassemble(&[0x00, 0xE0, 0xFF, 0xFF, 0x12], 0x200, Variant::Chip8);
+        assert_eq!(listing, ["200: 00E0  CLS", "202: FFFF  DW FFFF", "204: 12    DB 12"]);
+    }
+}
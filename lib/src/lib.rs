@@ -1,2 +1,35 @@
+pub mod alu;
+pub mod analysis;
+pub mod audio;
+pub mod cdp1802;
 pub mod chip8;
-pub mod gui;
\ No newline at end of file
+pub mod coverage;
+pub mod crash;
+pub mod detect;
+pub mod diagnostics;
+pub mod display;
+pub mod font;
+pub mod frontend;
+#[cfg(feature = "sdl")]
+pub mod gui;
+pub mod gym;
+pub mod harness;
+pub mod instruction;
+pub mod lint;
+pub mod machine;
+pub mod megachip;
+pub mod overlay;
+pub mod png;
+pub mod profile;
+pub mod quirks;
+pub mod rom;
+pub mod rpl;
+#[cfg(feature = "scripting")]
+pub mod script;
+pub mod speed;
+pub mod stack;
+pub mod state;
+pub mod timing;
+#[cfg(unix)]
+pub mod tty;
+pub mod vip;
\ No newline at end of file
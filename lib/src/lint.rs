@@ -0,0 +1,121 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use crate::chip8::Variant;
+use crate::instruction::{Flow, Instruction};
+
+// Static checks on a ROM before it runs. The code is found by following
+// every path from the start address, keeping track of I where it is a
+// constant, which is enough to catch stores aimed straight at instructions.
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Lint {
+    // The instruction at fault.
+    pub pc: u16,
+    pub message: String,
+}
+
+impl fmt::Display for Lint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:03X}: {}", self.pc, self.message)
+    }
+}
+
+// What I holds on entry to an instruction: a known address, or anything.
+type IState = Option<u16>;
+
+pub fn lint(rom: &[u8], variant: Variant) -> Vec<Lint> {
+    let origin = variant.program_start();
+    let fetch = |pc: u16| -> Option<(u16, Instruction)> {
+        let offset = (pc as usize).checked_sub(origin)?;
+        let opcode = u16::from_be_bytes([*rom.get(offset)?, *rom.get(offset + 1)?]);
+        Some((opcode, variant.decode(opcode)?))
+    };
+
+    // I on entry to each reachable instruction. Paths that disagree about I
+    // leave it unknown, so each instruction is revisited at most twice.
+    let mut entry: BTreeMap<u16, IState> = BTreeMap::new();
+    let mut work = vec![(origin as u16, None)];
+    while let Some((pc, i)) = work.pop() {
+        let merged = match entry.get(&pc) {
+            None => i,
+            Some(&known) if known == i || known.is_none() => continue,
+            Some(_) => None,
+        };
+        let (_, instruction) = match fetch(pc) {
+            Some(fetched) => fetched,
+            None => continue,
+        };
+        entry.insert(pc, merged);
+
+        let i = instruction.i_after(merged);
+        let next = pc.wrapping_add(2);
+        match instruction.flow(pc) {
+            Flow::Next => work.push((next, i)),
+            Flow::Skip => {
+                work.push((next, i));
+                work.push((next.wrapping_add(2), i));
+            },
+            Flow::Jump(target) => work.push((target, i)),
+            // The subroutine may do anything to I.
+            Flow::Call(target) => {
+                work.push((target, i));
+                work.push((next, None));
+            },
+            Flow::Return | Flow::Computed | Flow::Stop => {},
+        }
+    }
+
+    let code: BTreeSet<u16> = entry.keys().flat_map(|&pc| vec![pc, pc.wrapping_add(1)]).collect();
+    let mut lints = Vec::new();
+    for (&pc, &i) in &entry {
+        let (opcode, instruction) = fetch(pc).unwrap();
+        let (i, len) = match (i, stored_bytes(&instruction)) {
+            (Some(i), Some(len)) => (i, len),
+            _ => continue,
+        };
+        if let Some(target) = (i..i.saturating_add(len)).find(|address| code.contains(address)) {
+            lints.push(Lint {
+                pc,
+                message: format!("{:04X} stores over the code at {:03X}, the program modifies itself", opcode, target),
+            });
+        }
+    }
+    lints
+}
+
+// How many bytes from I an instruction writes.
+fn stored_bytes(instruction: &Instruction) -> Option<u16> {
+    match *instruction {
+        Instruction::Store(x) => Some(x as u16 + 1),
+        Instruction::Bcd(_) => Some(3),
+        Instruction::StoreRange(x, y) => Some((x as i32 - y as i32).unsigned_abs() as u16 + 1),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flags_stores_into_code() {
+        // Stores into data are what Fx55 is for.
+        let rom = [
+            0xA2, 0x06, // 200: LD I, 206
+            0xF1, 0x55, // 202: LD [I], V1
+            0x12, 0x04, // 204: JP 204
+            0x00, 0x00, // 206: data
+        ];
+        assert_eq!(lint(&rom, Variant::Chip8), []);
+
+        // Here the store lands on the subroutine at 20A.
+        let rom = [0xA2, 0x0A, 0xF1, 0x55, 0x22, 0x0A, 0x12, 0x06, 0x00, 0x00, 0x00, 0xEE];
+        let lints = lint(&rom, Variant::Chip8);
+        assert_eq!(lints.len(), 1);
+        assert_eq!(lints[0].to_string(), "202: F155 stores over the code at 20A, the program modifies itself");
+
+        // I unknown after a call, so nothing can be said.
+        let rom = [0x22, 0x06, 0xF2, 0x33, 0x12, 0x02, 0x00, 0xEE];
+        assert_eq!(lint(&rom, Variant::Chip8), []);
+    }
+}
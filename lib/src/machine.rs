@@ -0,0 +1,77 @@
+use crate::chip8::{Chip8, Chip8Error};
+use crate::coverage::Coverage;
+use crate::display::Frame;
+use crate::profile::Profiler;
+use crate::quirks::Quirks;
+use crate::stack::CallFrame;
+
+// What the emulator loop needs from an emulated computer, so pacing, input,
+// the overlay and every frontend work the same whichever machine is running.
+pub trait Machine {
+    // Runs one instruction and returns what it cost in the units of the
+    // machine's frame budget (see `SpeedControl::with_frame_budget`).
+    fn step(&mut self) -> Result<u32, Chip8Error>;
+
+    // End of a 60 Hz frame.
+    fn vblank(&mut self);
+
+    fn keypad(&mut self) -> &mut [u8; 16];
+
+    // The picture as the machine's display would show it.
+    fn frame(&self) -> Frame;
+
+    // The screen as 1 for lit and 0 for dark pixels, row by row, and its
+    // width; what colour either is depends on the machine.
+    fn lit_pixels(&self) -> (Vec<u8>, usize);
+
+    fn sound_active(&self) -> bool;
+
+    // Digitised sound produced since the last call, at `audio::SAMPLE_RATE`.
+    fn audio_samples(&mut self) -> Vec<i16> {
+        Vec::new()
+    }
+
+    // Calls that haven't returned yet, oldest first, for the register
+    // overlay and crash reports.
+    fn call_stack(&self) -> &[CallFrame] {
+        &[]
+    }
+
+    // CPU state for the register overlay, one line each.
+    fn register_lines(&self) -> Vec<String>;
+
+    // What the rest of these describe goes into crash reports; machines
+    // that can't say leave them out.
+    fn quirks(&self) -> Option<Quirks> {
+        None
+    }
+
+    // The last instructions run, oldest first, disassembled.
+    fn history(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    // Disassembly from `before` instructions ahead of pc to `after` past it.
+    fn listing(&self, _before: usize, _after: usize) -> Vec<String> {
+        Vec::new()
+    }
+
+    // A snapshot to reopen the machine from.
+    fn save_state(&self) -> Option<Vec<u8>> {
+        None
+    }
+
+    // How memory has been used, for machines that track it.
+    fn coverage(&self) -> Option<&Coverage> {
+        None
+    }
+
+    fn profiler(&self) -> Option<&Profiler> {
+        None
+    }
+
+    // For what only works on CHIP-8 itself, such as scripts.
+    fn as_chip8(&mut self) -> Option<&mut Chip8> {
+        None
+    }
+}
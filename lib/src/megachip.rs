@@ -0,0 +1,810 @@
+use std::fs;
+use rand::Rng;
+use crate::alu;
+use crate::crash::History;
+use crate::audio::{self, SamplePlayer};
+use crate::chip8::{Chip8Error, FONT_START};
+use crate::display::{self, Frame, Rgba};
+use crate::font::{self, Font};
+use crate::instruction::Instruction;
+use crate::machine::Machine;
+use crate::quirks::Quirks;
+use crate::rpl::{FlagStore, NoStore, FLAG_COUNT};
+use crate::stack::{CallFrame, CallStack};
+
+// MegaChip: SCHIP with a 24 bit address space and a 256x192 mode where
+// sprites are arbitrarily sized blocks of palette indices, blended onto a
+// back buffer that 00E0 puts on the screen. Digitised sound plays from memory.
+
+pub const MEMORY_SIZE: usize = 1 << 24;
+const PROGRAM_START: usize = 0x200;
+pub const WIDTH: usize = 256;
+pub const HEIGHT: usize = 192;
+
+// MegaChip programs expect a far faster machine than CHIP-8 ones; this is
+// the speed they are commonly run at.
+pub const INSTRUCTIONS_PER_FRAME: u32 = 3000;
+
+// The fonts predate colour, so in MegaChip mode they are drawn in white and
+// collide as this palette index.
+const FONT_COLOR: Rgba = [255, 255, 255, 255];
+const FONT_INDEX: u8 = 255;
+
+const BLACK: Rgba = [0, 0, 0, 255];
+
+// How a sprite pixel is combined with what is already drawn (080n). The
+// pixel's own palette alpha applies in every mode.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Blend {
+    Normal,
+    Quarter,
+    Half,
+    Add,
+    Multiply,
+}
+
+impl Blend {
+    fn from_mode(n: u8) -> Option<Self> {
+        match n {
+            0 => Some(Blend::Normal),
+            1 => Some(Blend::Quarter),
+            2 => Some(Blend::Half),
+            3 => Some(Blend::Add),
+            4 => Some(Blend::Multiply),
+            _ => None,
+        }
+    }
+
+    pub fn apply(self, source: Rgba, target: Rgba) -> Rgba {
+        let alpha = match self {
+            Blend::Quarter => source[3] as u32 / 4,
+            Blend::Half => source[3] as u32 / 2,
+            _ => source[3] as u32,
+        };
+
+        let mut out = BLACK;
+        for channel in 0..3 {
+            let (s, t) = (source[channel] as u32, target[channel] as u32);
+            let blended = match self {
+                Blend::Add => (t + s).min(255),
+                Blend::Multiply => t * s / 255,
+                _ => s,
+            };
+            out[channel] = ((blended * alpha + t * (255 - alpha)) / 255) as u8;
+        }
+        out
+    }
+}
+
+#[derive(Debug)]
+pub struct MegaChip {
+    pc: usize,
+    memory: Vec<u8>,
+    v_reg: [u8; 16],
+    i_reg: usize,
+    stack: CallStack,
+    delay_timer: u8,
+    sound_timer: u8,
+    pub keypad: [u8; 16],
+    quirks: Quirks,
+
+    // The SCHIP screen, 64x32 or 128x64, one byte per pixel.
+    hires: bool,
+    gfx: Vec<u8>,
+
+    mega: bool,
+    palette: [Rgba; 256],
+    sprite_width: usize,
+    sprite_height: usize,
+    alpha: u8,
+    blend: Blend,
+    collision_color: u8,
+    // Sprites go to `pixels`, with the palette index of each pixel kept in
+    // `indices` for collisions; 00E0 copies them to `shown`.
+    pixels: Vec<Rgba>,
+    indices: Vec<u8>,
+    shown: Vec<Rgba>,
+
+    sample: Option<SamplePlayer>,
+    audio: Vec<i16>,
+
+    flags: [u8; FLAG_COUNT],
+    flag_store: Box<dyn FlagStore>,
+
+    // Where the font was put and how long it is.
+    font_start: usize,
+    font_len: usize,
+
+    history: History,
+}
+
+impl Default for MegaChip {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MegaChip {
+    pub fn new() -> Self {
+        MegaChip::with_quirks(Quirks::SCHIP)
+    }
+
+    pub fn with_quirks(quirks: Quirks) -> Self {
+        let mut mega = MegaChip {
+            pc: PROGRAM_START,
+            memory: vec![0; MEMORY_SIZE],
+            v_reg: [0; 16],
+            i_reg: 0,
+            stack: CallStack::new(quirks.stack_depth),
+            delay_timer: 0,
+            sound_timer: 0,
+            keypad: [0; 16],
+            quirks,
+            hires: false,
+            gfx: vec![0; 64 * 32],
+            mega: false,
+            palette: [BLACK; 256],
+            sprite_width: 256,
+            sprite_height: 256,
+            alpha: 255,
+            blend: Blend::Normal,
+            collision_color: 0,
+            pixels: vec![BLACK; WIDTH * HEIGHT],
+            indices: vec![0; WIDTH * HEIGHT],
+            shown: vec![BLACK; WIDTH * HEIGHT],
+            sample: None,
+            audio: Vec::new(),
+            flags: [0; FLAG_COUNT],
+            flag_store: Box::new(NoStore),
+            font_start: FONT_START,
+            font_len: 0,
+            history: History::default(),
+        };
+        mega.set_font(&Font::default(), FONT_START).unwrap();
+        mega
+    }
+
+    // Replaces the font, placing it at `start`; load it before the program.
+    pub fn set_font(&mut self, font: &Font, start: usize) -> Result<(), Chip8Error> {
+        let bytes = font.bytes();
+        if start + bytes.len() > MEMORY_SIZE {
+            return Err(Chip8Error::MemoryOutOfBounds { pc: self.pc as u16, address: start + bytes.len() - 1 });
+        }
+        self.write_memory(start, &bytes);
+        self.font_start = start;
+        self.font_len = bytes.len();
+        Ok(())
+    }
+
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    pub fn i_reg(&self) -> usize {
+        self.i_reg
+    }
+
+    pub fn v_reg(&self) -> &[u8; 16] {
+        &self.v_reg
+    }
+
+    pub fn set_v_reg(&mut self, x: usize, value: u8) {
+        self.v_reg[x] = value;
+    }
+
+    pub fn call_stack(&self) -> &[CallFrame] {
+        self.stack.frames()
+    }
+
+    // Sets how many calls fit on the stack, `None` for no limit, over what
+    // the quirks say. This empties the stack, so do it before running.
+    pub fn set_stack_depth(&mut self, depth: Option<usize>) {
+        self.quirks.stack_depth = depth;
+        self.stack = CallStack::new(depth);
+    }
+
+    pub fn memory(&self) -> &[u8] {
+        &self.memory
+    }
+
+    pub fn write_memory(&mut self, address: usize, bytes: &[u8]) {
+        self.memory[address..address + bytes.len()].copy_from_slice(bytes);
+    }
+
+    // Keeps the RPL flags in `store` from now on, starting from what it holds.
+    pub fn set_flag_store(&mut self, mut store: Box<dyn FlagStore>) -> Result<(), Chip8Error> {
+        self.flags = store.load().map_err(|e| Chip8Error::Io(format!("Error loading RPL flags: {}", e)))?;
+        self.flag_store = store;
+        Ok(())
+    }
+
+    pub fn load_rom(&mut self, path: &str) -> Result<(), Chip8Error> {
+        let rom = fs::read(path).map_err(|e| Chip8Error::Io(format!("Error opening file {}: {}", path, e)))?;
+        self.load_bytes(&rom)
+    }
+
+    pub fn load_bytes(&mut self, rom: &[u8]) -> Result<(), Chip8Error> {
+        if rom.len() > MEMORY_SIZE - PROGRAM_START {
+            return Err(Chip8Error::RomTooLarge { size: rom.len(), max: MEMORY_SIZE - PROGRAM_START });
+        }
+
+        self.write_memory(PROGRAM_START, rom);
+        Ok(())
+    }
+
+    fn width(&self) -> usize {
+        if self.hires { 128 } else { 64 }
+    }
+
+    fn read(&self, address: usize) -> Result<u8, Chip8Error> {
+        self.memory.get(address).copied().ok_or(Chip8Error::MemoryOutOfBounds { pc: self.pc as u16, address })
+    }
+
+    fn write(&mut self, address: usize, value: u8) -> Result<(), Chip8Error> {
+        let pc = self.pc as u16;
+        let byte = self.memory.get_mut(address).ok_or(Chip8Error::MemoryOutOfBounds { pc, address })?;
+        *byte = value;
+        Ok(())
+    }
+
+    fn key(&self, key: u8) -> Result<bool, Chip8Error> {
+        let state = self.keypad.get(key as usize).ok_or(Chip8Error::InvalidKey { pc: self.pc as u16, key })?;
+        Ok(*state != 0)
+    }
+
+    // The 60 Hz frame boundary: ticks the timers and plays a frame's worth
+    // of the current sample.
+    pub fn vblank(&mut self) {
+        self.delay_timer = self.delay_timer.saturating_sub(1);
+        self.sound_timer = self.sound_timer.saturating_sub(1);
+
+        if let Some(sample) = &mut self.sample {
+            sample.render(audio::SAMPLES_PER_FRAME, &mut self.audio);
+            if sample.finished() {
+                self.sample = None;
+            }
+        }
+
+        // Nobody is listening; don't hoard more than a second.
+        if self.audio.len() > audio::SAMPLE_RATE as usize {
+            let excess = self.audio.len() - audio::SAMPLE_RATE as usize;
+            self.audio.drain(..excess);
+        }
+    }
+
+    pub fn interpret(&mut self) -> Result<(), Chip8Error> {
+        let opcode = u16::from_be_bytes([self.read(self.pc)?, self.read(self.pc + 1)?]);
+        self.history.record(self.pc, opcode);
+        let invalid = Chip8Error::InvalidOpcode { pc: self.pc as u16, opcode };
+        let instruction = Instruction::decode_megachip(opcode).ok_or_else(|| invalid.clone())?;
+        let next = self.pc + 2;
+        let skip = |taken: bool| if taken { next + 2 } else { next };
+
+        self.pc = match instruction {
+            Instruction::Cls => {
+                if self.mega {
+                    self.shown.copy_from_slice(&self.pixels);
+                    self.pixels.iter_mut().for_each(|pixel| *pixel = BLACK);
+                    self.indices.iter_mut().for_each(|index| *index = 0);
+                } else {
+                    self.gfx.iter_mut().for_each(|pixel| *pixel = 0);
+                }
+                next
+            },
+
+            Instruction::Ret => self.stack.pop(self.pc, self.quirks.wrap_stack)?.return_address,
+
+            Instruction::Jp(nnn) => nnn as usize,
+
+            Instruction::Call(nnn) => {
+                self.stack.push(CallFrame { call_site: self.pc, return_address: next }, self.quirks.wrap_stack)?;
+                nnn as usize
+            },
+
+            Instruction::SeImm(x, nn) => skip(self.v_reg[x] == nn),
+            Instruction::SneImm(x, nn) => skip(self.v_reg[x] != nn),
+            Instruction::Se(x, y) => skip(self.v_reg[x] == self.v_reg[y]),
+            Instruction::Sne(x, y) => skip(self.v_reg[x] != self.v_reg[y]),
+
+            Instruction::LdImm(x, nn) => {
+                self.v_reg[x] = nn;
+                next
+            },
+
+            Instruction::AddImm(x, nn) => {
+                self.v_reg[x] = self.v_reg[x].wrapping_add(nn);
+                next
+            },
+
+            Instruction::Alu { op, x, y } => {
+                if !alu::execute(&mut self.v_reg, op, x, y, &self.quirks) {
+                    return Err(invalid);
+                }
+                next
+            },
+
+            Instruction::LdI(nnn) => {
+                self.i_reg = nnn as usize;
+                next
+            },
+
+            Instruction::JpV0(nnn) => {
+                let x = (nnn >> 8) as usize;
+                let offset = if self.quirks.jump_vx { self.v_reg[x] } else { self.v_reg[0] };
+                nnn as usize + offset as usize
+            },
+
+            Instruction::Rnd(x, nn) => {
+                self.v_reg[x] = nn & rand::thread_rng().gen_range(0..=255);
+                next
+            },
+
+            Instruction::Drw(x, y, n) => {
+                let (vx, vy) = (self.v_reg[x] as usize, self.v_reg[y] as usize);
+                self.v_reg[0xF] = if self.mega {
+                    self.draw_color(vx, vy, n)? as u8
+                } else {
+                    self.draw_mono(vx, vy, n)?
+                };
+                next
+            },
+
+            Instruction::Skp(x) => skip(self.key(self.v_reg[x])?),
+            Instruction::Sknp(x) => skip(!self.key(self.v_reg[x])?),
+
+            Instruction::LdVxDt(x) => {
+                self.v_reg[x] = self.delay_timer;
+                next
+            },
+
+            Instruction::LdKey(x) => match self.keypad.iter().position(|&state| state != 0) {
+                Some(key) => {
+                    self.v_reg[x] = key as u8;
+                    next
+                },
+                None => self.pc,
+            },
+
+            Instruction::LdDt(x) => {
+                self.delay_timer = self.v_reg[x];
+                next
+            },
+
+            Instruction::LdSt(x) => {
+                self.sound_timer = self.v_reg[x];
+                next
+            },
+
+            Instruction::AddI(x) => {
+                self.i_reg = (self.i_reg + self.v_reg[x] as usize) % MEMORY_SIZE;
+                next
+            },
+
+            Instruction::LdF(x) => {
+                self.i_reg = self.font_start + font::SMALL_GLYPH * (self.v_reg[x] & 0xF) as usize;
+                next
+            },
+
+            Instruction::LdHf(x) => {
+                self.i_reg = self.font_start + font::SMALL_SIZE + font::LARGE_GLYPH * (self.v_reg[x] & 0xF) as usize;
+                next
+            },
+
+            Instruction::Bcd(x) => {
+                for (offset, digit) in alu::bcd(self.v_reg[x]).iter().enumerate() {
+                    self.write(self.i_reg + offset, *digit)?;
+                }
+                next
+            },
+
+            Instruction::Store(x) => {
+                for i in 0..=x {
+                    self.write(self.i_reg + i, self.v_reg[i])?;
+                }
+                if self.quirks.increment_i {
+                    self.i_reg += x + 1;
+                }
+                next
+            },
+
+            Instruction::Load(x) => {
+                for i in 0..=x {
+                    self.v_reg[i] = self.read(self.i_reg + i)?;
+                }
+                if self.quirks.increment_i {
+                    self.i_reg += x + 1;
+                }
+                next
+            },
+
+            Instruction::StoreFlags(x) => {
+                self.flags[..=x].copy_from_slice(&self.v_reg[..=x]);
+                self.flag_store.save(&self.flags)
+                    .map_err(|e| Chip8Error::Io(format!("Error saving RPL flags: {}", e)))?;
+                next
+            },
+
+            Instruction::LoadFlags(x) => {
+                self.v_reg[..=x].copy_from_slice(&self.flags[..=x]);
+                next
+            },
+
+            Instruction::ScrollDown(n) => {
+                self.scroll(0, n as isize);
+                next
+            },
+
+            Instruction::ScrollUp(n) => {
+                self.scroll(0, -(n as isize));
+                next
+            },
+
+            Instruction::ScrollRight => {
+                self.scroll(4, 0);
+                next
+            },
+
+            Instruction::ScrollLeft => {
+                self.scroll(-4, 0);
+                next
+            },
+
+            // There is nothing to return to; stay put like a halted machine.
+            Instruction::Exit => self.pc,
+
+            Instruction::Low | Instruction::High => {
+                self.hires = instruction == Instruction::High;
+                self.gfx = vec![0; self.width() * self.width() / 2];
+                next
+            },
+
+            Instruction::MegaOn | Instruction::MegaOff => {
+                self.mega = instruction == Instruction::MegaOn;
+                self.pixels.iter_mut().for_each(|pixel| *pixel = BLACK);
+                self.indices.iter_mut().for_each(|index| *index = 0);
+                self.shown.iter_mut().for_each(|pixel| *pixel = BLACK);
+                next
+            },
+
+            Instruction::LdIHigh(nn) => {
+                let low = u16::from_be_bytes([self.read(self.pc + 2)?, self.read(self.pc + 3)?]);
+                self.i_reg = (nn as usize) << 16 | low as usize;
+                self.pc + 4
+            },
+
+            // Entries are stored ARGB and fill the palette from index 1, 0
+            // being transparent.
+            Instruction::LdPalette(nn) => {
+                for entry in 0..nn as usize {
+                    let address = self.i_reg + entry * 4;
+                    let [a, r, g, b] = [self.read(address)?, self.read(address + 1)?, self.read(address + 2)?, self.read(address + 3)?];
+                    if let Some(color) = self.palette.get_mut(entry + 1) {
+                        *color = [r, g, b, a];
+                    }
+                }
+                next
+            },
+
+            Instruction::SpriteWidth(nn) => {
+                self.sprite_width = if nn == 0 { 256 } else { nn as usize };
+                next
+            },
+
+            Instruction::SpriteHeight(nn) => {
+                self.sprite_height = if nn == 0 { 256 } else { nn as usize };
+                next
+            },
+
+            Instruction::Alpha(nn) => {
+                self.alpha = nn;
+                next
+            },
+
+            // The sample starts with its rate (16 bits) and length (24 bits)
+            // and a reserved byte, followed by 8 bit unsigned PCM.
+            Instruction::PlaySample(n) => {
+                let header = self.i_reg;
+                let rate = u16::from_be_bytes([self.read(header)?, self.read(header + 1)?]);
+                let length = u32::from_be_bytes([0, self.read(header + 2)?, self.read(header + 3)?, self.read(header + 4)?]) as usize;
+                let start = header + 6;
+                if start + length > MEMORY_SIZE {
+                    return Err(Chip8Error::MemoryOutOfBounds { pc: self.pc as u16, address: start + length });
+                }
+                let data = self.memory[start..start + length].to_vec();
+                self.sample = Some(SamplePlayer::new(data, rate as u32, n == 0));
+                next
+            },
+
+            Instruction::StopSample => {
+                self.sample = None;
+                next
+            },
+
+            Instruction::BlendMode(n) => {
+                self.blend = Blend::from_mode(n).ok_or(invalid)?;
+                next
+            },
+
+            Instruction::CollisionColor(nn) => {
+                self.collision_color = nn;
+                next
+            },
+
+            _ => return Err(invalid),
+        };
+
+        Ok(())
+    }
+
+    // Draws on the SCHIP screen, 16x16 for n = 0 in hires. Returns VF: the
+    // collided rows in hires, whether anything collided in lores.
+    fn draw_mono(&mut self, x: usize, y: usize, n: u8) -> Result<u8, Chip8Error> {
+        let wide = n == 0 && self.hires;
+        let length = if wide { 32 } else { n as usize };
+
+        let mut sprite = Vec::with_capacity(length);
+        for offset in 0..length {
+            sprite.push(self.read(self.i_reg + offset)?);
+        }
+
+        let width = self.width();
+        let collided_rows = display::draw_sprite(&mut self.gfx, width, x, y, &sprite, wide, false);
+        Ok(if self.hires { collided_rows as u8 } else { (collided_rows > 0) as u8 })
+    }
+
+    // Draws a sprite_width x sprite_height block of palette indices from I,
+    // clipped at the edges; index 0 is transparent. Font characters stay
+    // 1 bit sprites, wherever the font was put. Returns whether a pixel of
+    // the collision colour was drawn over.
+    fn draw_color(&mut self, x: usize, y: usize, n: u8) -> Result<bool, Chip8Error> {
+        let font = (self.font_start..self.font_start + self.font_len).contains(&self.i_reg);
+        let (width, height) = if font { (8, n as usize) } else { (self.sprite_width, self.sprite_height) };
+
+        let mut collided = false;
+        for row in 0..height {
+            let py = y + row;
+            if py >= HEIGHT {
+                break;
+            }
+
+            for col in 0..width {
+                let px = x + col;
+                if px >= WIDTH {
+                    break;
+                }
+
+                let (index, color) = if font {
+                    let lit = self.read(self.i_reg + row)? & (0x80 >> col) != 0;
+                    (if lit { FONT_INDEX } else { 0 }, FONT_COLOR)
+                } else {
+                    let index = self.read(self.i_reg + row * width + col)?;
+                    (index, self.palette[index as usize])
+                };
+                if index == 0 {
+                    continue;
+                }
+
+                // Index 0 is empty screen, never something to collide with.
+                let at = py * WIDTH + px;
+                collided |= self.indices[at] != 0 && self.indices[at] == self.collision_color;
+                self.indices[at] = index;
+                self.pixels[at] = self.blend.apply(color, self.pixels[at]);
+            }
+        }
+
+        Ok(collided)
+    }
+
+    fn scroll(&mut self, dx: isize, dy: isize) {
+        if self.mega {
+            display::scroll(&mut self.pixels, WIDTH, dx, dy, BLACK);
+            display::scroll(&mut self.indices, WIDTH, dx, dy, 0);
+        } else {
+            let width = self.width();
+            display::scroll(&mut self.gfx, width, dx, dy, 0);
+        }
+    }
+
+    // What the display shows: the SCHIP screen, or the last picture 00E0
+    // put up, faded by the screen alpha.
+    pub fn frame(&self) -> Frame {
+        if !self.mega {
+            return Frame::monochrome(&self.gfx, self.width());
+        }
+
+        let fade = |channel: u8| (channel as u32 * self.alpha as u32 / 255) as u8;
+        let pixels = self.shown.iter().map(|&[r, g, b, _]| [fade(r), fade(g), fade(b), 255]).collect();
+        Frame { width: WIDTH, height: HEIGHT, pixels }
+    }
+}
+
+fn disassemble(address: usize, opcode: u16) -> String {
+    match Instruction::decode_megachip(opcode) {
+        Some(instruction) => format!("{:06X}: {:04X}  {}", address, opcode, instruction),
+        None => format!("{:06X}: {:04X}  DW {:04X}", address, opcode, opcode),
+    }
+}
+
+impl Machine for MegaChip {
+    fn step(&mut self) -> Result<u32, Chip8Error> {
+        self.interpret().map(|_| 1)
+    }
+
+    fn vblank(&mut self) {
+        MegaChip::vblank(self);
+    }
+
+    fn keypad(&mut self) -> &mut [u8; 16] {
+        &mut self.keypad
+    }
+
+    fn frame(&self) -> Frame {
+        MegaChip::frame(self)
+    }
+
+    // In MegaChip mode whatever isn't black is lit.
+    fn lit_pixels(&self) -> (Vec<u8>, usize) {
+        if !self.mega {
+            return (self.gfx.clone(), self.width());
+        }
+        (self.shown.iter().map(|pixel| (pixel[..3] != BLACK[..3]) as u8).collect(), WIDTH)
+    }
+
+    fn sound_active(&self) -> bool {
+        self.sound_timer > 0
+    }
+
+    fn audio_samples(&mut self) -> Vec<i16> {
+        std::mem::take(&mut self.audio)
+    }
+
+    fn call_stack(&self) -> &[CallFrame] {
+        MegaChip::call_stack(self)
+    }
+
+    fn quirks(&self) -> Option<Quirks> {
+        Some(self.quirks)
+    }
+
+    fn history(&self) -> Vec<String> {
+        self.history.entries().map(|&(address, opcode)| disassemble(address, opcode)).collect()
+    }
+
+    fn listing(&self, before: usize, after: usize) -> Vec<String> {
+        let start = self.pc.saturating_sub(before * 2);
+        let end = (self.pc + after * 2 + 2).min(MEMORY_SIZE);
+        (start..end).step_by(2).map(|address| {
+            let low = self.memory.get(address + 1).copied().unwrap_or(0);
+            disassemble(address, u16::from_be_bytes([self.memory[address], low]))
+        }).collect()
+    }
+
+    fn register_lines(&self) -> Vec<String> {
+        let mut lines = vec![format!("PC {:06X}", self.pc), format!("I  {:06X}", self.i_reg)];
+        for i in 0..8 {
+            lines.push(format!("V{:X} {:02X} V{:X} {:02X}", i, self.v_reg[i], i + 8, self.v_reg[i + 8]));
+        }
+        lines.push(format!("SP {:X}", self.stack.sp()));
+        lines.push(format!("DT {:02X} ST {:02X}", self.delay_timer, self.sound_timer));
+        if self.mega {
+            lines.push(String::from("MEGA"));
+        }
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(program: &[u8], setup: impl FnOnce(&mut MegaChip)) -> MegaChip {
+        let mut mega = MegaChip::new();
+        mega.load_bytes(program).unwrap();
+        setup(&mut mega);
+        while mega.pc < PROGRAM_START + program.len() {
+            mega.interpret().unwrap();
+        }
+        mega
+    }
+
+    #[test]
+    fn long_load_i() {
+        let mega = run(&[0x01, 0x12, 0x34, 0x56], |_| {});
+        assert_eq!(mega.i_reg(), 0x123456);
+        assert_eq!(mega.pc(), 0x204);
+    }
+
+    #[test]
+    fn palette_sprites_show_after_clear() {
+        let program = [
+            0x00, 0x10, // MEGAON
+            0xA3, 0x00, // LD I, 300
+            0x02, 0x02, // LDPAL 02
+            0x03, 0x02, // SPRW 02
+            0x04, 0x01, // SPRH 01
+            0xA3, 0x08, // LD I, 308
+            0xD0, 0x10, // DRW V0, V1, 0
+        ];
+        let mut mega = run(&program, |mega| {
+            mega.write_memory(0x300, &[0xFF, 0xFF, 0x00, 0x00, 0xFF, 0x00, 0x00, 0xFF, 0x01, 0x02]);
+            mega.set_v_reg(0, 10);
+            mega.set_v_reg(1, 20);
+        });
+
+        // Nothing is on screen until 00E0.
+        assert_eq!(mega.frame().pixel(10, 20), BLACK);
+        mega.write_memory(mega.pc, &[0x00, 0xE0]);
+        mega.interpret().unwrap();
+
+        let frame = mega.frame();
+        assert_eq!((frame.width, frame.height), (WIDTH, HEIGHT));
+        assert_eq!(frame.pixel(10, 20), [255, 0, 0, 255]);
+        assert_eq!(frame.pixel(11, 20), [0, 0, 255, 255]);
+        assert_eq!(frame.pixel(12, 20), BLACK);
+    }
+
+    #[test]
+    fn collision_colour() {
+        // Draws the same 1x1 sprite of index 3 twice.
+        let program = [0x00, 0x10, 0x03, 0x01, 0x04, 0x01, 0x09, 0x03, 0xA3, 0x00, 0xD0, 0x00, 0xD0, 0x00];
+        let mega = run(&program[..12], |mega| mega.write_memory(0x300, &[3]));
+        assert_eq!(mega.v_reg()[0xF], 0);
+        let mega = run(&program, |mega| mega.write_memory(0x300, &[3]));
+        assert_eq!(mega.v_reg()[0xF], 1);
+
+        // Without 09nn, drawing on blank screen collides with nothing.
+        let program = [0x00, 0x10, 0x03, 0x01, 0x04, 0x01, 0xA3, 0x00, 0xD0, 0x00];
+        let mega = run(&program, |mega| {
+            mega.write_memory(0x300, &[3]);
+            mega.set_v_reg(0xF, 0x55);
+        });
+        assert_eq!(mega.v_reg()[0xF], 0);
+    }
+
+    #[test]
+    fn font_glyphs_wherever_the_font_is() {
+        let mega = run(&[0x00, 0x10, 0xF0, 0x29, 0xD1, 0x25], |mega| mega.set_font(&Font::default(), 0x400).unwrap());
+        assert_eq!(mega.i_reg(), 0x400);
+        assert_eq!(&mega.indices[..5], &[FONT_INDEX, FONT_INDEX, FONT_INDEX, FONT_INDEX, 0]);
+        assert_eq!(mega.indices[WIDTH + 1], 0);
+    }
+
+    #[test]
+    fn blend_modes() {
+        let red = [200, 0, 0, 255];
+        let grey = [100, 100, 100, 255];
+        assert_eq!(Blend::Normal.apply(red, grey), [200, 0, 0, 255]);
+        assert_eq!(Blend::Half.apply(red, grey), [149, 50, 50, 255]);
+        assert_eq!(Blend::Add.apply(red, grey), [255, 100, 100, 255]);
+        assert_eq!(Blend::Multiply.apply(red, grey), [78, 0, 0, 255]);
+        assert_eq!(Blend::Normal.apply([200, 0, 0, 0], grey), grey);
+    }
+
+    #[test]
+    fn screen_alpha_fades() {
+        let mut mega = run(&[0x00, 0x10, 0x05, 0x80], |_| {});
+        mega.shown[0] = [255, 255, 255, 255];
+        assert_eq!(mega.frame().pixel(0, 0), [128, 128, 128, 255]);
+    }
+
+    #[test]
+    fn sample_plays_once() {
+        let mut mega = run(&[0xA3, 0x00, 0x06, 0x01], |mega| {
+            // 22050 Hz, 4 samples.
+            mega.write_memory(0x300, &[0x56, 0x22, 0x00, 0x00, 0x04, 0x00, 0x80, 0x90, 0xA0, 0xB0]);
+        });
+        mega.vblank();
+        let samples = Machine::audio_samples(&mut mega);
+        assert_eq!(samples, [0, 0, 16 << 8, 16 << 8, 32 << 8, 32 << 8, 48 << 8, 48 << 8]);
+        mega.vblank();
+        assert!(Machine::audio_samples(&mut mega).is_empty());
+    }
+
+    #[test]
+    fn schip_hires_wide_sprite() {
+        let mega = run(&[0x00, 0xFF, 0xA3, 0x00, 0xD0, 0x00], |mega| mega.write_memory(0x300, &[0xFF; 32]));
+        let frame = mega.frame();
+        assert_eq!((frame.width, frame.height), (128, 64));
+        assert_eq!(mega.gfx.iter().filter(|&&pixel| pixel != 0).count(), 256);
+    }
+}
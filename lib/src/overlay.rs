@@ -0,0 +1,192 @@
+use crate::machine::Machine;
+use crate::speed::{SpeedControl, SpeedMode};
+use std::time::{Duration, Instant};
+
+const MESSAGE_TIME: Duration = Duration::from_secs(2);
+const MAX_MESSAGES: usize = 4;
+// Deep recursion would fill the screen, so only the newest calls are listed.
+const MAX_CALLS: usize = 4;
+
+pub const GLYPH_WIDTH: usize = 3;
+pub const GLYPH_HEIGHT: usize = 5;
+
+// A piece of text placed on a grid of character cells.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Text {
+    pub col: usize,
+    pub row: usize,
+    pub text: String,
+}
+
+// Everything drawn on top of the CHIP-8 screen. It only holds text, the
+// frontends decide how to draw it (bitmap font in SDL, plain characters in
+// the terminal), and it never touches `gfx`.
+pub struct Overlay {
+    messages: Vec<(String, Instant)>,
+    show_stats: bool,
+    show_registers: bool,
+
+    speed: String,
+    fps: f64,
+    ips: f64,
+    registers: Vec<String>,
+    // Placed by a script, drawn under everything else.
+    texts: Vec<Text>,
+
+    frames: u32,
+    fps_start: Instant,
+}
+
+impl Overlay {
+    pub fn new() -> Self {
+        Overlay {
+            messages: Vec::new(),
+            show_stats: true,
+            show_registers: false,
+            speed: String::new(),
+            fps: 0.0,
+            ips: 0.0,
+            registers: Vec::new(),
+            texts: Vec::new(),
+            frames: 0,
+            fps_start: Instant::now(),
+        }
+    }
+
+    pub fn message(&mut self, text: &str) {
+        if self.messages.len() == MAX_MESSAGES {
+            self.messages.remove(0);
+        }
+        self.messages.push((String::from(text), Instant::now() + MESSAGE_TIME));
+    }
+
+    pub fn toggle_stats(&mut self) {
+        self.show_stats = !self.show_stats;
+    }
+
+    pub fn toggle_registers(&mut self) {
+        self.show_registers = !self.show_registers;
+    }
+
+    pub fn set_texts(&mut self, texts: Vec<Text>) {
+        self.texts = texts;
+    }
+
+    // Called once per rendered frame.
+    pub fn update(&mut self, speed: &SpeedControl, machine: &dyn Machine) {
+        let now = Instant::now();
+        self.messages.retain(|(_, expires)| *expires > now);
+
+        self.frames += 1;
+        let window = now.duration_since(self.fps_start);
+        if window >= Duration::from_millis(500) {
+            self.fps = self.frames as f64 / window.as_secs_f64();
+            self.frames = 0;
+            self.fps_start = now;
+        }
+
+        let normal = !speed.paused() && speed.mode() == SpeedMode::Normal;
+        self.speed = if normal { String::new() } else { speed.label() };
+        self.ips = speed.ips();
+
+        self.registers.clear();
+        if self.show_registers {
+            self.registers = machine.register_lines();
+            let calls = machine.call_stack().iter().rev().take(MAX_CALLS);
+            self.registers.extend(calls.map(|call| format!("RET {:03X} AT {:03X}", call.return_address, call.call_site)));
+        }
+    }
+
+    // Positions all visible text on a `cols` x `rows` grid.
+    pub fn layout(&self, cols: usize, rows: usize) -> Vec<Text> {
+        let mut texts: Vec<Text> = self.texts.iter().filter(|text| text.row < rows && text.col < cols).cloned().collect();
+
+        if !self.speed.is_empty() {
+            texts.push(Text { col: 0, row: 0, text: self.speed.clone() });
+        }
+
+        let mut right = Vec::new();
+        if self.show_stats {
+            right.push(format!("{:.0} FPS {:.0} IPS", self.fps, self.ips));
+        }
+        right.extend(self.registers.iter().cloned());
+        for (row, text) in right.into_iter().enumerate() {
+            texts.push(Text { col: cols.saturating_sub(text.len()), row, text });
+        }
+
+        let first = rows.saturating_sub(self.messages.len());
+        for (i, (text, _)) in self.messages.iter().enumerate() {
+            texts.push(Text { col: 0, row: first + i, text: text.clone() });
+        }
+
+        texts
+    }
+}
+
+impl Default for Overlay {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// 3x5 bitmap font, one byte per row with the leftmost pixel in bit 2.
+// Lowercase letters are drawn as uppercase.
+pub fn glyph(c: char) -> [u8; GLYPH_HEIGHT] {
+    match c.to_ascii_uppercase() {
+        '0' => [7, 5, 5, 5, 7],
+        '1' => [2, 6, 2, 2, 7],
+        '2' => [7, 1, 7, 4, 7],
+        '3' => [7, 1, 7, 1, 7],
+        '4' => [5, 5, 7, 1, 1],
+        '5' => [7, 4, 7, 1, 7],
+        '6' => [7, 4, 7, 5, 7],
+        '7' => [7, 1, 1, 1, 1],
+        '8' => [7, 5, 7, 5, 7],
+        '9' => [7, 5, 7, 1, 7],
+        'A' => [2, 5, 7, 5, 5],
+        'B' => [6, 5, 6, 5, 6],
+        'C' => [3, 4, 4, 4, 3],
+        'D' => [6, 5, 5, 5, 6],
+        'E' => [7, 4, 6, 4, 7],
+        'F' => [7, 4, 6, 4, 4],
+        'G' => [3, 4, 5, 5, 3],
+        'H' => [5, 5, 7, 5, 5],
+        'I' => [7, 2, 2, 2, 7],
+        'J' => [1, 1, 1, 5, 2],
+        'K' => [5, 5, 6, 5, 5],
+        'L' => [4, 4, 4, 4, 7],
+        'M' => [5, 7, 7, 5, 5],
+        'N' => [6, 5, 5, 5, 5],
+        'O' => [2, 5, 5, 5, 2],
+        'P' => [6, 5, 6, 4, 4],
+        'Q' => [2, 5, 5, 6, 3],
+        'R' => [6, 5, 6, 5, 5],
+        'S' => [3, 4, 2, 1, 6],
+        'T' => [7, 2, 2, 2, 2],
+        'U' => [5, 5, 5, 5, 7],
+        'V' => [5, 5, 5, 5, 2],
+        'W' => [5, 5, 7, 7, 5],
+        'X' => [5, 5, 2, 5, 5],
+        'Y' => [5, 5, 2, 2, 2],
+        'Z' => [7, 1, 2, 4, 7],
+        ' ' => [0, 0, 0, 0, 0],
+        '.' => [0, 0, 0, 0, 2],
+        ',' => [0, 0, 0, 2, 4],
+        ':' => [0, 2, 0, 2, 0],
+        '-' => [0, 0, 7, 0, 0],
+        '+' => [0, 2, 7, 2, 0],
+        '=' => [0, 7, 0, 7, 0],
+        '/' => [1, 1, 2, 4, 4],
+        '%' => [5, 1, 2, 4, 5],
+        '(' => [1, 2, 2, 2, 1],
+        ')' => [4, 2, 2, 2, 4],
+        '[' => [6, 4, 4, 4, 6],
+        ']' => [3, 1, 1, 1, 3],
+        '!' => [2, 2, 2, 0, 2],
+        '|' => [2, 2, 2, 2, 2],
+        '_' => [0, 0, 0, 0, 7],
+        '>' => [4, 2, 1, 2, 4],
+        '<' => [1, 2, 4, 2, 1],
+        _ => [6, 1, 2, 0, 2],
+    }
+}
\ No newline at end of file
@@ -0,0 +1,88 @@
+use crate::display::Frame;
+
+// Just enough PNG to save frames and heatmaps: 8 bit RGBA, no filtering,
+// and deflate's stored blocks in place of compression.
+
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n'];
+
+// Stored deflate blocks hold at most this much.
+const BLOCK_SIZE: usize = 65_535;
+
+pub fn encode(frame: &Frame) -> Vec<u8> {
+    let mut header = Vec::new();
+    header.extend_from_slice(&(frame.width as u32).to_be_bytes());
+    header.extend_from_slice(&(frame.height as u32).to_be_bytes());
+    // Bit depth, RGBA, deflate, adaptive filtering, no interlace.
+    header.extend_from_slice(&[8, 6, 0, 0, 0]);
+
+    // Every row starts with its filter type, 0 for none.
+    let mut raw = Vec::with_capacity((frame.width * 4 + 1) * frame.height);
+    for row in frame.pixels.chunks(frame.width.max(1)) {
+        raw.push(0);
+        raw.extend(row.iter().flatten());
+    }
+
+    let mut out = SIGNATURE.to_vec();
+    chunk(&mut out, b"IHDR", &header);
+    chunk(&mut out, b"IDAT", &zlib_stored(&raw));
+    chunk(&mut out, b"IEND", &[]);
+    out
+}
+
+fn chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = out.len();
+    out.extend_from_slice(kind);
+    out.extend_from_slice(data);
+    let crc = crc32(&out[start..]);
+    out.extend_from_slice(&crc.to_be_bytes());
+}
+
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x78, 0x01];
+    let blocks: Vec<&[u8]> = if data.is_empty() { vec![&[]] } else { data.chunks(BLOCK_SIZE).collect() };
+    for (i, block) in blocks.iter().enumerate() {
+        out.push((i + 1 == blocks.len()) as u8);
+        let len = block.len() as u16;
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, &byte| {
+        (0..8).fold(crc ^ byte as u32, |crc, _| if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 })
+    })
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (a, b) = data.iter().fold((1u32, 0u32), |(a, b), &byte| {
+        let a = (a + byte as u32) % 65_521;
+        (a, (b + a) % 65_521)
+    });
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checksums_and_layout() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+
+        let frame = Frame { width: 2, height: 1, pixels: vec![[255, 0, 0, 255], [0, 0, 255, 128]] };
+        let png = encode(&frame);
+        assert_eq!(&png[..8], &SIGNATURE);
+        assert_eq!(&png[12..16], b"IHDR");
+        assert_eq!(&png[16..24], &[0, 0, 0, 2, 0, 0, 0, 1]);
+        assert_eq!(&png[png.len() - 8..png.len() - 4], b"IEND");
+        // Filter byte and both pixels, stored as they are.
+        let raw = [0, 255, 0, 0, 255, 0, 0, 255, 128];
+        assert!(png.windows(raw.len()).any(|window| window == raw));
+    }
+}
@@ -0,0 +1,223 @@
+use std::collections::{BTreeMap, HashMap};
+use crate::chip8::Variant;
+use crate::instruction;
+
+// Execution profile of a running program. Time is counted in instructions
+// executed, which is what the speed settings budget. Subroutine time follows
+// 2nnn/00EE pairs, so a program that leaves subroutines some other way gets
+// its later time charged to the caller it never returned to.
+
+// A frame that reads the delay timer this often from one place is taken to
+// be spinning on it rather than doing work.
+const BUSY_POLLS: u32 = 3;
+
+// Where nothing was called from.
+const ROOT: usize = 0;
+
+#[derive(Debug, Clone)]
+pub struct Profiler {
+    variant: Variant,
+    counts: Vec<u64>,
+    // The opcode last seen at each address, for the listing.
+    opcodes: Vec<u16>,
+
+    // Call paths as a tree: each node is a subroutine entry below its
+    // parent node. Node 0 is the root.
+    nodes: Vec<(usize, usize)>,
+    children: HashMap<(usize, usize), usize>,
+    self_counts: Vec<u64>,
+    path: Vec<usize>,
+    calls: BTreeMap<usize, u64>,
+
+    instructions: u64,
+    frames: u64,
+    busy_frames: u64,
+    // Fx07 reads this frame, by address.
+    polls: HashMap<usize, u32>,
+    // Busy frames, by the address of the polling read.
+    busy_loops: BTreeMap<usize, u64>,
+}
+
+impl Profiler {
+    pub fn new(memory_size: usize, variant: Variant) -> Self {
+        Profiler {
+            variant,
+            counts: vec![0; memory_size],
+            opcodes: vec![0; memory_size],
+            nodes: vec![(ROOT, 0)],
+            children: HashMap::new(),
+            self_counts: vec![0],
+            path: vec![ROOT],
+            calls: BTreeMap::new(),
+            instructions: 0,
+            frames: 0,
+            busy_frames: 0,
+            polls: HashMap::new(),
+            busy_loops: BTreeMap::new(),
+        }
+    }
+
+    // Called with every instruction about to run.
+    pub fn record(&mut self, pc: usize, opcode: u16) {
+        if let Some(count) = self.counts.get_mut(pc) {
+            *count += 1;
+            self.opcodes[pc] = opcode;
+        }
+        self.instructions += 1;
+        let node = *self.path.last().unwrap();
+        self.self_counts[node] += 1;
+
+        if opcode & 0xF000 == 0x2000 {
+            let entry = (opcode & 0x0FFF) as usize;
+            *self.calls.entry(entry).or_insert(0) += 1;
+            let next = self.nodes.len();
+            let child = *self.children.entry((node, entry)).or_insert(next);
+            if child == next {
+                self.nodes.push((node, entry));
+                self.self_counts.push(0);
+            }
+            self.path.push(child);
+        } else if opcode == 0x00EE && self.path.len() > 1 {
+            self.path.pop();
+        } else if opcode & 0xF0FF == 0xF007 {
+            *self.polls.entry(pc).or_insert(0) += 1;
+        }
+    }
+
+    // Called at every vertical blank.
+    pub fn end_frame(&mut self) {
+        self.frames += 1;
+        let spinning = self.polls.iter().filter(|(_, &polls)| polls >= BUSY_POLLS).map(|(&pc, _)| pc).min();
+        if let Some(pc) = spinning {
+            self.busy_frames += 1;
+            *self.busy_loops.entry(pc).or_insert(0) += 1;
+        }
+        self.polls.clear();
+    }
+
+    pub fn count(&self, pc: usize) -> u64 {
+        self.counts[pc]
+    }
+
+    pub fn busy_frames(&self) -> u64 {
+        self.busy_frames
+    }
+
+    // Subroutine entries from the root down to `node`.
+    fn stack(&self, mut node: usize) -> Vec<usize> {
+        let mut entries = Vec::new();
+        while node != ROOT {
+            let (parent, entry) = self.nodes[node];
+            entries.push(entry);
+            node = parent;
+        }
+        entries.reverse();
+        entries
+    }
+
+    // Instructions run in each subroutine itself and including what it
+    // called, by entry address.
+    pub fn subroutines(&self) -> BTreeMap<usize, (u64, u64)> {
+        let mut times = BTreeMap::new();
+        for node in 1..self.nodes.len() {
+            let stack = self.stack(node);
+            let own = self.self_counts[node];
+            times.entry(*stack.last().unwrap()).or_insert((0, 0)).0 += own;
+            // Recursion would count the same time more than once.
+            let mut seen: Vec<usize> = Vec::new();
+            for entry in stack {
+                if !seen.contains(&entry) {
+                    seen.push(entry);
+                    times.entry(entry).or_insert((0, 0)).1 += own;
+                }
+            }
+        }
+        times
+    }
+
+    // gprof-style: totals, the hottest addresses, subroutines and the loops
+    // that spin on the delay timer.
+    pub fn flat(&self) -> String {
+        let total = self.instructions.max(1) as f64;
+        let percent = |count: u64| 100.0 * count as f64 / total;
+
+        let mut out = format!(
+            "{} instructions over {} frames, {} of them busy-waiting on the delay timer\n",
+            self.instructions, self.frames, self.busy_frames);
+
+        out += "\nInstructions\n     count       %  address\n";
+        let mut hot: Vec<usize> = (0..self.counts.len()).filter(|&pc| self.counts[pc] > 0).collect();
+        hot.sort_by_key(|&pc| (std::cmp::Reverse(self.counts[pc]), pc));
+        for pc in hot {
+            let line = instruction::disassemble(&self.opcodes[pc].to_be_bytes(), pc as u16, self.variant).remove(0);
+            out += &format!("{:>10} {:>6.2}%  {}\n", self.counts[pc], percent(self.counts[pc]), line);
+        }
+
+        out += "\nSubroutines\n      self       %      total       %     calls  entry\n";
+        let mut subroutines: Vec<(usize, (u64, u64))> = self.subroutines().into_iter().collect();
+        subroutines.sort_by_key(|&(entry, (_, inclusive))| (std::cmp::Reverse(inclusive), entry));
+        for (entry, (own, inclusive)) in subroutines {
+            out += &format!("{:>10} {:>6.2}% {:>10} {:>6.2}% {:>9}  {:03X}\n",
+                own, percent(own), inclusive, percent(inclusive), self.calls.get(&entry).copied().unwrap_or(0), entry);
+        }
+
+        out += "\nBusy-wait loops\n    frames  Fx07 at\n";
+        for (pc, frames) in &self.busy_loops {
+            out += &format!("{:>10}  {:03X}\n", frames, pc);
+        }
+        out
+    }
+
+    // One line per call path with the instructions run there, as flame
+    // graph tools take them: "main;sub_2A0;sub_31C 1234".
+    pub fn folded(&self) -> String {
+        let mut lines: Vec<String> = (0..self.nodes.len()).filter(|&node| self.self_counts[node] > 0).map(|node| {
+            let mut names = vec![String::from("main")];
+            names.extend(self.stack(node).iter().map(|entry| format!("sub_{:03X}", entry)));
+            format!("{} {}", names.join(";"), self.self_counts[node])
+        }).collect();
+        lines.sort();
+        lines.iter().map(|line| format!("{}\n", line)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn attributes_subroutine_time() {
+        let mut profiler = Profiler::new(4096, Variant::Chip8);
+        // main calls 300 twice, and 300 calls 400 once.
+        let trace = [
+            (0x200, 0x2300), (0x300, 0x6001), (0x302, 0x2400), (0x400, 0x00EE), (0x304, 0x00EE),
+            (0x202, 0x2300), (0x300, 0x6001), (0x302, 0x00EE), (0x204, 0x1204),
+        ];
+        trace.iter().for_each(|&(pc, opcode)| profiler.record(pc, opcode));
+
+        assert_eq!(profiler.count(0x300), 2);
+        let subroutines = profiler.subroutines();
+        assert_eq!(subroutines[&0x300], (5, 6));
+        assert_eq!(subroutines[&0x400], (1, 1));
+        assert_eq!(profiler.folded(), "main 3\nmain;sub_300 5\nmain;sub_300;sub_400 1\n");
+
+        let flat = profiler.flat();
+        assert!(flat.starts_with("9 instructions over 0 frames"));
+        assert!(flat.contains("         2  22.22%  300: 6001  LD V0, 01\n"));
+        assert!(flat.contains("         5  55.56%          6  66.67%         2  300\n"));
+    }
+
+    #[test]
+    fn counts_busy_wait_frames() {
+        let mut profiler = Profiler::new(4096, Variant::Chip8);
+        for _ in 0..5 {
+            [(0x210, 0xF007), (0x212, 0x3000), (0x214, 0x1210)].iter().for_each(|&(pc, opcode)| profiler.record(pc, opcode));
+        }
+        profiler.end_frame();
+        profiler.record(0x210, 0xF007);
+        profiler.end_frame();
+
+        assert_eq!(profiler.busy_frames(), 1);
+        assert!(profiler.flat().contains("Busy-wait loops\n    frames  Fx07 at\n         1  210\n"));
+    }
+}
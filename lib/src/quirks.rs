@@ -0,0 +1,80 @@
+use crate::stack;
+
+// Behaviours that differ between CHIP-8 interpreters. Each flag is named
+// after what happens when it is enabled.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Quirks {
+    // 8xy1/8xy2/8xy3 clear VF.
+    pub vf_reset: bool,
+    // 8xy6/8xyE shift Vx in place instead of loading the shifted Vy.
+    pub shift_vx: bool,
+    // Fx55/Fx65 leave I pointing past the last register transferred.
+    pub increment_i: bool,
+    // Bnnn becomes Bxnn and jumps to xnn + Vx instead of nnn + V0.
+    pub jump_vx: bool,
+    // Dxyn waits for the next vertical blank before drawing, which limits
+    // games to 60 sprites per second.
+    pub display_wait: bool,
+    // Dxyn wraps pixels that run off the screen edges around to the other
+    // side instead of clipping them.
+    pub wrap_sprites: bool,
+    // A CALL on a full stack and a RET on an empty one wrap the stack
+    // pointer around instead of stopping with an error.
+    pub wrap_stack: bool,
+    // How many calls fit on the stack, None for no limit.
+    pub stack_depth: Option<usize>,
+}
+
+impl Quirks {
+    // The original COSMAC VIP interpreter.
+    pub const CHIP8: Quirks = Quirks {
+        vf_reset: true,
+        shift_vx: false,
+        increment_i: true,
+        jump_vx: false,
+        display_wait: true,
+        wrap_sprites: false,
+        wrap_stack: false,
+        stack_depth: Some(stack::VIP_DEPTH),
+    };
+
+    // SUPER-CHIP 1.1 on the HP-48.
+    pub const SCHIP: Quirks = Quirks {
+        vf_reset: false,
+        shift_vx: true,
+        increment_i: false,
+        jump_vx: true,
+        display_wait: false,
+        wrap_sprites: false,
+        wrap_stack: false,
+        stack_depth: Some(stack::DEFAULT_DEPTH),
+    };
+
+    // Octo's XO-CHIP.
+    pub const XO_CHIP: Quirks = Quirks {
+        vf_reset: false,
+        shift_vx: false,
+        increment_i: true,
+        jump_vx: false,
+        display_wait: false,
+        wrap_sprites: true,
+        wrap_stack: false,
+        stack_depth: Some(stack::DEFAULT_DEPTH),
+    };
+
+    pub const PRESETS: [(&'static str, Quirks); 3] = [
+        ("chip8", Quirks::CHIP8),
+        ("schip", Quirks::SCHIP),
+        ("xochip", Quirks::XO_CHIP),
+    ];
+
+    pub fn from_name(name: &str) -> Option<Quirks> {
+        Quirks::PRESETS.iter().find(|(preset, _)| *preset == name).map(|(_, quirks)| *quirks)
+    }
+}
+
+impl Default for Quirks {
+    fn default() -> Self {
+        Quirks::CHIP8
+    }
+}
\ No newline at end of file
@@ -0,0 +1,110 @@
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use crate::rom;
+
+// SCHIP's RPL user flags: on the HP-48 they lived in calculator memory and
+// survived power cycles, so games keep high scores there. SCHIP programs use
+// 8 of them, XO-CHIP ones all 16.
+
+pub const FLAG_COUNT: usize = 16;
+
+// Where the flags live between sessions.
+pub trait FlagStore: fmt::Debug {
+    // The saved flags, all zero when nothing was saved yet.
+    fn load(&mut self) -> io::Result<[u8; FLAG_COUNT]>;
+
+    fn save(&mut self, flags: &[u8; FLAG_COUNT]) -> io::Result<()>;
+}
+
+// Keeps nothing: flags last as long as the machine does.
+#[derive(Debug, Default)]
+pub struct NoStore;
+
+impl FlagStore for NoStore {
+    fn load(&mut self) -> io::Result<[u8; FLAG_COUNT]> {
+        Ok([0; FLAG_COUNT])
+    }
+
+    fn save(&mut self, _flags: &[u8; FLAG_COUNT]) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// One small file per ROM, named after the ROM's hash so renaming or moving
+// the ROM keeps its scores.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileStore {
+    path: PathBuf,
+}
+
+impl FileStore {
+    pub fn new(path: PathBuf) -> Self {
+        FileStore { path }
+    }
+
+    pub fn for_rom(dir: &Path, rom: &[u8]) -> Self {
+        FileStore::new(dir.join(format!("{}.flags", rom::hash(rom))))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl FlagStore for FileStore {
+    fn load(&mut self) -> io::Result<[u8; FLAG_COUNT]> {
+        let mut flags = [0; FLAG_COUNT];
+        match fs::read(&self.path) {
+            Ok(bytes) => {
+                let count = bytes.len().min(FLAG_COUNT);
+                flags[..count].copy_from_slice(&bytes[..count]);
+                Ok(flags)
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(flags),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn save(&mut self, flags: &[u8; FLAG_COUNT]) -> io::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(&self.path, flags)
+    }
+}
+
+// The per-user data directory for saved flags: $XDG_DATA_HOME, else
+// ~/.local/share, else %APPDATA%, else the working directory.
+pub fn data_dir() -> PathBuf {
+    let base = env::var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".local").join("share")))
+        .or_else(|| env::var_os("APPDATA").map(PathBuf::from))
+        .unwrap_or_else(|| PathBuf::from("."));
+    base.join("rc8emu").join("flags")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_store_round_trip() {
+        let dir = env::temp_dir().join(format!("rc8emu-flags-{}", std::process::id()));
+        let mut store = FileStore::for_rom(&dir, b"rom");
+        assert_eq!(store.path(), dir.join(format!("{}.flags", rom::hash(b"rom"))));
+        assert_eq!(store.load().unwrap(), [0; FLAG_COUNT]);
+
+        let mut flags = [0; FLAG_COUNT];
+        flags[0] = 42;
+        flags[7] = 7;
+        store.save(&flags).unwrap();
+        assert_eq!(FileStore::for_rom(&dir, b"rom").load().unwrap(), flags);
+        assert_eq!(FileStore::for_rom(&dir, b"other rom").load().unwrap(), [0; FLAG_COUNT]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,491 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs;
+use std::rc::Rc;
+use rhai::{Dynamic, Engine, EvalAltResult, FnPtr, Scope, AST, INT};
+use crate::chip8::{Chip8, Chip8Error};
+use crate::display::{Frame, Rgba};
+use crate::machine::Machine;
+use crate::overlay::Text;
+
+// Rhai scripts driving a CHIP-8 machine, for automated tests and bots. A
+// script's top level runs once on load and registers callbacks:
+//
+//   on_frame(|| ...)              after every 60 Hz frame
+//   on_pc(0x2A4, || ...)          before the instruction at an address runs
+//   on_write(0x3F0, |addr, v| ...) after the program stores to an address
+//   on_sound(|| ...)              when the buzzer starts
+//
+// Callbacks see the machine through peek/poke, v/set_v, i/set_i, pc/set_pc,
+// delay/set_delay, sound/set_sound, key, press/release and frames, and draw
+// over the screen with pixel, rect, line (screen pixels, 0xRRGGBB colours),
+// text (overlay cells) and clear. print() goes to the overlay.
+//
+// The machine is copied in before a callback and its changes copied back
+// after, so a callback sees its own writes but not the program running.
+
+const MEMORY_SIZE: usize = 4096;
+
+type ScriptResult<T> = Result<T, Box<EvalAltResult>>;
+
+#[derive(Debug, Clone, Default)]
+struct Hooks {
+    frame: Vec<FnPtr>,
+    pc: HashMap<u16, Vec<FnPtr>>,
+    write: HashMap<usize, Vec<FnPtr>>,
+    sound: Vec<FnPtr>,
+}
+
+// What callbacks work on: a copy of the machine plus what they changed.
+#[derive(Debug, Clone)]
+struct View {
+    memory: Vec<u8>,
+    v_reg: [u8; 16],
+    i_reg: u16,
+    pc: u16,
+    delay_timer: u8,
+    sound_timer: u8,
+    keypad: [u8; 16],
+    writes: Vec<(usize, u8)>,
+    registers_changed: bool,
+}
+
+impl View {
+    fn new() -> Self {
+        View {
+            memory: vec![0; MEMORY_SIZE],
+            v_reg: [0; 16],
+            i_reg: 0,
+            pc: 0,
+            delay_timer: 0,
+            sound_timer: 0,
+            keypad: [0; 16],
+            writes: Vec::new(),
+            registers_changed: false,
+        }
+    }
+
+    fn copy_from(&mut self, c8: &mut Chip8) {
+        self.memory.copy_from_slice(c8.memory());
+        self.v_reg = *c8.v_reg();
+        self.i_reg = c8.i_reg();
+        self.pc = c8.pc();
+        self.delay_timer = c8.delay_timer();
+        self.sound_timer = c8.sound_timer;
+        self.keypad = *Machine::keypad(c8);
+        self.writes.clear();
+        self.registers_changed = false;
+    }
+
+    fn copy_to(&mut self, c8: &mut Chip8) {
+        for (address, value) in self.writes.drain(..) {
+            c8.write_memory(address, &[value]);
+        }
+        if self.registers_changed {
+            for (x, &value) in self.v_reg.iter().enumerate() {
+                c8.set_v_reg(x, value);
+            }
+            c8.set_i_reg(self.i_reg);
+            c8.set_pc(self.pc);
+            c8.set_delay_timer(self.delay_timer);
+            c8.sound_timer = self.sound_timer;
+            self.registers_changed = false;
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct Drawing {
+    pixels: Vec<(INT, INT, Rgba)>,
+    texts: Vec<Text>,
+}
+
+impl Drawing {
+    fn plot(&mut self, x: INT, y: INT, color: INT) {
+        self.pixels.push((x, y, rgba(color)));
+    }
+}
+
+// Shared between the script's functions and `Script`.
+struct Host {
+    view: View,
+    hooks: Hooks,
+    drawing: Drawing,
+    // Keys the script holds down.
+    held: [bool; 16],
+    messages: Vec<String>,
+    frames: INT,
+}
+
+pub struct Script {
+    path: String,
+    engine: Engine,
+    ast: AST,
+    host: Rc<RefCell<Host>>,
+    logging_writes: bool,
+    sounding: bool,
+    // Keys `hold_keys` pressed last time, to let go of once released.
+    pressed: [bool; 16],
+}
+
+impl Script {
+    // Loads the script at `path` and runs its top level against `machine`,
+    // which has to be a CHIP-8.
+    pub fn load(path: &str, machine: &mut dyn Machine) -> Result<Self, Chip8Error> {
+        let host = Rc::new(RefCell::new(Host {
+            view: View::new(),
+            hooks: Hooks::default(),
+            drawing: Drawing::default(),
+            held: [false; 16],
+            messages: Vec::new(),
+            frames: 0,
+        }));
+        let mut script = Script {
+            path: String::from(path),
+            engine: engine(&host),
+            ast: AST::empty(),
+            host,
+            logging_writes: false,
+            sounding: false,
+            pressed: [false; 16],
+        };
+        script.reload(machine)?;
+        Ok(script)
+    }
+
+    // Reads the file again and starts over with its callbacks. A script
+    // that doesn't compile leaves the old one running.
+    pub fn reload(&mut self, machine: &mut dyn Machine) -> Result<(), Chip8Error> {
+        let c8 = machine.as_chip8().ok_or_else(|| Chip8Error::Io(String::from("Scripts only run on the chip8 machine")))?;
+        let text = fs::read_to_string(&self.path)
+            .map_err(|e| Chip8Error::Io(format!("Error opening script {}: {}", self.path, e)))?;
+        let ast = self.engine.compile(&text).map_err(|e| Chip8Error::Io(format!("Script {}: {}", self.path, e)))?;
+
+        {
+            let mut host = self.host.borrow_mut();
+            host.hooks = Hooks::default();
+            host.drawing = Drawing::default();
+            host.held = [false; 16];
+            host.frames = 0;
+        }
+        self.ast = ast;
+        self.sounding = Machine::sound_active(c8);
+        self.host.borrow_mut().view.copy_from(c8);
+        let result = self.engine.run_ast_with_scope(&mut Scope::new(), &self.ast);
+        self.finish(c8);
+        result.map_err(|e| Chip8Error::Io(format!("Script {}: {}", self.path, e)))
+    }
+
+    // Runs the callbacks for the instruction about to run.
+    pub fn before_step(&mut self, machine: &mut dyn Machine) {
+        let c8 = match machine.as_chip8() {
+            Some(c8) => c8,
+            None => return,
+        };
+        let hooks = match self.host.borrow().hooks.pc.get(&c8.pc()) {
+            Some(hooks) => hooks.clone(),
+            None => return,
+        };
+        self.run(c8, &hooks, Vec::new);
+    }
+
+    // Runs the callbacks for what the last instruction did.
+    pub fn after_step(&mut self, machine: &mut dyn Machine) {
+        let c8 = match machine.as_chip8() {
+            Some(c8) => c8,
+            None => return,
+        };
+        if self.logging_writes {
+            for (address, value) in c8.take_writes() {
+                let hooks = self.host.borrow().hooks.write.get(&address).cloned();
+                if let Some(hooks) = hooks {
+                    self.run(c8, &hooks, || vec![Dynamic::from(address as INT), Dynamic::from(value as INT)]);
+                }
+            }
+        }
+        let sounding = Machine::sound_active(c8);
+        if sounding && !self.sounding {
+            let hooks = self.host.borrow().hooks.sound.clone();
+            self.run(c8, &hooks, Vec::new);
+        }
+        self.sounding = sounding;
+    }
+
+    pub fn end_frame(&mut self, machine: &mut dyn Machine) {
+        let c8 = match machine.as_chip8() {
+            Some(c8) => c8,
+            None => return,
+        };
+        self.host.borrow_mut().frames += 1;
+        let hooks = self.host.borrow().hooks.frame.clone();
+        self.run(c8, &hooks, Vec::new);
+    }
+
+    // Presses the keys the script is holding, over whatever the frontend
+    // left in `keypad`, and lets go of the ones it released since last time.
+    pub fn hold_keys(&mut self, keypad: &mut [u8; 16]) {
+        let held = self.host.borrow().held;
+        for (key, (&held, pressed)) in keypad.iter_mut().zip(held.iter().zip(self.pressed.iter_mut())) {
+            if held {
+                *key = 1;
+            } else if *pressed {
+                *key = 0;
+            }
+            *pressed = held;
+        }
+    }
+
+    // Puts what the script drew on `frame`.
+    pub fn draw(&self, frame: &mut Frame) {
+        for &(x, y, color) in &self.host.borrow().drawing.pixels {
+            if x >= 0 && y >= 0 && (x as usize) < frame.width && (y as usize) < frame.height {
+                frame.pixels[y as usize * frame.width + x as usize] = color;
+            }
+        }
+    }
+
+    pub fn texts(&self) -> Vec<Text> {
+        self.host.borrow().drawing.texts.clone()
+    }
+
+    // print() output and errors from callbacks since the last call.
+    pub fn take_messages(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.host.borrow_mut().messages)
+    }
+
+    fn run(&mut self, c8: &mut Chip8, hooks: &[FnPtr], args: impl Fn() -> Vec<Dynamic>) {
+        if hooks.is_empty() {
+            return;
+        }
+        self.host.borrow_mut().view.copy_from(c8);
+        for hook in hooks {
+            if let Err(e) = hook.call::<Dynamic>(&self.engine, &self.ast, args()) {
+                // Rhai adds the call chain on further lines; the overlay has
+                // room for one.
+                let error = e.to_string();
+                self.host.borrow_mut().messages.push(format!("Script error: {}", error.lines().next().unwrap_or("")));
+            }
+        }
+        self.finish(c8);
+    }
+
+    // Hands what the script changed back to the machine.
+    fn finish(&mut self, c8: &mut Chip8) {
+        let mut host = self.host.borrow_mut();
+        host.view.copy_to(c8);
+        let logging_writes = !host.hooks.write.is_empty();
+        if logging_writes != self.logging_writes {
+            c8.log_writes(logging_writes);
+            self.logging_writes = logging_writes;
+        }
+    }
+}
+
+fn rgba(color: INT) -> Rgba {
+    let [_, _, _, _, _, r, g, b] = color.to_be_bytes();
+    [r, g, b, 255]
+}
+
+fn address(address: INT) -> ScriptResult<usize> {
+    if (0..MEMORY_SIZE as INT).contains(&address) {
+        Ok(address as usize)
+    } else {
+        Err(format!("Address out of range: {:#x}", address).into())
+    }
+}
+
+fn index(i: INT, what: &str) -> ScriptResult<usize> {
+    if (0..16).contains(&i) {
+        Ok(i as usize)
+    } else {
+        Err(format!("No {} {}", what, i).into())
+    }
+}
+
+fn engine(host: &Rc<RefCell<Host>>) -> Engine {
+    let mut engine = Engine::new();
+
+    let h = host.clone();
+    engine.on_print(move |text| h.borrow_mut().messages.push(String::from(text)));
+
+    let h = host.clone();
+    engine.register_fn("peek", move |a: INT| -> ScriptResult<INT> { Ok(h.borrow().view.memory[address(a)?] as INT) });
+    let h = host.clone();
+    engine.register_fn("poke", move |a: INT, value: INT| -> ScriptResult<()> {
+        let a = address(a)?;
+        let mut host = h.borrow_mut();
+        host.view.memory[a] = value as u8;
+        host.view.writes.push((a, value as u8));
+        Ok(())
+    });
+
+    let h = host.clone();
+    engine.register_fn("v", move |x: INT| -> ScriptResult<INT> { Ok(h.borrow().view.v_reg[index(x, "register V")?] as INT) });
+    let h = host.clone();
+    engine.register_fn("set_v", move |x: INT, value: INT| -> ScriptResult<()> {
+        let x = index(x, "register V")?;
+        let mut host = h.borrow_mut();
+        host.view.v_reg[x] = value as u8;
+        host.view.registers_changed = true;
+        Ok(())
+    });
+
+    // The rest of the registers only differ in where they live and how wide
+    // they are.
+    macro_rules! register {
+        ($get:literal, $set:literal, $field:ident, $ty:ty) => {
+            let h = host.clone();
+            engine.register_fn($get, move || h.borrow().view.$field as INT);
+            let h = host.clone();
+            engine.register_fn($set, move |value: INT| {
+                let mut host = h.borrow_mut();
+                host.view.$field = value as $ty;
+                host.view.registers_changed = true;
+            });
+        };
+    }
+    register!("i", "set_i", i_reg, u16);
+    register!("pc", "set_pc", pc, u16);
+    register!("delay", "set_delay", delay_timer, u8);
+    register!("sound", "set_sound", sound_timer, u8);
+
+    let h = host.clone();
+    engine.register_fn("key", move |k: INT| -> ScriptResult<bool> { Ok(h.borrow().view.keypad[index(k, "key")?] != 0) });
+    let h = host.clone();
+    engine.register_fn("press", move |k: INT| -> ScriptResult<()> {
+        h.borrow_mut().held[index(k, "key")?] = true;
+        Ok(())
+    });
+    let h = host.clone();
+    engine.register_fn("release", move |k: INT| -> ScriptResult<()> {
+        h.borrow_mut().held[index(k, "key")?] = false;
+        Ok(())
+    });
+    let h = host.clone();
+    engine.register_fn("frames", move || h.borrow().frames);
+
+    let h = host.clone();
+    engine.register_fn("on_frame", move |f: FnPtr| h.borrow_mut().hooks.frame.push(f));
+    let h = host.clone();
+    engine.register_fn("on_pc", move |a: INT, f: FnPtr| -> ScriptResult<()> {
+        let a = address(a)? as u16;
+        h.borrow_mut().hooks.pc.entry(a).or_default().push(f);
+        Ok(())
+    });
+    let h = host.clone();
+    engine.register_fn("on_write", move |a: INT, f: FnPtr| -> ScriptResult<()> {
+        let a = address(a)?;
+        h.borrow_mut().hooks.write.entry(a).or_default().push(f);
+        Ok(())
+    });
+    let h = host.clone();
+    engine.register_fn("on_sound", move |f: FnPtr| h.borrow_mut().hooks.sound.push(f));
+
+    let h = host.clone();
+    engine.register_fn("pixel", move |x: INT, y: INT, color: INT| h.borrow_mut().drawing.plot(x, y, color));
+    let h = host.clone();
+    engine.register_fn("rect", move |x: INT, y: INT, width: INT, height: INT, color: INT| {
+        let mut host = h.borrow_mut();
+        for y in y..y + height {
+            for x in x..x + width {
+                host.drawing.plot(x, y, color);
+            }
+        }
+    });
+    let h = host.clone();
+    engine.register_fn("line", move |x0: INT, y0: INT, x1: INT, y1: INT, color: INT| {
+        let mut host = h.borrow_mut();
+        let steps = (x1 - x0).abs().max((y1 - y0).abs()).max(1);
+        for step in 0..=steps {
+            host.drawing.plot(x0 + (x1 - x0) * step / steps, y0 + (y1 - y0) * step / steps, color);
+        }
+    });
+    let h = host.clone();
+    engine.register_fn("text", move |col: INT, row: INT, text: &str| {
+        if col >= 0 && row >= 0 {
+            h.borrow_mut().drawing.texts.push(Text { col: col as usize, row: row as usize, text: String::from(text) });
+        }
+    });
+    let h = host.clone();
+    engine.register_fn("clear", move || h.borrow_mut().drawing = Drawing::default());
+
+    engine
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn write_script(name: &str, text: &str) -> String {
+        let path = env::temp_dir().join(format!("rc8emu-{}-{}.rhai", name, std::process::id()));
+        fs::write(&path, text).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn hooks_see_and_change_the_machine() {
+        let mut c8 = Chip8::new();
+        // 200: LD V0, 05; 202: LD I, 300; 204: LD [I], V0; 206: LD ST, V0; 208: JP 208
+        c8.load_bytes(&[0x60, 0x05, 0xA3, 0x00, 0xF0, 0x55, 0xF0, 0x18, 0x12, 0x08]).unwrap();
+        let path = write_script("hooks", "
+            poke(0x400, 7);
+            on_pc(0x202, || set_v(1, v(0) + 1));
+            on_write(0x300, |a, value| print(`stored ${value} at ${a}`));
+            on_sound(|| press(0xA));
+            on_frame(|| { clear(); pixel(1, 0, 0xFF0000); text(0, 1, `F${frames()}`); });
+        ");
+        let mut script = Script::load(&path, &mut c8).unwrap();
+        assert_eq!(c8.memory()[0x400], 7);
+
+        for _ in 0..5 {
+            script.before_step(&mut c8);
+            c8.step().unwrap();
+            script.after_step(&mut c8);
+        }
+        c8.vblank();
+        script.end_frame(&mut c8);
+
+        assert_eq!(c8.v_reg()[1], 6);
+        assert_eq!(script.take_messages(), ["stored 5 at 768"]);
+        let mut keypad = [0; 16];
+        script.hold_keys(&mut keypad);
+        assert_eq!(keypad[0xA], 1);
+        let mut frame = c8.frame();
+        script.draw(&mut frame);
+        assert_eq!(frame.pixel(1, 0), [255, 0, 0, 255]);
+        assert_eq!(script.texts(), [Text { col: 0, row: 1, text: String::from("F1") }]);
+
+        fs::write(&path, "on_frame(|| peek(0x1000));").unwrap();
+        script.reload(&mut c8).unwrap();
+        assert!(script.texts().is_empty());
+        script.end_frame(&mut c8);
+        assert_eq!(script.take_messages(), ["Script error: Runtime error: Address out of range: 0x1000 (line 1, position 13)"]);
+        fs::write(&path, "on_frame(||").unwrap();
+        assert!(script.reload(&mut c8).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn released_keys_go_up() {
+        let mut c8 = Chip8::new();
+        c8.load_bytes(&[0x12, 0x00]).unwrap();
+        let path = write_script("release", "on_frame(|| if frames() == 1 { press(5) } else { release(5) });");
+        let mut script = Script::load(&path, &mut c8).unwrap();
+        let mut keypad = [0; 16];
+        keypad[2] = 1;
+
+        script.end_frame(&mut c8);
+        script.hold_keys(&mut keypad);
+        assert_eq!((keypad[5], keypad[2]), (1, 1));
+        script.end_frame(&mut c8);
+        script.hold_keys(&mut keypad);
+        assert_eq!((keypad[5], keypad[2]), (0, 1));
+        // Keys the script never pressed are the frontend's to let go of.
+        keypad[5] = 1;
+        script.end_frame(&mut c8);
+        script.hold_keys(&mut keypad);
+        assert_eq!(keypad[5], 1);
+        fs::remove_file(&path).unwrap();
+    }
+}
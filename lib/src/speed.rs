@@ -0,0 +1,200 @@
+use crate::frontend::Hotkey;
+use std::time::{Duration, Instant};
+
+const FRAME: Duration = Duration::from_micros(16_667);
+const METER_WINDOW: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SpeedMode {
+    Normal,
+    FastForward,
+    Turbo,
+    SlowMotion(u32),
+}
+
+// What the interpreter is asked to do next: run one instruction, or end the
+// current 60 Hz frame.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Tick {
+    Instruction,
+    Vblank,
+}
+
+// Paces the interpreter against the wall clock. Each instruction has a cost
+// and every 60 Hz frame has a budget of `frame_budget` cost units at normal
+// speed; everything else is a factor applied on top of that. With `new` every
+// instruction costs one unit, with `with_frame_budget` the units can be
+// anything the step function reports, such as machine cycles.
+pub struct SpeedControl {
+    frame_budget: u32,
+    ff_multiplier: u32,
+    mode: SpeedMode,
+    paused: bool,
+    frame_steps: u32,
+    frame_position: u32,
+    credit: f64,
+    last: Instant,
+
+    meter_start: Instant,
+    meter_count: u64,
+    ips: f64,
+}
+
+impl SpeedControl {
+    // `delay` is the number of milliseconds one instruction takes.
+    pub fn new(delay: u128, ff_multiplier: u32) -> Self {
+        let budget = (FRAME.as_secs_f64() * 1000.0 / delay.max(1) as f64).round() as u32;
+        SpeedControl::with_frame_budget(budget, ff_multiplier)
+    }
+
+    pub fn with_frame_budget(frame_budget: u32, ff_multiplier: u32) -> Self {
+        let now = Instant::now();
+        SpeedControl {
+            frame_budget: frame_budget.max(1),
+            ff_multiplier: ff_multiplier.max(1),
+            mode: SpeedMode::Normal,
+            paused: false,
+            frame_steps: 0,
+            frame_position: 0,
+            credit: 0.0,
+            last: now,
+            meter_start: now,
+            meter_count: 0,
+            ips: 0.0,
+        }
+    }
+
+    pub fn handle(&mut self, hotkey: Hotkey) {
+        match hotkey {
+            Hotkey::TogglePause => self.paused = !self.paused,
+            Hotkey::FrameAdvance => {
+                self.paused = true;
+                self.frame_steps += 1;
+            },
+            Hotkey::FastForward => self.toggle(SpeedMode::FastForward),
+            Hotkey::Turbo => self.toggle(SpeedMode::Turbo),
+            Hotkey::SlowMotion => {
+                self.mode = match self.mode {
+                    SpeedMode::SlowMotion(2) => SpeedMode::SlowMotion(4),
+                    SpeedMode::SlowMotion(_) => SpeedMode::Normal,
+                    _ => SpeedMode::SlowMotion(2),
+                };
+            },
+            Hotkey::ToggleStats | Hotkey::ToggleRegisters | Hotkey::ToggleHeatmap | Hotkey::ReloadScript => {},
+        }
+    }
+
+    fn toggle(&mut self, mode: SpeedMode) {
+        self.mode = if self.mode == mode { SpeedMode::Normal } else { mode };
+    }
+
+    pub fn mode(&self) -> SpeedMode {
+        self.mode
+    }
+
+    pub fn paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn ips(&self) -> f64 {
+        self.ips
+    }
+
+    // Cost units in one 60 Hz frame at normal speed.
+    pub fn frame_budget(&self) -> u32 {
+        self.frame_budget
+    }
+
+    fn factor(&self) -> f64 {
+        match self.mode {
+            SpeedMode::Normal | SpeedMode::Turbo => 1.0,
+            SpeedMode::FastForward => self.ff_multiplier as f64,
+            SpeedMode::SlowMotion(divisor) => 1.0 / divisor as f64,
+        }
+    }
+
+    // Calls `step` for every instruction that is due since the last call and
+    // returns how many ran. `step` returns what the instruction cost. Once a
+    // frame's budget is used up it is also called with `Tick::Vblank`, so
+    // emulated frames follow the emulated speed rather than the wall clock.
+    // Stops at the first error.
+    pub fn run<E, F: FnMut(Tick) -> Result<u32, E>>(&mut self, mut step: F) -> Result<u32, E> {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last);
+        self.last = now;
+
+        let mut executed = 0;
+        if self.paused {
+            self.credit = 0.0;
+            if self.frame_steps > 0 {
+                self.frame_steps -= 1;
+                loop {
+                    executed += 1;
+                    if self.instruction(&mut step)?.1 {
+                        break;
+                    }
+                }
+            }
+        } else if self.mode == SpeedMode::Turbo {
+            // Uncapped: keep going for a frame's worth of wall time so the
+            // frontend still gets to poll input and draw.
+            while now.elapsed() < FRAME {
+                for _ in 0..256 {
+                    self.instruction(&mut step)?;
+                    executed += 1;
+                }
+            }
+        } else {
+            let frames = elapsed.as_secs_f64() / FRAME.as_secs_f64();
+            self.credit += frames * self.frame_budget as f64 * self.factor();
+            // Don't try to catch up on more than a few frames after a stall.
+            self.credit = self.credit.min(self.frame_budget as f64 * self.factor() * 4.0);
+            while self.credit > 0.0 {
+                self.credit -= self.instruction(&mut step)?.0 as f64;
+                executed += 1;
+            }
+        }
+
+        self.meter(executed, now);
+        Ok(executed)
+    }
+
+    // Runs one instruction and ends every frame whose budget it used up.
+    // Returns the cost and whether a vertical blank followed. Whatever an
+    // instruction overran the frame by is taken from the next one.
+    fn instruction<E, F: FnMut(Tick) -> Result<u32, E>>(&mut self, step: &mut F) -> Result<(u32, bool), E> {
+        let cost = step(Tick::Instruction)?.max(1);
+        self.frame_position += cost;
+
+        let mut vblank = false;
+        while self.frame_position >= self.frame_budget {
+            self.frame_position -= self.frame_budget;
+            step(Tick::Vblank)?;
+            vblank = true;
+        }
+        Ok((cost, vblank))
+    }
+
+    fn meter(&mut self, executed: u32, now: Instant) {
+        self.meter_count += executed as u64;
+        let window = now.duration_since(self.meter_start);
+        if window >= METER_WINDOW {
+            self.ips = self.meter_count as f64 / window.as_secs_f64();
+            self.meter_count = 0;
+            self.meter_start = now;
+        }
+    }
+
+    pub fn label(&self) -> String {
+        if self.paused {
+            return String::from("Paused");
+        }
+
+        match self.mode {
+            SpeedMode::Normal => String::from("1x"),
+            SpeedMode::FastForward => format!("{}x", self.ff_multiplier),
+            SpeedMode::Turbo => String::from("Turbo"),
+            SpeedMode::SlowMotion(divisor) => format!("{}x", 1.0 / divisor as f64),
+        }
+    }
+}
\ No newline at end of file
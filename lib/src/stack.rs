@@ -0,0 +1,165 @@
+use crate::chip8::Chip8Error;
+use crate::state::{self, StateReader, StateWriter};
+
+// The subroutine stack. Interpreters disagree on its size: the COSMAC VIP
+// one has room for 12 calls, SCHIP and most later ones for 16, and some
+// modern ones never run out.
+
+pub const DEFAULT_DEPTH: usize = 16;
+pub const VIP_DEPTH: usize = 12;
+
+// A call that hasn't returned yet.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CallFrame {
+    // Where the CALL was.
+    pub call_site: usize,
+    pub return_address: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CallStack {
+    // Holds `depth` slots when bounded; entries at and past `sp` are stale
+    // but still there for a wrapped return to find.
+    frames: Vec<CallFrame>,
+    sp: usize,
+    depth: Option<usize>,
+}
+
+impl CallStack {
+    // `None` lets the stack grow without limit.
+    pub fn new(depth: Option<usize>) -> Self {
+        CallStack { frames: vec![CallFrame::default(); depth.unwrap_or(0)], sp: 0, depth }
+    }
+
+    pub fn depth(&self) -> Option<usize> {
+        self.depth
+    }
+
+    pub fn sp(&self) -> usize {
+        self.sp
+    }
+
+    // The live frames, oldest first.
+    pub fn frames(&self) -> &[CallFrame] {
+        &self.frames[..self.sp]
+    }
+
+    // Replaces the live frames, keeping the depth.
+    pub fn set_frames(&mut self, frames: &[CallFrame]) {
+        *self = CallStack::new(self.depth);
+        for (i, frame) in frames.iter().enumerate() {
+            self.put(i, *frame);
+        }
+        self.sp = frames.len();
+    }
+
+    // A full stack either fails or, with `wrap`, starts over at the bottom
+    // and overwrites the oldest frame.
+    pub fn push(&mut self, frame: CallFrame, wrap: bool) -> Result<(), Chip8Error> {
+        let mut sp = self.sp;
+        if Some(sp) == self.depth {
+            if !wrap {
+                return Err(Chip8Error::StackOverflow { pc: frame.call_site as u16 });
+            }
+            sp = 0;
+        }
+        self.put(sp, frame);
+        self.sp = sp + 1;
+        Ok(())
+    }
+
+    // An empty stack either fails or, with `wrap`, returns through whatever
+    // the top slot last held. An unbounded stack has no top slot to wrap to.
+    pub fn pop(&mut self, pc: usize, wrap: bool) -> Result<CallFrame, Chip8Error> {
+        self.sp = match (self.sp, self.depth) {
+            (0, Some(depth)) if wrap && depth > 0 => depth,
+            (0, _) => return Err(Chip8Error::StackUnderflow { pc: pc as u16 }),
+            (sp, _) => sp,
+        } - 1;
+        Ok(self.frames[self.sp])
+    }
+
+    pub fn save(&self, out: &mut StateWriter) {
+        out.bool(self.depth.is_some());
+        out.u32(self.depth.unwrap_or(0) as u32);
+        out.u32(self.sp as u32);
+        out.u32(self.frames.len() as u32);
+        for frame in &self.frames {
+            out.u32(frame.call_site as u32);
+            out.u32(frame.return_address as u32);
+        }
+    }
+
+    pub fn load(input: &mut StateReader) -> Result<Self, Chip8Error> {
+        let bounded = input.bool()?;
+        let depth = input.u32()? as usize;
+        let sp = input.u32()? as usize;
+        let mut frames = Vec::new();
+        for _ in 0..input.u32()? {
+            frames.push(CallFrame { call_site: input.u32()? as usize, return_address: input.u32()? as usize });
+        }
+        if sp > frames.len() || (bounded && frames.len() != depth) {
+            return Err(state::invalid("inconsistent call stack"));
+        }
+        Ok(CallStack { frames, sp, depth: if bounded { Some(depth) } else { None } })
+    }
+
+    fn put(&mut self, index: usize, frame: CallFrame) {
+        if index == self.frames.len() {
+            self.frames.push(frame);
+        } else {
+            self.frames[index] = frame;
+        }
+    }
+}
+
+impl Default for CallStack {
+    fn default() -> Self {
+        CallStack::new(Some(DEFAULT_DEPTH))
+    }
+}
+
+// "unlimited" or a number of calls.
+pub fn parse_depth(text: &str) -> Option<Option<usize>> {
+    match text {
+        "unlimited" => Some(None),
+        _ => text.parse().ok().filter(|&depth| depth > 0).map(Some),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(call_site: usize) -> CallFrame {
+        CallFrame { call_site, return_address: call_site + 2 }
+    }
+
+    #[test]
+    fn bounded_stack_errors_or_wraps() {
+        let mut stack = CallStack::new(Some(2));
+        stack.push(frame(0x200), false).unwrap();
+        stack.push(frame(0x300), false).unwrap();
+        assert_eq!(stack.push(frame(0x400), false).unwrap_err(), Chip8Error::StackOverflow { pc: 0x400 });
+
+        stack.push(frame(0x400), true).unwrap();
+        assert_eq!(stack.frames(), [frame(0x400)]);
+        assert_eq!(stack.pop(0x500, false).unwrap(), frame(0x400));
+        assert_eq!(stack.pop(0x500, false).unwrap_err(), Chip8Error::StackUnderflow { pc: 0x500 });
+        assert_eq!(stack.pop(0x500, true).unwrap(), frame(0x300));
+        assert_eq!(stack.sp(), 1);
+    }
+
+    #[test]
+    fn unbounded_stack_grows() {
+        let mut stack = CallStack::new(None);
+        (0..100).for_each(|i| stack.push(frame(i * 2), false).unwrap());
+        assert_eq!(stack.frames().len(), 100);
+        assert_eq!(stack.frames()[99], frame(198));
+        (0..100).for_each(|_| { stack.pop(0, true).unwrap(); });
+        assert_eq!(stack.pop(0x200, true).unwrap_err(), Chip8Error::StackUnderflow { pc: 0x200 });
+        assert_eq!(parse_depth("unlimited"), Some(None));
+        assert_eq!(parse_depth("12"), Some(Some(12)));
+        assert_eq!(parse_depth("0"), None);
+    }
+}
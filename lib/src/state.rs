@@ -0,0 +1,147 @@
+use crate::chip8::Chip8Error;
+use crate::quirks::Quirks;
+
+// Save state encoding: a magic number and version followed by the machine's
+// fields in a fixed order, integers big-endian.
+
+const MAGIC: &[u8; 4] = b"RC8S";
+const VERSION: u8 = 2;
+
+#[derive(Debug, Default)]
+pub struct StateWriter {
+    bytes: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        let mut writer = StateWriter { bytes: MAGIC.to_vec() };
+        writer.u8(VERSION);
+        writer
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.bytes.extend_from_slice(&value.to_be_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_be_bytes());
+    }
+
+    pub fn option_u8(&mut self, value: Option<u8>) {
+        self.bool(value.is_some());
+        self.u8(value.unwrap_or(0));
+    }
+
+    pub fn quirks(&mut self, quirks: Quirks) {
+        let Quirks { vf_reset, shift_vx, increment_i, jump_vx, display_wait, wrap_sprites, wrap_stack, stack_depth } = quirks;
+        for flag in [vf_reset, shift_vx, increment_i, jump_vx, display_wait, wrap_sprites, wrap_stack].iter() {
+            self.bool(*flag);
+        }
+        self.bool(stack_depth.is_some());
+        self.u32(stack_depth.unwrap_or(0) as u32);
+    }
+
+    // Length-prefixed.
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.u32(bytes.len() as u32);
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+#[derive(Debug)]
+pub struct StateReader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Result<Self, Chip8Error> {
+        let mut reader = StateReader { bytes };
+        if reader.take(MAGIC.len())? != MAGIC {
+            return Err(invalid("not a save state"));
+        }
+        match reader.u8()? {
+            VERSION => Ok(reader),
+            version => Err(invalid(&format!("unsupported version {}", version))),
+        }
+    }
+
+    fn take(&mut self, count: usize) -> Result<&'a [u8], Chip8Error> {
+        if count > self.bytes.len() {
+            return Err(invalid("truncated"));
+        }
+        let (taken, rest) = self.bytes.split_at(count);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, Chip8Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, Chip8Error> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub fn u16(&mut self) -> Result<u16, Chip8Error> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, Chip8Error> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    pub fn option_u8(&mut self) -> Result<Option<u8>, Chip8Error> {
+        let present = self.bool()?;
+        let value = self.u8()?;
+        Ok(if present { Some(value) } else { None })
+    }
+
+    pub fn quirks(&mut self) -> Result<Quirks, Chip8Error> {
+        Ok(Quirks {
+            vf_reset: self.bool()?,
+            shift_vx: self.bool()?,
+            increment_i: self.bool()?,
+            jump_vx: self.bool()?,
+            display_wait: self.bool()?,
+            wrap_sprites: self.bool()?,
+            wrap_stack: self.bool()?,
+            stack_depth: match (self.bool()?, self.u32()? as usize) {
+                (true, depth) => Some(depth),
+                (false, _) => None,
+            },
+        })
+    }
+
+    pub fn bytes(&mut self) -> Result<&'a [u8], Chip8Error> {
+        let count = self.u32()? as usize;
+        self.take(count)
+    }
+
+    // Fills `out` from a length-prefixed run that must be exactly its size.
+    pub fn bytes_into(&mut self, out: &mut [u8]) -> Result<(), Chip8Error> {
+        let bytes = self.bytes()?;
+        if bytes.len() != out.len() {
+            return Err(invalid("field has the wrong size"));
+        }
+        out.copy_from_slice(bytes);
+        Ok(())
+    }
+}
+
+pub fn invalid(reason: &str) -> Chip8Error {
+    Chip8Error::Io(format!("Invalid save state: {}", reason))
+}
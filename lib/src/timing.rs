@@ -0,0 +1,140 @@
+use crate::alu;
+use crate::chip8::{Chip8, Chip8Error};
+use crate::instruction::Instruction;
+use crate::vip;
+
+// COSMAC VIP timing in 1802 machine cycles (8 clocks each). The CPU runs at
+// 1.7609 MHz, so one 60 Hz frame lasts `vip::CYCLES_PER_FRAME`. The CDP1861
+// takes 1024 of them for display DMA (128 lines of 8 bytes) and the interrupt
+// routine that ticks the timers takes some more; CHIP-8 gets the rest.
+const DISPLAY_DMA: u32 = 1024;
+const INTERRUPT: u32 = 46;
+pub const VIP_FRAME_BUDGET: u32 = vip::CYCLES_PER_FRAME - DISPLAY_DMA - INTERRUPT;
+
+// Fetching an opcode and dispatching it through the interpreter's jump table,
+// paid by every instruction on top of its own cost.
+const FETCH: u32 = 40;
+// What one poll costs while Dxyn is waiting for the vertical blank.
+const WAIT: u32 = 8;
+// A skip that is taken steps the CHIP-8 pc twice more (INC R5, INC R5).
+const SKIP: u32 = 4;
+
+// Machine cycles `instruction` takes on the VIP interpreter when executed
+// from the state in `c8`, fetch included. The counts come from Laurence
+// Scotford's annotated listing of the interpreter ("Chip-8 on the COSMAC
+// VIP"), as tabulated in microseconds by Jackson S. in "Chip-8 Instruction
+// Scheduling and Frequency" (2019); one machine cycle is 4.54 us. Where the
+// time depends on the operands the table gives the average, and the loops
+// below are priced so they come out at it over all operands.
+pub fn vip_cycles(instruction: &Instruction, c8: &Chip8) -> u32 {
+    let v = c8.v_reg();
+    let skip = |taken: bool| if taken { SKIP } else { 0 };
+
+    let cost = match *instruction {
+        Instruction::Cls => 24,
+        Instruction::Ret => 23,
+        // Calls the machine code routine, which costs what it costs on top.
+        Instruction::Sys(_) => 23,
+        Instruction::Jp(_) => 23,
+        Instruction::Call(_) => 23,
+        Instruction::SeImm(x, nn) => 12 + skip(v[x] == nn),
+        Instruction::SneImm(x, nn) => 12 + skip(v[x] != nn),
+        Instruction::Se(x, y) => 16 + skip(v[x] == v[y]),
+        Instruction::Sne(x, y) => 16 + skip(v[x] != v[y]),
+        Instruction::LdImm(..) => 6,
+        Instruction::AddImm(..) => 10,
+        // The interpreter assembles the 1802 ALU instruction in RAM and runs it.
+        Instruction::Alu { .. } => 44,
+        Instruction::LdI(_) => 12,
+        Instruction::JpV0(_) => 23,
+        Instruction::Rnd(..) => 36,
+        Instruction::Drw(x, _, n) => draw_cycles(v[x], n),
+        Instruction::Skp(x) => 16 + skip(c8.keypad.get(v[x] as usize) == Some(&1)),
+        Instruction::Sknp(x) => 16 + skip(c8.keypad.get(v[x] as usize) != Some(&1)),
+        Instruction::LdVxDt(_) | Instruction::LdKey(_) | Instruction::LdDt(_) | Instruction::LdSt(_) => 10,
+        Instruction::AddI(_) => 19,
+        Instruction::LdF(_) => 20,
+        // Digits are found by repeated subtraction; 204 on average.
+        Instruction::Bcd(x) => 55 + 16 * alu::bcd(v[x]).iter().map(|&d| d as u32).sum::<u32>(),
+        // 133 on average.
+        Instruction::Store(x) | Instruction::Load(x) => 14 + 14 * (x as u32 + 1),
+        // The variants ran their own interpreters; these are priced like the
+        // CHIP-8 instructions closest to them.
+        Instruction::BgColor | Instruction::Nop | Instruction::Stop | Instruction::Skip => 10,
+        Instruction::WaitDt | Instruction::Delay(_) => 10,
+        Instruction::Out(_) | Instruction::In(_) | Instruction::Inp(_) => 10,
+        Instruction::Skp2(x) => 16 + skip(c8.keypad2.get(v[x] as usize) == Some(&1)),
+        Instruction::Sknp2(x) => 16 + skip(c8.keypad2.get(v[x] as usize) != Some(&1)),
+        Instruction::Sgt(x, y) => 16 + skip(v[x] > v[y]),
+        Instruction::AddNibbles(..) => 44,
+        Instruction::Color(..) => 60,
+        Instruction::StoreRange(x, y) | Instruction::LoadRange(x, y) => 14 + 14 * (x.max(y) - x.min(y) + 1) as u32,
+        Instruction::JpBack(_) | Instruction::JpForward(_) | Instruction::SkipBytes(_) => 23,
+        // SCHIP and MegaChip never ran on the VIP.
+        Instruction::ScrollDown(_) | Instruction::ScrollRight | Instruction::ScrollLeft | Instruction::ScrollUp(_)
+        | Instruction::Exit | Instruction::Low | Instruction::High | Instruction::MegaOn | Instruction::MegaOff
+        | Instruction::LdIHigh(_) | Instruction::LdPalette(_) | Instruction::SpriteWidth(_) | Instruction::SpriteHeight(_)
+        | Instruction::Alpha(_) | Instruction::PlaySample(_) | Instruction::StopSample | Instruction::BlendMode(_)
+        | Instruction::CollisionColor(_) | Instruction::StoreFlags(_) | Instruction::LoadFlags(_) | Instruction::LdHf(_) => 12,
+    };
+
+    FETCH + cost
+}
+
+// Each sprite row is shifted into place one bit at a time and spread over two
+// display bytes unless the sprite starts on a byte boundary.
+fn draw_cycles(x: u8, rows: u8) -> u32 {
+    let shift = (x % 8) as u32;
+    let per_row = if shift == 0 { 26 } else { 46 + 4 * shift };
+    68 + rows as u32 * per_row
+}
+
+// Runs one instruction and returns what it cost in machine cycles. A draw
+// that is only waiting for the vertical blank costs a poll.
+pub fn step_vip(c8: &mut Chip8) -> Result<u32, Chip8Error> {
+    let cost = c8.instruction().map_or(FETCH, |instruction| vip_cycles(&instruction, c8));
+    c8.interpret()?;
+    Ok(if c8.waiting_for_vblank() { WAIT } else { cost })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cycles(opcode: u16, setup: impl FnOnce(&mut Chip8)) -> u32 {
+        let mut c8 = Chip8::new();
+        setup(&mut c8);
+        vip_cycles(&Instruction::decode(opcode).unwrap(), &c8)
+    }
+
+    #[test]
+    fn taken_skip_costs_more() {
+        assert_eq!(cycles(0x3100, |_| {}), cycles(0x3101, |_| {}) + SKIP);
+    }
+
+    // The published averages of the instructions that loop.
+    #[test]
+    fn loops_average_to_table() {
+        let bcd: u32 = (0..=255).map(|value| cycles(0xF033, |c8| c8.set_v_reg(0, value)) - FETCH).sum();
+        assert_eq!((bcd as f64 / 256.0).round(), 204.0);
+        let store: u32 = (0..16).map(|x| cycles(0xF055 | x << 8, |_| {}) - FETCH).sum();
+        assert_eq!(store / 16, 133);
+    }
+
+    #[test]
+    fn unaligned_draw_costs_more() {
+        let aligned = cycles(0xD125, |c8| c8.set_v_reg(1, 8));
+        let unaligned = cycles(0xD125, |c8| c8.set_v_reg(1, 11));
+        assert!(unaligned > aligned);
+        assert!(cycles(0xD12F, |c8| c8.set_v_reg(1, 8)) > aligned);
+    }
+
+    #[test]
+    fn draw_waits_for_vblank() {
+        let mut c8 = Chip8::with_quirks(crate::quirks::Quirks::CHIP8);
+        c8.write_memory(0x200, &[0xD0, 0x01]);
+        assert_eq!(step_vip(&mut c8).unwrap(), WAIT);
+        c8.vblank();
+        assert_eq!(step_vip(&mut c8).unwrap(), FETCH + draw_cycles(0, 1));
+    }
+}
@@ -0,0 +1,310 @@
+use crate::display::{Frame, Rgba};
+use crate::frontend::{hotkey, keypad_index, Frontend, Hotkey};
+use crate::overlay::{Overlay, Text};
+use std::io::{self, Read, Write};
+use std::time::{Duration, Instant};
+
+// Terminals only report key presses, never releases. A key is considered held
+// for as long as the terminal's auto-repeat keeps sending it, so until the
+// first repeat shows up we have to wait out the whole repeat delay. Both
+// values are re-measured from the actual repeats and clamped to sane ranges.
+const DEFAULT_REPEAT_DELAY: Duration = Duration::from_millis(500);
+const DEFAULT_REPEAT_RATE: Duration = Duration::from_millis(40);
+const RELEASE_SLACK: Duration = Duration::from_millis(50);
+
+#[derive(Clone, Copy)]
+struct HeldKey {
+    pressed_at: Instant,
+    last_seen: Instant,
+    repeating: bool,
+}
+
+pub struct Tty {
+    original: libc::termios,
+    keys: [Option<HeldKey>; 16],
+    repeat_delay: Duration,
+    repeat_rate: Duration,
+    size: (usize, usize),
+    last_frame: String,
+    last_texts: Vec<Text>,
+    sound: bool,
+    hotkeys: Vec<Hotkey>,
+}
+
+impl Tty {
+    pub fn new() -> Self {
+        let original = unsafe {
+            let mut termios: libc::termios = std::mem::zeroed();
+            if libc::tcgetattr(libc::STDIN_FILENO, &mut termios) != 0 {
+                panic!("The tty frontend needs stdin to be a terminal");
+            }
+
+            let mut raw = termios;
+            libc::cfmakeraw(&mut raw);
+            raw.c_cc[libc::VMIN] = 0;
+            raw.c_cc[libc::VTIME] = 0;
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw);
+
+            termios
+        };
+
+        // Alternate screen, hidden cursor.
+        print!("\x1b[?1049h\x1b[?25l");
+        io::stdout().flush().unwrap();
+
+        Tty {
+            original,
+            keys: [None; 16],
+            repeat_delay: DEFAULT_REPEAT_DELAY,
+            repeat_rate: DEFAULT_REPEAT_RATE,
+            size: (0, 0),
+            last_frame: String::new(),
+            last_texts: Vec::new(),
+            sound: false,
+            hotkeys: Vec::new(),
+        }
+    }
+
+    fn key_event(&mut self, key: usize, now: Instant) {
+        match &mut self.keys[key] {
+            Some(held) => {
+                if held.repeating {
+                    let rate = now.duration_since(held.last_seen);
+                    if rate >= Duration::from_millis(10) && rate <= Duration::from_millis(200) {
+                        self.repeat_rate = rate;
+                    }
+                } else {
+                    let delay = now.duration_since(held.pressed_at);
+                    if delay >= Duration::from_millis(150) && delay <= Duration::from_millis(1000) {
+                        self.repeat_delay = delay;
+                    }
+                    held.repeating = true;
+                }
+                held.last_seen = now;
+            },
+
+            slot => {
+                *slot = Some(HeldKey {
+                    pressed_at: now,
+                    last_seen: now,
+                    repeating: false,
+                });
+            },
+        }
+    }
+
+    fn render_half_blocks(&self, frame: &Frame, cols: usize, rows: usize, out: &mut String) {
+        let scale = (cols / frame.width).min(rows * 2 / frame.height).max(1);
+        let width = frame.width * scale;
+        let height = frame.height * scale;
+        let left = (cols - width) / 2 + 1;
+        let top = (rows - height / 2) / 2 + 1;
+
+        let pixel = |x: usize, y: usize| frame.pixel(x / scale, (y / scale).min(frame.height - 1));
+
+        for row in 0..height / 2 {
+            out.push_str(&format!("\x1b[{};{}H", top + row, left));
+            let mut colors = None;
+
+            for x in 0..width {
+                let upper = pixel(x, row * 2);
+                let lower = pixel(x, row * 2 + 1);
+                if colors != Some((upper, lower)) {
+                    push_color(out, 38, upper);
+                    push_color(out, 48, lower);
+                    colors = Some((upper, lower));
+                }
+                out.push('▀');
+            }
+        }
+    }
+
+    // Fallback for terminals too small for half-blocks: each braille cell
+    // carries a 2x4 block of pixels, sampled further when even that doesn't
+    // fit. Dots are the pixels that differ from the most common colour, drawn
+    // in the second most common one.
+    fn render_braille(&self, frame: &Frame, cols: usize, rows: usize, out: &mut String) {
+        const DOTS: [[u32; 2]; 4] = [[0x01, 0x08], [0x02, 0x10], [0x04, 0x20], [0x40, 0x80]];
+
+        let step = frame.width.div_ceil(cols * 2).max(frame.height.div_ceil(rows * 4)).max(1);
+        let cells_x = (frame.width / step / 2).min(cols);
+        let cells_y = (frame.height / step / 4).min(rows);
+        let left = (cols - cells_x) / 2 + 1;
+        let top = (rows - cells_y) / 2 + 1;
+
+        let (background, foreground) = dominant_colors(frame);
+        push_color(out, 38, foreground);
+        push_color(out, 48, background);
+
+        for cy in 0..cells_y {
+            out.push_str(&format!("\x1b[{};{}H", top + cy, left));
+
+            for cx in 0..cells_x {
+                let mut bits = 0;
+                for (dy, row) in DOTS.iter().enumerate() {
+                    for (dx, dot) in row.iter().enumerate() {
+                        if frame.pixel((cx * 2 + dx) * step, (cy * 4 + dy) * step) != background {
+                            bits |= dot;
+                        }
+                    }
+                }
+                out.push(std::char::from_u32(0x2800 + bits).unwrap());
+            }
+        }
+    }
+}
+
+impl Default for Tty {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for Tty {
+    fn drop(&mut self) {
+        print!("\x1b[0m\x1b[2J\x1b[?25h\x1b[?1049l");
+        let _ = io::stdout().flush();
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.original);
+        }
+    }
+}
+
+impl Frontend for Tty {
+    fn process_input(&mut self, keypad: &mut [u8]) -> bool {
+        let now = Instant::now();
+        let mut buf = [0u8; 64];
+        let n = io::stdin().read(&mut buf).unwrap_or(0);
+
+        let mut i = 0;
+        while i < n {
+            match buf[i] {
+                // Raw mode swallows SIGINT, so handle Ctrl-C ourselves.
+                0x03 => return true,
+
+                0x1b => {
+                    if i + 1 == n {
+                        return true;
+                    }
+
+                    if buf[i + 1] == b'[' || buf[i + 1] == b'O' {
+                        // Skip the rest of an arrow/function key sequence.
+                        i += 2;
+                        while i < n && !(0x40..=0x7e).contains(&buf[i]) {
+                            i += 1;
+                        }
+                    } else {
+                        // Alt+key
+                        i += 1;
+                    }
+                },
+
+                byte => {
+                    if let Some(hotkey) = hotkey(byte as char) {
+                        self.hotkeys.push(hotkey);
+                    } else if let Some(key) = keypad_index(byte as char) {
+                        self.key_event(key, now);
+                    }
+                },
+            }
+            i += 1;
+        }
+
+        for (key, slot) in self.keys.iter_mut().enumerate() {
+            if let Some(held) = slot {
+                let window = if held.repeating { self.repeat_rate * 2 } else { self.repeat_delay };
+                if now.duration_since(held.last_seen) > window + RELEASE_SLACK {
+                    *slot = None;
+                }
+            }
+            keypad[key] = if slot.is_some() { 1 } else { 0 };
+        }
+
+        false
+    }
+
+    fn render_frame(&mut self, frame: &Frame, overlay: &Overlay) {
+        let size = terminal_size();
+        let (cols, rows) = size;
+        let texts = overlay.layout(cols, rows);
+        let mut out = String::new();
+
+        // Text that went away has to be wiped, the screen only covers its own cells.
+        if size != self.size || texts != self.last_texts {
+            out.push_str("\x1b[0m\x1b[2J");
+            self.size = size;
+            self.last_frame.clear();
+        }
+
+        // The bottom line is kept free for overlay messages.
+        let screen_rows = rows.saturating_sub(1).max(1);
+
+        let mut drawn = String::new();
+        if cols >= frame.width && screen_rows * 2 >= frame.height {
+            self.render_half_blocks(frame, cols, screen_rows, &mut drawn);
+        } else {
+            self.render_braille(frame, cols, screen_rows, &mut drawn);
+        }
+
+        drawn.push_str("\x1b[0m");
+        for text in &texts {
+            drawn.push_str(&format!("\x1b[{};{}H\x1b[7m{}\x1b[27m", text.row + 1, text.col + 1, text.text));
+        }
+
+        // Redrawing an unchanged screen is pure bandwidth over SSH.
+        if drawn == self.last_frame {
+            return;
+        }
+
+        out.push_str(&drawn);
+        out.push_str("\x1b[0m");
+        let mut stdout = io::stdout();
+        stdout.write_all(out.as_bytes()).unwrap();
+        stdout.flush().unwrap();
+        self.last_frame = drawn;
+        self.last_texts = texts;
+    }
+
+    fn poll_hotkeys(&mut self) -> Vec<Hotkey> {
+        std::mem::take(&mut self.hotkeys)
+    }
+
+    fn set_sound(&mut self, active: bool) {
+        if active && !self.sound {
+            print!("\x07");
+            io::stdout().flush().unwrap();
+        }
+        self.sound = active;
+    }
+}
+
+fn push_color(out: &mut String, layer: u8, [r, g, b, _]: Rgba) {
+    out.push_str(&format!("\x1b[{};2;{};{};{}m", layer, r, g, b));
+}
+
+// The most and second most common colours of `frame`; the second is the
+// first again for a blank frame.
+fn dominant_colors(frame: &Frame) -> (Rgba, Rgba) {
+    let mut counts: Vec<(Rgba, usize)> = Vec::new();
+    for &pixel in &frame.pixels {
+        match counts.iter_mut().find(|(color, _)| *color == pixel) {
+            Some((_, count)) => *count += 1,
+            None => counts.push((pixel, 1)),
+        }
+    }
+    counts.sort_by_key(|&(_, count)| std::cmp::Reverse(count));
+    let background = counts.first().map_or([0, 0, 0, 255], |&(color, _)| color);
+    let foreground = counts.get(1).map_or(background, |&(color, _)| color);
+    (background, foreground)
+}
+
+fn terminal_size() -> (usize, usize) {
+    unsafe {
+        let mut ws: libc::winsize = std::mem::zeroed();
+        if libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut ws) == 0 && ws.ws_col > 0 {
+            (ws.ws_col as usize, ws.ws_row as usize)
+        } else {
+            (80, 24)
+        }
+    }
+}
\ No newline at end of file
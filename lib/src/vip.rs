@@ -0,0 +1,337 @@
+use crate::cdp1802::{Bus, Cdp1802};
+use crate::chip8::Chip8Error;
+use crate::display::Frame;
+use crate::machine::Machine;
+
+// RCA COSMAC VIP: a CDP1802 with 4 KB of RAM at 0000-0FFF (mirrored up to
+// 7FFF), the 512 byte monitor ROM at 8000-81FF (mirrored up to FFFF) and a
+// CDP1861 "Pixie" video chip. CHIP-8 is not built in: the original interpreter
+// is a 512 byte program loaded at 0000, and it runs the CHIP-8 program at 0200.
+// Neither the interpreter nor the monitor can be shipped here, so both images
+// are supplied by the user.
+
+const RAM_SIZE: usize = 0x1000;
+const ROM_SIZE: usize = 0x200;
+const PROGRAM_START: usize = 0x200;
+
+// CDP1861 timing: 14 machine cycles per line, 262 lines per frame. 128 lines
+// are displayed, each fetching 8 bytes by DMA. The interrupt comes two lines
+// before the first one so the interpreter can point R0 at the display buffer,
+// and EF1 is asserted for the four lines before and the last four lines of
+// the display.
+const CYCLES_PER_LINE: u32 = 14;
+const LINES_PER_FRAME: u32 = 262;
+const INTERRUPT_LINE: u32 = 78;
+const FIRST_LINE: u32 = 80;
+const DISPLAY_LINES: u32 = 128;
+const BYTES_PER_LINE: usize = 8;
+pub const CYCLES_PER_FRAME: u32 = CYCLES_PER_LINE * LINES_PER_FRAME;
+
+struct VipBus {
+    ram: [u8; RAM_SIZE],
+    monitor: Vec<u8>,
+    // After reset the monitor also answers at 0000 until the first access
+    // with A15 set.
+    monitor_low: bool,
+    keypad: [u8; 16],
+    // The key tested by EF3, selected with OUT 2.
+    key_latch: u8,
+    display_on: bool,
+    line: u32,
+}
+
+impl VipBus {
+    fn rom(&self, address: u16) -> u8 {
+        self.monitor.get(address as usize % ROM_SIZE).copied().unwrap_or(0xFF)
+    }
+}
+
+impl Bus for VipBus {
+    fn read(&mut self, address: u16) -> u8 {
+        if address & 0x8000 != 0 {
+            self.monitor_low = false;
+            self.rom(address)
+        } else if self.monitor_low {
+            self.rom(address)
+        } else {
+            self.ram[address as usize % RAM_SIZE]
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        if address & 0x8000 == 0 {
+            self.ram[address as usize % RAM_SIZE] = value;
+        }
+    }
+
+    fn output(&mut self, port: u8, value: u8) {
+        match port {
+            1 => self.display_on = false,
+            2 => self.key_latch = value & 0x0F,
+            _ => {},
+        }
+    }
+
+    fn input(&mut self, port: u8) -> u8 {
+        if port == 1 {
+            self.display_on = true;
+        }
+        0
+    }
+
+    fn ef(&self, flag: u8) -> bool {
+        match flag {
+            1 => {
+                let last = FIRST_LINE + DISPLAY_LINES;
+                (FIRST_LINE - 4..FIRST_LINE).contains(&self.line) || (last - 4..last).contains(&self.line)
+            },
+            3 => self.keypad[self.key_latch as usize] != 0,
+            _ => false,
+        }
+    }
+}
+
+pub struct Vip {
+    cpu: Cdp1802,
+    bus: VipBus,
+    line_cycle: u32,
+    interrupt_pending: bool,
+    dma_pending: bool,
+    lines: [u8; DISPLAY_LINES as usize * BYTES_PER_LINE],
+    screen: [u8; 64 * 32],
+}
+
+impl Vip {
+    // `interpreter` is loaded at 0000. With a monitor image the machine boots
+    // through it like the real one; without, it starts the interpreter
+    // directly with R1.1 holding the top RAM page, as the monitor leaves it.
+    pub fn new(interpreter: &[u8], monitor: Option<&[u8]>) -> Result<Self, Chip8Error> {
+        if interpreter.len() > PROGRAM_START {
+            return Err(Chip8Error::Io(format!("Interpreter image is {} bytes, at most {} fit", interpreter.len(), PROGRAM_START)));
+        }
+        if monitor.is_some_and(|monitor| monitor.len() > ROM_SIZE) {
+            return Err(Chip8Error::Io(format!("Monitor image is larger than {} bytes", ROM_SIZE)));
+        }
+
+        let mut bus = VipBus {
+            ram: [0; RAM_SIZE],
+            monitor: monitor.map_or(Vec::new(), |monitor| monitor.to_vec()),
+            monitor_low: monitor.is_some(),
+            keypad: [0; 16],
+            key_latch: 0,
+            display_on: false,
+            line: 0,
+        };
+        bus.ram[..interpreter.len()].copy_from_slice(interpreter);
+
+        let mut cpu = Cdp1802::new();
+        if monitor.is_none() {
+            cpu.r[1] = ((RAM_SIZE / 0x100 - 1) as u16) << 8;
+        }
+
+        Ok(Vip {
+            cpu,
+            bus,
+            line_cycle: 0,
+            interrupt_pending: false,
+            dma_pending: false,
+            lines: [0; DISPLAY_LINES as usize * BYTES_PER_LINE],
+            screen: [0; 64 * 32],
+        })
+    }
+
+    pub fn load_rom(&mut self, path: &str) -> Result<(), Chip8Error> {
+        let rom = std::fs::read(path).map_err(|e| Chip8Error::Io(format!("Error opening file {}: {}", path, e)))?;
+        self.load_bytes(&rom)
+    }
+
+    pub fn load_bytes(&mut self, rom: &[u8]) -> Result<(), Chip8Error> {
+        if rom.len() > RAM_SIZE - PROGRAM_START {
+            return Err(Chip8Error::RomTooLarge { size: rom.len(), max: RAM_SIZE - PROGRAM_START });
+        }
+        self.bus.ram[PROGRAM_START..PROGRAM_START + rom.len()].copy_from_slice(rom);
+        Ok(())
+    }
+
+    pub fn cpu(&self) -> &Cdp1802 {
+        &self.cpu
+    }
+
+    pub fn ram(&self) -> &[u8] {
+        &self.bus.ram
+    }
+
+    // Runs one 1802 instruction, plus the interrupt or DMA cycles the 1861
+    // asked for, and returns the machine cycles used.
+    pub fn step(&mut self) -> u32 {
+        let mut cycles = 0;
+        if self.interrupt_pending && self.cpu.ie {
+            self.interrupt_pending = false;
+            cycles += self.cpu.interrupt();
+        }
+        cycles += self.cpu.step(&mut self.bus);
+        self.advance(cycles);
+
+        while self.dma_pending {
+            self.dma_pending = false;
+            let start = (self.bus.line - FIRST_LINE) as usize * BYTES_PER_LINE;
+            for i in 0..BYTES_PER_LINE {
+                self.lines[start + i] = self.cpu.dma_out(&mut self.bus);
+            }
+            cycles += BYTES_PER_LINE as u32;
+            self.advance(BYTES_PER_LINE as u32);
+        }
+
+        cycles
+    }
+
+    fn advance(&mut self, cycles: u32) {
+        for _ in 0..cycles {
+            self.line_cycle += 1;
+            if self.line_cycle == CYCLES_PER_LINE {
+                self.line_cycle = 0;
+                self.bus.line = (self.bus.line + 1) % LINES_PER_FRAME;
+                self.start_line();
+            }
+        }
+    }
+
+    fn start_line(&mut self) {
+        let line = self.bus.line;
+        if !self.bus.display_on {
+            if line == 0 {
+                self.screen = [0; 64 * 32];
+            }
+            return;
+        }
+
+        match line {
+            INTERRUPT_LINE => self.interrupt_pending = true,
+            FIRST_LINE => self.interrupt_pending = false,
+            _ => {},
+        }
+        if (FIRST_LINE..FIRST_LINE + DISPLAY_LINES).contains(&line) {
+            self.dma_pending = true;
+        }
+        if line == FIRST_LINE + DISPLAY_LINES {
+            self.compose();
+        }
+    }
+
+    // The interpreter shows every CHIP-8 row on four display lines; merge
+    // them back into a 64x32 screen.
+    fn compose(&mut self) {
+        let lines = &self.lines;
+        for (i, pixel) in self.screen.iter_mut().enumerate() {
+            let (row, col) = (i / 64, i % 64);
+            let lit = (0..4).any(|sub| {
+                let byte = lines[(row * 4 + sub) * BYTES_PER_LINE + col / 8];
+                byte & (0x80 >> (col % 8)) != 0
+            });
+            *pixel = lit as u8;
+        }
+    }
+}
+
+impl Machine for Vip {
+    fn step(&mut self) -> Result<u32, Chip8Error> {
+        Ok(Vip::step(self))
+    }
+
+    // The 1861 keeps its own frame timing.
+    fn vblank(&mut self) {}
+
+    fn keypad(&mut self) -> &mut [u8; 16] {
+        &mut self.bus.keypad
+    }
+
+    fn frame(&self) -> Frame {
+        Frame::monochrome(&self.screen, 64)
+    }
+
+    fn lit_pixels(&self) -> (Vec<u8>, usize) {
+        (self.screen.to_vec(), 64)
+    }
+
+    // The VIP's tone generator is switched by Q.
+    fn sound_active(&self) -> bool {
+        self.cpu.q
+    }
+
+    fn register_lines(&self) -> Vec<String> {
+        let cpu = &self.cpu;
+        let mut lines: Vec<String> = (0..8).map(|i| format!("R{:X} {:04X} R{:X} {:04X}", i, cpu.r[i], i + 8, cpu.r[i + 8])).collect();
+        lines.push(format!("P {:X} X {:X} D {:02X} DF {}", cpu.p, cpu.x, cpu.d, cpu.df as u8));
+        lines.push(format!("T {:02X} IE {} Q {}", cpu.t, cpu.ie as u8, cpu.q as u8));
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Turns the display on and counts interrupts at 0100. R0 is the DMA
+    // pointer, so the main code runs with R3 as program counter and the
+    // interrupt routine points R0 at a display buffer at 0B00.
+    const PROGRAM: [u8; 0x34] = [
+        0xF8, 0x00, 0xB3, 0xF8, 0x07, 0xA3, 0xD3, // R3 = 0007, SEP 3
+        0xF8, 0x00, 0xB1, 0xF8, 0x20, 0xA1,       // R1 = 0020
+        0xF8, 0x0A, 0xB2, 0xF8, 0xFF, 0xA2,       // R2 = 0AFF
+        0xE2, 0x69, 0x30, 0x15,                   // SEX 2, INP 1, BR 0015
+        0, 0, 0, 0, 0, 0, 0, 0,
+        0x70,                                     // 001F: RET
+        0x22, 0x78,                               // 0020: DEC R2, SAV
+        0xF8, 0x0B, 0xB0, 0xF8, 0x00, 0xA0,       // R0 = 0B00
+        0xF8, 0x01, 0xB4, 0xF8, 0x00, 0xA4,       // R4 = 0100
+        0x04, 0xFC, 0x01, 0x54,                   // LDN R4, ADI 01, STR R4
+        0x30, 0x1F,                               // BR 001F
+    ];
+
+    fn run_frames(vip: &mut Vip, frames: u32) {
+        let mut cycles = 0;
+        while cycles < frames * CYCLES_PER_FRAME {
+            cycles += vip.step();
+        }
+    }
+
+    #[test]
+    fn display_dma_and_interrupts() {
+        let mut vip = Vip::new(&PROGRAM, None).unwrap();
+        vip.bus.ram[0xB00] = 0x80;
+        vip.bus.ram[0xB00 + 4 * BYTES_PER_LINE] = 0x01;
+        vip.bus.ram[0xB00 + 127 * BYTES_PER_LINE + 7] = 0x01;
+        run_frames(&mut vip, 3);
+
+        assert_eq!(vip.ram()[0x100], 3);
+        assert_eq!(vip.screen[0], 1);
+        assert_eq!(vip.screen[64 + 7], 1);
+        assert_eq!(vip.screen[31 * 64 + 63], 1);
+        assert_eq!(vip.screen.iter().filter(|&&pixel| pixel != 0).count(), 3);
+    }
+
+    #[test]
+    fn keypad_on_ef3() {
+        let mut vip = Vip::new(&[], None).unwrap();
+        vip.keypad()[0xA] = 1;
+        vip.bus.output(2, 0x0A);
+        assert!(vip.bus.ef(3));
+        vip.bus.output(2, 0x0B);
+        assert!(!vip.bus.ef(3));
+    }
+
+    #[test]
+    fn boots_through_monitor() {
+        // The monitor is seen at 0000 after reset; LBR 8003 switches to high
+        // addresses, and from then on 0000 is RAM again.
+        let monitor = [0xC0, 0x80, 0x03, 0x7B, 0xC0, 0x00, 0x00];
+        let mut vip = Vip::new(&[0x7A, 0x00], Some(&monitor)).unwrap();
+        for _ in 0..3 {
+            vip.step();
+        }
+        assert!(vip.sound_active());
+        vip.step();
+        assert!(!vip.sound_active());
+        assert_eq!(vip.cpu().r[0], 1);
+    }
+}
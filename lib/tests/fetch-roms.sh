@@ -0,0 +1,16 @@
+#!/bin/sh
+# Downloads the community test ROMs that the ignored tests in roms.rs run,
+# from a fixed release of Timendus' CHIP-8 test suite. They come under that
+# project's licence, so they stay out of this repository.
+#
+#   lib/tests/fetch-roms.sh && cargo test -p lib --test roms -- --ignored
+set -eu
+
+VERSION=v4.1
+URL="https://github.com/Timendus/chip8-test-suite/raw/$VERSION/bin"
+DIR="$(dirname "$0")/roms/community"
+
+mkdir -p "$DIR"
+for rom in 3-corax+.ch8 4-flags.ch8 5-quirks.ch8; do
+    curl -fsSL -o "$DIR/$rom" "$URL/$rom"
+done
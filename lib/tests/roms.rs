@@ -0,0 +1,179 @@
+// Runs the ROMs in tests/roms headlessly and compares the final screen with an
+// ASCII snapshot in tests/golden. Set UPDATE_GOLDEN=1 to
+// rewrite the snapshots after an intended change.
+//
+// The community test ROMs (Timendus' CHIP-8 test suite) come under their own
+// licence and aren't kept here. tests/fetch-roms.sh downloads them into
+// tests/roms/community, after which their tests run with
+// `cargo test -p lib --test roms -- --ignored`. Their snapshots are what a
+// reference emulator shows for the ROM, not what this one does: record a
+// missing one with UPDATE_GOLDEN=1 only after checking it against one.
+use lib::chip8::Chip8;
+use lib::harness::{self, KeyEvent};
+use lib::machine::Machine;
+use lib::megachip::MegaChip;
+use lib::quirks::Quirks;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+struct RomTest<'a> {
+    name: &'static str,
+    golden: &'static str,
+    quirks: Quirks,
+    // SCHIP programs run on MegaChip, which implements it.
+    schip: bool,
+    // Written to 0x1FF before starting, where the community ROMs look for the
+    // platform to test instead of asking for it.
+    platform: Option<u8>,
+    frames: u32,
+    instructions_per_frame: u32,
+    script: &'a [KeyEvent],
+}
+
+impl Default for RomTest<'_> {
+    fn default() -> Self {
+        RomTest {
+            name: "",
+            golden: "",
+            quirks: Quirks::CHIP8,
+            schip: false,
+            platform: None,
+            frames: 120,
+            instructions_per_frame: 15,
+            script: &[],
+        }
+    }
+}
+
+fn load(test: &RomTest, rom: &str) -> Box<dyn Machine> {
+    let platform = test.platform.map_or_else(Vec::new, |platform| vec![platform]);
+    if test.schip {
+        let mut mega = MegaChip::with_quirks(test.quirks);
+        mega.load_rom(rom).unwrap();
+        mega.write_memory(0x1FF, &platform);
+        Box::new(mega)
+    } else {
+        let mut c8 = Chip8::with_quirks(test.quirks);
+        c8.load_rom(rom).unwrap();
+        c8.write_memory(0x1FF, &platform);
+        Box::new(c8)
+    }
+}
+
+fn check(test: &RomTest) {
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests");
+    let rom = dir.join("roms").join(format!("{}.ch8", test.name));
+    let golden = dir.join("golden").join(format!("{}.txt", test.golden));
+    if !rom.exists() {
+        panic!("Missing ROM {}, run tests/fetch-roms.sh", rom.display());
+    }
+
+    let mut machine = load(test, rom.to_str().unwrap());
+    harness::run(machine.as_mut(), test.frames, test.instructions_per_frame, test.script).unwrap();
+    let (screen, width) = machine.lit_pixels();
+    let actual = harness::to_ascii(&screen, width);
+
+    if env::var_os("UPDATE_GOLDEN").is_some() {
+        fs::create_dir_all(golden.parent().unwrap()).unwrap();
+        fs::write(&golden, &actual).unwrap();
+        return;
+    }
+
+    let expected = fs::read_to_string(&golden)
+        .unwrap_or_else(|_| panic!("Missing snapshot {}, run with UPDATE_GOLDEN=1", golden.display()));
+    if let Some(diff) = harness::diff(&expected, &actual) {
+        panic!("{} doesn't match its snapshot:\n{}", test.golden, diff);
+    }
+}
+
+#[test]
+fn opcodes() {
+    check(&RomTest {
+        name: "opcodes",
+        golden: "opcodes",
+        script: &[KeyEvent::press(60, 0xA)],
+        ..RomTest::default()
+    });
+}
+// Every flags test runs long enough for the ROM to reach its final loop.
+fn flags(name: &'static str, golden: &'static str, quirks: Quirks) {
+    check(&RomTest { name, golden, quirks, ..RomTest::default() });
+}
+
+#[test]
+fn flags_chip8() {
+    flags("flags", "flags_chip8", Quirks::CHIP8);
+}
+
+#[test]
+fn flags_schip() {
+    flags("flags", "flags_schip", Quirks::SCHIP);
+}
+
+#[test]
+fn flags_xochip() {
+    flags("flags", "flags_xochip", Quirks::XO_CHIP);
+}
+
+#[test]
+fn flags_vf_chip8() {
+    flags("flags_vf", "flags_vf_chip8", Quirks::CHIP8);
+}
+
+#[test]
+fn flags_vf_schip() {
+    flags("flags_vf", "flags_vf_schip", Quirks::SCHIP);
+}
+
+#[test]
+fn flags_vf_xochip() {
+    flags("flags_vf", "flags_vf_xochip", Quirks::XO_CHIP);
+}
+
+// The community ROMs, each under the preset of every platform it tests that a
+// machine here implements. 0x1FF holds 1 for CHIP-8 and 2 for SCHIP; 3, for
+// XO-CHIP, isn't run since nothing here has its opcodes.
+fn community(name: &'static str, golden: &'static str, quirks: Quirks, platform: u8) {
+    assert!(platform == 1 || platform == 2, "No machine implements platform {}", platform);
+    check(&RomTest {
+        name,
+        golden,
+        quirks,
+        schip: platform == 2,
+        platform: Some(platform),
+        frames: 600,
+        instructions_per_frame: 30,
+        ..RomTest::default()
+    });
+}
+
+#[test]
+#[ignore = "needs tests/fetch-roms.sh"]
+fn community_corax() {
+    community("community/3-corax+", "community/corax", Quirks::CHIP8, 1);
+}
+
+#[test]
+#[ignore = "needs tests/fetch-roms.sh"]
+fn community_flags_chip8() {
+    community("community/4-flags", "community/flags_chip8", Quirks::CHIP8, 1);
+}
+
+#[test]
+#[ignore = "needs tests/fetch-roms.sh"]
+fn community_flags_schip() {
+    community("community/4-flags", "community/flags_schip", Quirks::SCHIP, 2);
+}
+
+#[test]
+#[ignore = "needs tests/fetch-roms.sh"]
+fn community_quirks_chip8() {
+    community("community/5-quirks", "community/quirks_chip8", Quirks::CHIP8, 1);
+}
+
+#[test]
+#[ignore = "needs tests/fetch-roms.sh"]
+fn community_quirks_schip() {
+    community("community/5-quirks", "community/quirks_schip", Quirks::SCHIP, 2);
+}